@@ -3,11 +3,31 @@
 // license that can be found in the LICENSE file
 
 use std::fmt;
+use std::rc::Rc;
 
 #[derive(PartialEq, Clone, Debug)]
 pub struct Ident(pub String);
 
-#[derive(PartialEq, Clone, Debug)]
+/// A position in the source, counted from 1.
+#[derive(PartialEq, Clone, Copy, Debug, Default)]
+pub struct Span {
+    pub line: usize,
+    pub column: usize,
+}
+
+impl Span {
+    pub fn new(line: usize, column: usize) -> Self {
+        Span { line, column }
+    }
+}
+
+impl fmt::Display for Span {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}, column {}", self.line, self.column)
+    }
+}
+
+#[derive(PartialEq, Clone, Copy, Debug)]
 pub enum Prefix {
     Plus,
     Minus,
@@ -24,7 +44,7 @@ impl fmt::Display for Prefix {
     }
 }
 
-#[derive(PartialEq, Clone, Debug)]
+#[derive(PartialEq, Clone, Copy, Debug)]
 pub enum Infix {
     Plus,
     Minus,
@@ -36,6 +56,7 @@ pub enum Infix {
     GreaterThan,
     LessThanEqual,
     LessThan,
+    NullCoalesce,
 }
 
 impl fmt::Display for Infix {
@@ -51,29 +72,62 @@ impl fmt::Display for Infix {
             Infix::GreaterThan => write!(f, ">"),
             Infix::LessThanEqual => write!(f, "<="),
             Infix::LessThan => write!(f, "<"),
+            Infix::NullCoalesce => write!(f, "??"),
         }
     }
 }
 
+/// Where the resolver found a local variable: `depth` scopes out from the
+/// current one, at `index` in that scope.
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub struct Slot {
+    pub depth: usize,
+    pub index: usize,
+}
+
 #[derive(PartialEq, Clone, Debug)]
 pub enum Expr {
     Ident(Ident),
+    /// A variable of an enclosing function or `catch` scope. Produced by the resolver.
+    ///
+    /// Until its declaration runs, the variable it shadows is read instead;
+    /// parameters, which are always bound, have none.
+    Local(Ident, Slot, Option<Box<Expr>>),
     Literal(Literal),
     Prefix(Prefix, Box<Expr>),
     Infix(Infix, Box<Expr>, Box<Expr>),
     Index(Box<Expr>, Box<Expr>),
+    Dot(Box<Expr>, Ident),
+    OptionalIndex(Box<Expr>, Box<Expr>),
+    OptionalDot(Box<Expr>, Ident),
     If {
         cond: Box<Expr>,
         consequence: BlockStmt,
         alternative: Option<BlockStmt>,
     },
     Func {
-        params: Vec<Ident>,
-        body: BlockStmt,
+        params: Rc<Vec<Ident>>,
+        body: Rc<BlockStmt>,
     },
     Call {
         func: Box<Expr>,
         args: Vec<Expr>,
+        span: Span,
+    },
+    Propagate(Box<Expr>),
+    /// The function of the method call `receiver.name(..)` or `receiver?.name(..)`,
+    /// with the variable `name` refers to in scope, which is called when the
+    /// receiver has no field of that name. Produced by the resolver.
+    Method {
+        receiver: Box<Expr>,
+        name: Ident,
+        optional: bool,
+        var: Box<Expr>,
+    },
+    Try {
+        body: BlockStmt,
+        catch: Option<(Ident, BlockStmt)>,
+        finally: Option<BlockStmt>,
     },
 }
 
@@ -82,6 +136,7 @@ pub enum Literal {
     Int(i64),
     String(String),
     Bool(bool),
+    Null,
     Array(Vec<Expr>),
     Hash(Vec<(Expr, Expr)>),
 }
@@ -90,22 +145,203 @@ pub enum Literal {
 pub enum Stmt {
     Blank,
     Let(Ident, Expr),
+    Const(Ident, Expr),
     Return(Expr),
+    Throw(Expr),
     Expr(Expr),
+    Struct(Ident, Vec<Ident>),
+    Enum(Ident, Vec<(Ident, Vec<Ident>)>),
+    /// A declaration inside a function or `catch` scope, binding its names to
+    /// these slots of the scope in order. Produced by the resolver.
+    Local(Vec<usize>, Box<Stmt>),
 }
 
 pub type BlockStmt = Vec<Stmt>;
 
 pub type Program = BlockStmt;
 
+/// Writes `items` separated by commas.
+fn write_list<T: fmt::Display>(f: &mut fmt::Formatter, items: &[T]) -> fmt::Result {
+    for (i, item) in items.iter().enumerate() {
+        if i > 0 {
+            write!(f, ", ")?;
+        }
+        write!(f, "{}", item)?;
+    }
+    Ok(())
+}
+
+fn write_block(f: &mut fmt::Formatter, block: &[Stmt]) -> fmt::Result {
+    write!(f, "{{")?;
+    for stmt in block {
+        if *stmt != Stmt::Blank {
+            write!(f, " {}", stmt)?;
+        }
+    }
+    write!(f, " }}")
+}
+
+impl fmt::Display for Ident {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+// Expressions are written back as source the parser reads to the same tree,
+// with every operator parenthesized so precedence never comes into play
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Expr::Ident(ref ident) | Expr::Local(ref ident, ..) => write!(f, "{}", ident),
+            Expr::Literal(ref literal) => write!(f, "{}", literal),
+            Expr::Prefix(prefix, ref right) => write!(f, "({}{})", prefix, right),
+            Expr::Infix(infix, ref left, ref right) => {
+                write!(f, "({} {} {})", left, infix, right)
+            }
+            Expr::Index(ref left, ref index) => write!(f, "{}[{}]", left, index),
+            Expr::Dot(ref left, ref field) => write!(f, "{}.{}", left, field),
+            Expr::OptionalIndex(ref left, ref index) => write!(f, "{}?[{}]", left, index),
+            Expr::OptionalDot(ref left, ref field) => write!(f, "{}?.{}", left, field),
+            Expr::Method {
+                ref receiver,
+                ref name,
+                optional,
+                ..
+            } => {
+                let dot = if optional { "?." } else { "." };
+                write!(f, "{}{}{}", receiver, dot, name)
+            }
+            Expr::If {
+                ref cond,
+                ref consequence,
+                ref alternative,
+            } => {
+                match **cond {
+                    Expr::Prefix(..) | Expr::Infix(..) => write!(f, "if {} ", cond)?,
+                    _ => write!(f, "if ({}) ", cond)?,
+                }
+                write_block(f, consequence)?;
+                if let Some(ref alternative) = *alternative {
+                    write!(f, " else ")?;
+                    write_block(f, alternative)?;
+                }
+                Ok(())
+            }
+            Expr::Func {
+                ref params,
+                ref body,
+            } => {
+                write!(f, "fun(")?;
+                write_list(f, params)?;
+                write!(f, ") ")?;
+                write_block(f, body)
+            }
+            Expr::Call {
+                ref func, ref args, ..
+            } => {
+                match **func {
+                    Expr::Func { .. } | Expr::If { .. } | Expr::Try { .. } => {
+                        write!(f, "({})(", func)?
+                    }
+                    _ => write!(f, "{}(", func)?,
+                }
+                write_list(f, args)?;
+                write!(f, ")")
+            }
+            Expr::Propagate(ref expr) => write!(f, "{}?", expr),
+            Expr::Try {
+                ref body,
+                ref catch,
+                ref finally,
+            } => {
+                write!(f, "try ")?;
+                write_block(f, body)?;
+                if let Some((ref name, ref handler)) = *catch {
+                    write!(f, " catch ({}) ", name)?;
+                    write_block(f, handler)?;
+                }
+                if let Some(ref finally) = *finally {
+                    write!(f, " finally ")?;
+                    write_block(f, finally)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl fmt::Display for Literal {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            // The most negative integer has no literal, as `-` is applied after parsing
+            Literal::Int(i64::MIN) => write!(f, "({} - 1)", i64::MIN + 1),
+            Literal::Int(value) if value < 0 => write!(f, "({})", value),
+            Literal::Int(value) => write!(f, "{}", value),
+            Literal::String(ref value) => write!(f, "\"{}\"", value),
+            Literal::Bool(value) => write!(f, "{}", value),
+            Literal::Null => write!(f, "null"),
+            Literal::Array(ref items) => {
+                write!(f, "[")?;
+                write_list(f, items)?;
+                write!(f, "]")
+            }
+            Literal::Hash(ref pairs) => {
+                write!(f, "{{")?;
+                for (i, (key, value)) in pairs.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}: {}", key, value)?;
+                }
+                write!(f, "}}")
+            }
+        }
+    }
+}
+
+impl fmt::Display for Stmt {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Stmt::Blank => Ok(()),
+            Stmt::Let(ref name, ref expr) => write!(f, "let {} = {};", name, expr),
+            Stmt::Const(ref name, ref expr) => write!(f, "const {} = {};", name, expr),
+            Stmt::Return(ref expr) => write!(f, "return {};", expr),
+            Stmt::Throw(ref expr) => write!(f, "throw {};", expr),
+            Stmt::Expr(ref expr) => write!(f, "{};", expr),
+            Stmt::Struct(ref name, ref fields) => {
+                write!(f, "struct {} {{ ", name)?;
+                write_list(f, fields)?;
+                write!(f, " }}")
+            }
+            Stmt::Enum(ref name, ref variants) => {
+                write!(f, "enum {} {{ ", name)?;
+                for (i, (tag, fields)) in variants.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}", tag)?;
+                    if !fields.is_empty() {
+                        write!(f, "(")?;
+                        write_list(f, fields)?;
+                        write!(f, ")")?;
+                    }
+                }
+                write!(f, " }}")
+            }
+            Stmt::Local(_, ref stmt) => write!(f, "{}", stmt),
+        }
+    }
+}
+
 #[derive(PartialEq, PartialOrd, Debug, Clone)]
 pub enum Precedence {
     Lowest,
+    Coalesce,    // ??
     Equals,      // ==
     LessGreater, // > or <
     Sum,         // +
     Product,     // *
     Prefix,      // -X or !X
-    Call,        // myFunction(x)
-    Index,       // array[index]
+    Call,        // myFunction(x) or result?
+    Index,       // array[index], value.field, value?.field or array?[index]
 }
@@ -0,0 +1,243 @@
+// Copyright 2024 Dimitrios Papakonstantinou. All rights reserved.
+// Use of this source code is governed by a MIT
+// license that can be found in the LICENSE file
+
+use crate::evaluator::error::{ErrorKind, RuntimeError};
+use crate::evaluator::object::Object;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// The deadline is checked once every this many steps, as reading the clock
+/// on every step would slow scripts down noticeably.
+const DEADLINE_INTERVAL: u64 = 1024;
+
+/// Limits on how much work a script may do, so that a runaway script is
+/// aborted with a `LimitError` instead of hanging or exhausting memory.
+///
+/// A `LimitError` can not be caught by `try`, and `finally` blocks do not run
+/// while it unwinds.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Limits {
+    /// Steps a run may take: an expression for the evaluator, an instruction
+    /// for the VM.
+    pub fuel: Option<u64>,
+    /// Wall-clock time a run may take.
+    pub timeout: Option<Duration>,
+    /// Total size of the lists, hashes and strings a run may create, counted
+    /// in elements, entries and bytes.
+    ///
+    /// Values built by literals, operators and builtins are counted, so a list
+    /// grown with `push` is counted again at each step.
+    pub max_alloc: Option<usize>,
+}
+
+/// What is left of the [`Limits`] of a run.
+#[derive(Debug)]
+pub(crate) struct Budget {
+    steps: u64,
+    fuel: u64,
+    deadline: Option<Instant>,
+    allocated: usize,
+    max_alloc: usize,
+}
+
+impl Budget {
+    pub(crate) fn new(limits: &Limits) -> Self {
+        Budget {
+            steps: 0,
+            fuel: limits.fuel.unwrap_or(u64::MAX),
+            deadline: limits.timeout.map(|timeout| Instant::now() + timeout),
+            allocated: 0,
+            max_alloc: limits.max_alloc.unwrap_or(usize::MAX),
+        }
+    }
+
+    /// Takes a step, failing once the fuel is used up or the deadline passed.
+    #[inline]
+    pub(crate) fn step(&mut self) -> Result<(), RuntimeError> {
+        self.steps += 1;
+        if self.steps > self.fuel {
+            return Err(Self::exceeded(format!(
+                "ran out of fuel after {} steps",
+                self.fuel
+            )));
+        }
+
+        if self.steps.is_multiple_of(DEADLINE_INTERVAL) {
+            if let Some(deadline) = self.deadline {
+                if Instant::now() >= deadline {
+                    // Fail every later step too, without reading the clock again
+                    self.fuel = self.steps - 1;
+                    return Err(Self::exceeded(String::from("ran out of time")));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Counts a list, hash or string that was just created.
+    pub(crate) fn allocate(&mut self, obj: &Object) -> Result<(), RuntimeError> {
+        let size = match *obj {
+            Object::Array(ref items) => items.len(),
+            Object::Hash(ref hash) => hash.len(),
+            Object::String(ref s) => s.len(),
+            _ => return Ok(()),
+        };
+
+        self.allocated = self.allocated.saturating_add(size);
+        if self.allocated > self.max_alloc {
+            return Err(Self::exceeded(format!(
+                "exceeded the allocation limit of {}",
+                self.max_alloc
+            )));
+        }
+        Ok(())
+    }
+
+    fn exceeded(message: String) -> RuntimeError {
+        RuntimeError::new(ErrorKind::LimitError, message)
+    }
+}
+
+impl Default for Budget {
+    fn default() -> Self {
+        Budget::new(&Limits::default())
+    }
+}
+
+/// Lets another thread, or a signal handler, stop a running script.
+///
+/// Clones share the same flag. The script is aborted with an `InterruptError`
+/// at its next call, which can not be caught by `try`.
+#[derive(Debug, Clone, Default)]
+pub struct InterruptHandle(Arc<AtomicBool>);
+
+impl InterruptHandle {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Asks the running script to stop.
+    pub fn interrupt(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_interrupted(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    /// Forgets an interrupt that came in after the last run ended.
+    pub(crate) fn clear(&self) {
+        self.0.store(false, Ordering::Relaxed);
+    }
+
+    /// Fails once an interrupt was asked for.
+    #[inline]
+    pub(crate) fn check(&self) -> Result<(), RuntimeError> {
+        if self.is_interrupted() {
+            return Err(RuntimeError::new(
+                ErrorKind::InterruptError,
+                String::from("interrupted"),
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::evaluator::budget::*;
+    use std::collections::HashMap;
+    use std::rc::Rc;
+
+    #[test]
+    fn test_fuel() {
+        let mut budget = Budget::new(&Limits {
+            fuel: Some(3),
+            ..Limits::default()
+        });
+
+        for _ in 0..3 {
+            assert!(budget.step().is_ok());
+        }
+        for _ in 0..2 {
+            let err = budget.step().unwrap_err();
+            assert_eq!(ErrorKind::LimitError, err.kind);
+            assert_eq!("ran out of fuel after 3 steps", err.message);
+        }
+    }
+
+    #[test]
+    fn test_deadline() {
+        let mut budget = Budget::new(&Limits {
+            timeout: Some(Duration::ZERO),
+            ..Limits::default()
+        });
+
+        // The clock is only read every DEADLINE_INTERVAL steps
+        for _ in 1..DEADLINE_INTERVAL {
+            assert!(budget.step().is_ok());
+        }
+        assert_eq!("ran out of time", budget.step().unwrap_err().message);
+        assert_eq!(
+            format!("ran out of fuel after {} steps", DEADLINE_INTERVAL - 1),
+            budget.step().unwrap_err().message
+        );
+    }
+
+    #[test]
+    fn test_unlimited() {
+        let mut budget = Budget::default();
+
+        for _ in 0..2 * DEADLINE_INTERVAL {
+            assert!(budget.step().is_ok());
+        }
+        let big = Object::String("x".repeat(1 << 16));
+        assert!(budget.allocate(&big).is_ok());
+    }
+
+    #[test]
+    #[allow(clippy::mutable_key_type)]
+    fn test_allocate() {
+        let mut budget = Budget::new(&Limits {
+            max_alloc: Some(10),
+            ..Limits::default()
+        });
+        let hash = HashMap::from([(Object::Int(1), Object::Int(2))]);
+
+        assert!(budget.allocate(&Object::Int(1_000)).is_ok());
+        assert!(budget.allocate(&Object::Null).is_ok());
+        assert!(budget
+            .allocate(&Object::Array(Rc::new(vec![Object::Null; 4])))
+            .is_ok());
+        assert!(budget.allocate(&Object::Hash(Rc::new(hash))).is_ok());
+        assert!(budget
+            .allocate(&Object::String(String::from("abcde")))
+            .is_ok());
+        assert_eq!(10, budget.allocated);
+
+        let err = budget
+            .allocate(&Object::String(String::from("f")))
+            .unwrap_err();
+        assert_eq!(ErrorKind::LimitError, err.kind);
+        assert_eq!("exceeded the allocation limit of 10", err.message);
+    }
+
+    #[test]
+    fn test_interrupt_handle() {
+        let handle = InterruptHandle::new();
+        let clone = handle.clone();
+        assert!(handle.check().is_ok());
+
+        clone.interrupt();
+        assert!(handle.is_interrupted());
+        let err = handle.check().unwrap_err();
+        assert_eq!(ErrorKind::InterruptError, err.kind);
+        assert!(err.kind.is_fatal());
+
+        handle.clear();
+        assert!(!clone.is_interrupted());
+        assert!(clone.check().is_ok());
+    }
+}
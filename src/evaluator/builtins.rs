@@ -2,62 +2,136 @@
 // Use of this source code is governed by a MIT
 // license that can be found in the LICENSE file
 
-use crate::repl::read_from_stdin;
-use crate::{ast::Ident, evaluator::object::*};
-use std::fs::{self, File};
-use std::io::Write;
-use std::{cell::RefCell, collections::HashMap, rc::Rc};
+use crate::evaluator::host::{Host, StdHost};
+use crate::{evaluator::error::ErrorKind, evaluator::object::*};
+use std::{collections::HashMap, io, rc::Rc};
 
-use super::{Env, Evaluator};
+use super::{Evaluator, Interp};
 
 pub fn new_builtins() -> HashMap<String, Object> {
     let mut builtins = HashMap::new();
-    builtins.insert(String::from("read"), Object::Builtin(0, lang_read));
-    builtins.insert(String::from("readFile"), Object::Builtin(1, lang_read_file));
+    builtins.insert(String::from("print"), Object::builtin(-1, lang_print));
+    builtins.insert(String::from("read"), Object::builtin(0, lang_read));
+    builtins.insert(String::from("readFile"), Object::builtin(1, lang_read_file));
     builtins.insert(
         String::from("writeFile"),
-        Object::Builtin(2, lang_write_file),
+        Object::builtin(2, lang_write_file),
     );
-    builtins.insert(String::from("len"), Object::Builtin(1, lang_len));
-    builtins.insert(String::from("first"), Object::Builtin(1, lang_first));
-    builtins.insert(String::from("last"), Object::Builtin(1, lang_last));
-    builtins.insert(String::from("tail"), Object::Builtin(1, lang_tail));
-    builtins.insert(String::from("get"), Object::Builtin(2, lang_get));
-    builtins.insert(String::from("push"), Object::Builtin(2, lang_push));
-    builtins.insert(String::from("map"), Object::Builtin(2, lang_map));
-    builtins.insert(String::from("filter"), Object::Builtin(2, lang_filter));
-    builtins.insert(String::from("sort"), Object::Builtin(2, lang_sort));
-    builtins.insert(String::from("reverse"), Object::Builtin(1, lang_reverse));
-    builtins.insert(String::from("trim"), Object::Builtin(1, lang_trim));
+    builtins.insert(String::from("len"), Object::builtin(1, lang_len));
+    builtins.insert(String::from("first"), Object::builtin(1, lang_first));
+    builtins.insert(String::from("last"), Object::builtin(1, lang_last));
+    builtins.insert(String::from("tail"), Object::builtin(1, lang_tail));
+    builtins.insert(String::from("get"), Object::builtin(2, lang_get));
+    builtins.insert(String::from("push"), Object::builtin(2, lang_push));
+    builtins.insert(
+        String::from("map"),
+        Object::Builtin(2, BuiltinFunc::HigherOrder(lang_map)),
+    );
+    builtins.insert(
+        String::from("filter"),
+        Object::Builtin(2, BuiltinFunc::HigherOrder(lang_filter)),
+    );
+    builtins.insert(String::from("sort"), Object::builtin(2, lang_sort));
+    builtins.insert(String::from("reverse"), Object::builtin(1, lang_reverse));
+    builtins.insert(String::from("trim"), Object::builtin(1, lang_trim));
     builtins.insert(
         String::from("parseNumber"),
-        Object::Builtin(1, lang_parse_number),
+        Object::builtin(1, lang_parse_number),
     );
-    builtins.insert(String::from("explode"), Object::Builtin(1, lang_explode));
+    builtins.insert(String::from("explode"), Object::builtin(1, lang_explode));
     builtins.insert(
         String::from("replaceString"),
-        Object::Builtin(3, lang_replace_substring),
+        Object::builtin(3, lang_replace_substring),
     );
     builtins.insert(
         String::from("replaceN"),
-        Object::Builtin(4, lang_replace_n_substring),
+        Object::builtin(4, lang_replace_n_substring),
     );
     builtins.insert(
         String::from("lowercase"),
-        Object::Builtin(1, lang_to_lowercase),
+        Object::builtin(1, lang_to_lowercase),
     );
     builtins.insert(
         String::from("uppercase"),
-        Object::Builtin(1, lang_to_uppercase),
+        Object::builtin(1, lang_to_uppercase),
+    );
+    builtins.insert(String::from("typeOf"), Object::builtin(1, lang_type_of));
+    builtins.insert(String::from("tagOf"), Object::builtin(1, lang_tag_of));
+    builtins.insert(String::from("isNull"), Object::builtin(1, lang_is_null));
+    builtins.insert(
+        String::from("Ok"),
+        Object::VariantCtor(
+            String::from("Result"),
+            String::from("Ok"),
+            vec![String::from("value")],
+        ),
+    );
+    builtins.insert(
+        String::from("Err"),
+        Object::VariantCtor(
+            String::from("Result"),
+            String::from("Err"),
+            vec![String::from("error")],
+        ),
     );
     builtins
 }
 
+type HostFn = fn(&dyn Host, Vec<Object>) -> Object;
+
+/// The builtins that talk to the [`Host`], with their arities.
+const HOST_BUILTINS: [(&str, i32, HostFn); 4] = [
+    ("print", -1, print_with),
+    ("read", 0, read_with),
+    ("readFile", 1, read_file_with),
+    ("writeFile", 2, write_file_with),
+];
+
+/// Replaces the I/O builtins with ones that go through `host` instead of the
+/// process's stdin, stdout and filesystem.
+pub fn use_host(builtins: &mut HashMap<String, Object>, host: Rc<dyn Host>) {
+    for (name, arity, f) in HOST_BUILTINS {
+        let host = Rc::clone(&host);
+        builtins.insert(
+            String::from(name),
+            Object::Builtin(arity, BuiltinFunc::new(move |_, args| f(&*host, args))),
+        );
+    }
+}
+
+/// Switches the fallible builtins to "result mode".
+///
+/// In result mode `readFile`, `writeFile` and `parseNumber` return `Ok(value)`,
+/// or `Err(message)` when I/O fails or the input can not be parsed, instead of
+/// aborting the script. Calling them wrongly and accesses the sandbox denies
+/// still raise an error, so that `?` can not hide them.
+pub fn enable_result_mode(builtins: &mut HashMap<String, Object>) {
+    for name in ["readFile", "writeFile", "parseNumber"] {
+        if let Some(Object::Builtin(arity, f)) = builtins.remove(name) {
+            let result = BuiltinFunc::new(move |interp, args| into_result(f.call(interp, args)));
+            builtins.insert(String::from(name), Object::Builtin(arity, result));
+        }
+    }
+}
+
+fn into_result(obj: Object) -> Object {
+    match obj {
+        Object::Error(e) if matches!(e.kind, ErrorKind::IOError | ErrorKind::ValueError) => {
+            Object::err(Object::String(e.message))
+        }
+        err @ Object::Error(_) => err,
+        o => Object::ok(o),
+    }
+}
+
 fn lang_len(args: Vec<Object>) -> Object {
     match &args[0] {
         Object::String(s) => Object::Int(s.len() as i64),
         Object::Array(o) => Object::Int(o.len() as i64),
-        o => Object::Error(format!("argument to `len` not supported, got {}", o)),
+        o => Object::error(
+            ErrorKind::TypeError,
+            format!("argument to `len` not supported, got {}", o),
+        ),
     }
 }
 
@@ -70,7 +144,10 @@ fn lang_first(args: Vec<Object>) -> Object {
                 Object::Null
             }
         }
-        o => Object::Error(format!("argument to `first` must be array. got {}", o)),
+        o => Object::error(
+            ErrorKind::TypeError,
+            format!("argument to `first` must be array. got {}", o),
+        ),
     }
 }
 
@@ -83,7 +160,10 @@ fn lang_last(args: Vec<Object>) -> Object {
                 Object::Null
             }
         }
-        o => Object::Error(format!("argument to `last` must be array. got {}", o)),
+        o => Object::error(
+            ErrorKind::TypeError,
+            format!("argument to `last` must be array. got {}", o),
+        ),
     }
 }
 
@@ -91,12 +171,15 @@ fn lang_tail(args: Vec<Object>) -> Object {
     match &args[0] {
         Object::Array(o) => {
             if !o.is_empty() {
-                Object::Array(o[1..].to_vec())
+                Object::Array(Rc::new(o[1..].to_vec()))
             } else {
                 Object::Null
             }
         }
-        o => Object::Error(format!("argument to `tail` must be array. got {}", o)),
+        o => Object::error(
+            ErrorKind::TypeError,
+            format!("argument to `tail` must be array. got {}", o),
+        ),
     }
 }
 
@@ -109,315 +192,267 @@ fn lang_get(args: Vec<Object>) -> Object {
                 Object::Null
             }
         }
-        (o1, o2) => Object::Error(format!(
-            "argument to `get` must be Array, Int. got {}, {}",
-            o1, o2
-        )),
+        (o1, o2) => Object::error(
+            ErrorKind::TypeError,
+            format!("argument to `get` must be Array, Int. got {}, {}", o1, o2),
+        ),
     }
 }
 
 fn lang_push(args: Vec<Object>) -> Object {
     match &args[0] {
         Object::Array(o) => {
-            let mut arr = o.clone();
+            let mut arr = o.to_vec();
             arr.push(args[1].clone());
-            Object::Array(arr)
+            Object::Array(Rc::new(arr))
         }
-        o => Object::Error(format!("argument to `push` must be array. got {}", o)),
+        o => Object::error(
+            ErrorKind::TypeError,
+            format!("argument to `push` must be array. got {}", o),
+        ),
     }
 }
 
 fn lang_reverse(args: Vec<Object>) -> Object {
     match &args[0] {
         Object::Array(o) => {
-            let mut new_arr = o.clone();
+            let mut new_arr = o.to_vec();
             new_arr.reverse();
-            Object::Array(new_arr)
+            Object::Array(Rc::new(new_arr))
         }
-        o => Object::Error(format!("argument to `reverse` must be Array. got {}", o)),
+        o => Object::error(
+            ErrorKind::TypeError,
+            format!("argument to `reverse` must be Array. got {}", o),
+        ),
     }
 }
 
 // Replaces all substrings
 fn lang_replace_substring(args: Vec<Object>) -> Object {
-    match (&args[0], &args[1], &args[2]) {
-        (Object::String(s1), Object::String(s2), Object::String(s3)) => {
-            let new_string = s1.replace(s2, s3);
-            Object::String(new_string)
-        }
-        (o1, o2, o3) => Object::Error(format!(
-            "argument to `replaceString` must be a String, String, String. got {}, {}, {}",
-            o1, o2, o3
-        )),
-    }
+    (|s: String, from: String, to: String| s.replace(&from, &to)).call_typed("replaceString", args)
 }
 
 // Replaces first N substrings
 fn lang_replace_n_substring(args: Vec<Object>) -> Object {
-    match (&args[0], &args[1], &args[2], &args[3]) {
-        (Object::String(s1), Object::String(s2), Object::String(s3), Object::Int(i)) => {
-            let new_string = s1.replacen(s2, s3, *i as usize);
-            Object::String(new_string)
-        }
-        (o1, o2, o3, o4) => Object::Error(format!(
-            "argument to `replaceN` must be a String, String, String, Int. got {}, {}, {}, {}",
-            o1, o2, o3, o4
-        )),
-    }
+    (|s: String, from: String, to: String, n: i64| s.replacen(&from, &to, n as usize))
+        .call_typed("replaceN", args)
 }
 
 // trim String
 fn lang_trim(args: Vec<Object>) -> Object {
-    match &args[0] {
-        Object::String(s) => {
-            let new_string = s.trim();
-            Object::String(new_string.to_string())
-        }
-        o => Object::Error(format!("argument to `trim` must be a String. got {}", o)),
-    }
+    (|s: String| s.trim().to_string()).call_typed("trim", args)
 }
 
 fn lang_explode(args: Vec<Object>) -> Object {
-    match &args[0] {
-        Object::String(s) => {
-            let new_vec = s.chars().map(|c| Object::String(String::from(c))).collect();
-            Object::Array(new_vec)
-        }
-        o => Object::Error(format!("argument to `expload` must be a String. got {}", o)),
-    }
+    (|s: String| s.chars().map(String::from).collect::<Vec<_>>()).call_typed("explode", args)
 }
 
 // String to lowercase
 fn lang_to_lowercase(args: Vec<Object>) -> Object {
-    match &args[0] {
-        Object::String(s) => {
-            let new_string = s.to_lowercase();
-            Object::String(new_string)
-        }
-        o => Object::Error(format!(
-            "argument to `lowercase` must be a String. got {}",
-            o
-        )),
-    }
+    (|s: String| s.to_lowercase()).call_typed("lowercase", args)
 }
 
 // String to lowercase
 fn lang_to_uppercase(args: Vec<Object>) -> Object {
-    match &args[0] {
-        Object::String(s) => {
-            let new_string = s.to_uppercase();
-            Object::String(new_string)
-        }
-        o => Object::Error(format!(
-            "argument to `uppercase` must be a String. got {}",
-            o
-        )),
-    }
+    (|s: String| s.to_uppercase()).call_typed("uppercase", args)
 }
 
 // Parse String to int
 fn lang_parse_number(args: Vec<Object>) -> Object {
+    (|s: String| match s.parse::<i64>() {
+        Ok(num) => Object::Int(num),
+        Err(_) => Object::error(ErrorKind::ValueError, String::from("could not parse int")),
+    })
+    .call_typed("parseNumber", args)
+}
+
+// Name of the argument's type, or the struct name for records
+fn lang_type_of(args: Vec<Object>) -> Object {
+    Object::String(args[0].type_name())
+}
+
+// Name of the variant an enum value was built with
+fn lang_tag_of(args: Vec<Object>) -> Object {
     match &args[0] {
-        Object::String(s) => match s.parse::<i64>() {
-            Ok(num) => Object::Int(num),
-            Err(_) => Object::Error(String::from("could not parse int")),
-        },
-        o => Object::Error(format!(
-            "argument to `uppercase` must be a String. got {}",
-            o
-        )),
+        Object::Variant(_, tag, _) => Object::String(tag.clone()),
+        o => Object::error(
+            ErrorKind::TypeError,
+            format!("argument to `tagOf` must be an enum value. got {}", o),
+        ),
     }
 }
 
-// Modify the 'read' builtin to use a function pointer
-fn lang_read(_args: Vec<Object>) -> Object {
-    // Create a handle to standard input
-    let mut input = String::new();
+// Checks for null without also matching false like `!x` does
+fn lang_is_null(args: Vec<Object>) -> Object {
+    Object::Bool(args[0] == Object::Null)
+}
 
-    // Read a line from stdin
-    read_from_stdin(&mut input);
-    Object::String(input.trim().to_string())
+fn lang_print(args: Vec<Object>) -> Object {
+    print_with(&StdHost, args)
 }
 
-fn lang_map(args: Vec<Object>) -> Object {
-    if args.len() != 2 {
-        return Object::Error(format!(
-            "wrong number of arguments to map: got={}, want=2",
-            args.len()
-        ));
-    }
+fn lang_read(args: Vec<Object>) -> Object {
+    read_with(&StdHost, args)
+}
 
-    match (&args[0], &args[1]) {
-        (Object::Array(arr), Object::Func(params, body, env)) => {
-            let mut new_array: Vec<Object> = Vec::new();
-
-            // We need to make sure the function accepts one argument
-            if params.len() != 1 {
-                return Object::Error(format!(
-                    "map function expects a function with exactly one parameter, got {} parameters",
-                    params.len()
-                ));
-            }
+fn lang_read_file(args: Vec<Object>) -> Object {
+    read_file_with(&StdHost, args)
+}
 
-            for item in arr {
-                // Create a new environment for each function call, with the closure env as outer
-                let mut scoped_env = Env::new_with_outer(Rc::clone(env));
-
-                // Bind the current array item to the function's parameter
-                let Ident(param_name) = params[0].clone();
-                scoped_env.set(param_name, item);
-
-                // Create a new evaluator with this scoped environment
-                let mut evaluator = Evaluator::new(Rc::new(RefCell::new(scoped_env)));
-
-                // Evaluate the function body
-                match evaluator.eval_block_stmt(body.clone()) {
-                    Some(Object::ReturnValue(value)) => new_array.push(*value),
-                    Some(obj) => {
-                        if let Object::Error(_) = obj {
-                            return obj;
-                        }
-                        new_array.push(obj);
-                    }
-                    None => new_array.push(Object::Null),
-                }
-            }
+fn lang_write_file(args: Vec<Object>) -> Object {
+    write_file_with(&StdHost, args)
+}
 
-            Object::Array(new_array)
-        }
-        (Object::Array(_), Object::Builtin(_, _)) => Object::Error(
-            "cannot use builtin functions with map yet; use a function literal".to_string(),
-        ),
-        (Object::Array(_), not_func) => Object::Error(format!(
-            "second argument to `map` must be a function, got {}",
-            not_func
-        )),
-        (not_array, _) => Object::Error(format!(
-            "first argument to `map` must be an array, got {}",
-            not_array
-        )),
+// Accesses denied by the sandbox are raised as a `PermissionError`
+fn io_error(context: &str, err: io::Error) -> Object {
+    let kind = match err.kind() {
+        io::ErrorKind::PermissionDenied => ErrorKind::PermissionError,
+        _ => ErrorKind::IOError,
+    };
+    Object::error(kind, format!("{}: {}", context, err))
+}
+
+fn print_with(host: &dyn Host, args: Vec<Object>) -> Object {
+    for arg in args {
+        host.print_line(&arg.to_string());
     }
+    Object::Null
 }
 
-fn lang_filter(args: Vec<Object>) -> Object {
-    if args.len() != 2 {
-        return Object::Error(format!(
-            "wrong number of arguments to filter: got={}, want=2",
-            args.len()
-        ));
+fn read_with(host: &dyn Host, _args: Vec<Object>) -> Object {
+    match host.read_line() {
+        Ok(input) => Object::String(input.trim().to_string()),
+        Err(err) => io_error("Error reading input", err),
     }
+}
 
-    match (&args[0], &args[1]) {
-        (Object::Array(arr), Object::Func(params, body, env)) => {
-            let mut new_array: Vec<Object> = Vec::new();
-
-            // We need to make sure the function accepts one argument
-            if params.len() != 1 {
-                return Object::Error(format!(
-                    "filter function expects a function with exactly one parameter, got {} parameters",
-                    params.len()
-                ));
-            }
+fn read_file_with(host: &dyn Host, args: Vec<Object>) -> Object {
+    (|path: String| match host.read_file(&path) {
+        Ok(content) => Object::String(content),
+        Err(err) => io_error("Error opening file", err),
+    })
+    .call_typed("readFile", args)
+}
 
-            for item in arr {
-                // Create a new environment for each function call, with the closure env as outer
-                let mut scoped_env = Env::new_with_outer(Rc::clone(env));
-
-                // Bind the current array item to the function's parameter
-                let Ident(param_name) = params[0].clone();
-                scoped_env.set(param_name, item);
-
-                // Create a new evaluator with this scoped environment
-                let mut evaluator = Evaluator::new(Rc::new(RefCell::new(scoped_env)));
-
-                // Evaluate the function body
-                let result = match evaluator.eval_block_stmt(body.clone()) {
-                    Some(Object::ReturnValue(value)) => *value,
-                    Some(obj) => {
-                        if let Object::Error(_) = obj {
-                            return obj;
-                        }
-                        obj
-                    }
-                    None => Object::Null,
-                };
-
-                // Only include the item if the function returns a truthy value
-                if Evaluator::is_truthy(result) {
-                    new_array.push(item.clone());
-                }
-            }
+fn write_file_with(host: &dyn Host, args: Vec<Object>) -> Object {
+    (|path: String, contents: String| match host.write_file(&path, &contents) {
+        Ok(_) => Object::Null,
+        Err(err) => io_error("Failed to write to file", err),
+    })
+    .call_typed("writeFile", args)
+}
 
-            Object::Array(new_array)
+fn lang_map(interp: &mut dyn Interp, args: Vec<Object>) -> Object {
+    let (items, func) = match list_and_callable("map", args) {
+        Ok(args) => args,
+        Err(err) => return err,
+    };
+
+    let mut new_array = Vec::with_capacity(items.len());
+    for item in items.iter() {
+        match interp.apply(func.clone(), vec![item.clone()]) {
+            obj @ Object::Error(_) => return obj,
+            obj => new_array.push(obj),
         }
-        (Object::Array(_), Object::Builtin(_, _)) => Object::Error(
-            "cannot use builtin functions with filter yet; use a function literal".to_string(),
-        ),
-        (Object::Array(_), not_func) => Object::Error(format!(
-            "second argument to `filter` must be a function, got {}",
-            not_func
-        )),
-        (not_array, _) => Object::Error(format!(
-            "first argument to `filter` must be an array, got {}",
-            not_array
-        )),
     }
-}
 
-// TODO
-fn lang_sort(_args: Vec<Object>) -> Object {
-    Object::Error(String::from("TODO: sort is not implemented yet"))
+    Object::Array(Rc::new(new_array))
 }
 
-// Build in function for reading from a file
-fn lang_read_file(args: Vec<Object>) -> Object {
-    let s = match args.first() {
-        Some(Object::String(s)) => s,
-        Some(o) => {
-            return Object::Error(format!(
-                "argument to `lang_read_file` must be a String. got {:?}",
-                o
-            ))
-        }
-        None => return Object::Error("No arguments provided".to_string()),
+fn lang_filter(interp: &mut dyn Interp, args: Vec<Object>) -> Object {
+    let (items, func) = match list_and_callable("filter", args) {
+        Ok(args) => args,
+        Err(err) => return err,
     };
 
-    match fs::read_to_string(s) {
-        Ok(content) => Object::String(content),
-        Err(err) => Object::Error(format!("Error opening file: {}", err)),
+    let mut new_array = Vec::new();
+    for item in items.iter() {
+        let result = interp.apply(func.clone(), vec![item.clone()]);
+        if let Object::Error(_) = result {
+            return result;
+        }
+        // Only include the item if the function returns a truthy value
+        if Evaluator::is_truthy(result) {
+            new_array.push(item.clone());
+        }
     }
+
+    Object::Array(Rc::new(new_array))
 }
 
-// Build in function for writing to a file
-fn lang_write_file(args: Vec<Object>) -> Object {
-    if args.len() < 2 {
-        return Object::Error("Expected 2 arguments for `writeFile`".to_string());
+/// Checks the arguments of a builtin taking a list and something to call on its items.
+fn list_and_callable(name: &str, args: Vec<Object>) -> Result<(Rc<Vec<Object>>, Object), Object> {
+    let got = args.len();
+    let mut args = args.into_iter();
+    match (args.next(), args.next()) {
+        (Some(Object::Array(items)), Some(func)) if func.is_callable() => Ok((items, func)),
+        (Some(Object::Array(_)), Some(not_func)) => Err(Object::error(
+            ErrorKind::TypeError,
+            format!(
+                "second argument to `{}` must be a function, got {}",
+                name, not_func
+            ),
+        )),
+        (Some(not_array), Some(_)) => Err(Object::error(
+            ErrorKind::TypeError,
+            format!(
+                "first argument to `{}` must be an array, got {}",
+                name, not_array
+            ),
+        )),
+        _ => Err(Object::error(
+            ErrorKind::ArityError,
+            format!("wrong number of arguments to {}: got={}, want=2", name, got),
+        )),
     }
+}
 
-    let (s1, s2) = match (&args[0], &args[1]) {
-        (Object::String(s1), Object::String(s2)) => (s1, s2),
-        (o1, o2) => {
-            return Object::Error(format!(
-                "argument to `writeFile` must be String and String. got {:?} and {:?}",
-                o1, o2
-            ))
-        }
-    };
+// TODO
+fn lang_sort(_args: Vec<Object>) -> Object {
+    Object::error(
+        ErrorKind::Error,
+        String::from("TODO: sort is not implemented yet"),
+    )
+}
 
-    let mut file = match File::create(s1) {
-        Ok(f) => f,
-        Err(err) => return Object::Error(format!("Failed to open file: {}", err)),
+#[test]
+fn test_result_mode_builtins() {
+    let mut builtins = new_builtins();
+    enable_result_mode(&mut builtins);
+
+    let parse = match builtins.get("parseNumber") {
+        Some(Object::Builtin(_, f)) => f.clone(),
+        o => panic!("parseNumber should be a builtin, got {:?}", o),
     };
+    let mut evaluator = Evaluator::new(super::Env::from(builtins).shared());
 
-    match file.write_all(s2.as_bytes()) {
-        Ok(_) => Object::Null,
-        Err(err) => Object::Error(format!("Failed to write to file: {}", err)),
-    }
+    assert_eq!(
+        Object::ok(Object::Int(42)),
+        parse.call(&mut evaluator, vec![Object::String(String::from("42"))])
+    );
+    assert_eq!(
+        Object::err(Object::String(String::from("could not parse int"))),
+        parse.call(&mut evaluator, vec![Object::String(String::from("4x2"))])
+    );
+}
+
+#[test]
+fn test_lang_read_file_result_missing_file() {
+    let mut builtins = new_builtins();
+    enable_result_mode(&mut builtins);
+
+    let read_file = builtins["readFile"].clone();
+    let mut evaluator = Evaluator::new(super::Env::from(builtins).shared());
+    let args = vec![Object::String(String::from("/no/such/file.be"))];
+    match Evaluator::apply_native(&mut evaluator, read_file, args) {
+        Object::Variant(_, tag, _) => assert_eq!("Err", tag),
+        o => panic!("Expected Err got {} instead", o),
+    };
 }
 
 #[test]
 fn test_lang_len_buildin() {
-    let input = vec![Object::Array(vec![Object::Int(2), Object::Int(2)])];
+    let input = vec![Object::Array(Rc::new(vec![Object::Int(2), Object::Int(2)]))];
     match lang_len(input) {
         Object::Int(i) => assert_eq!(i, 2),
         o => panic!("lang_len did return {} instead of Int", o),
@@ -426,11 +461,11 @@ fn test_lang_len_buildin() {
 
 #[test]
 fn test_lang_first_buildin_normal_array() {
-    let input = vec![Object::Array(vec![
+    let input = vec![Object::Array(Rc::new(vec![
         Object::Int(1),
         Object::Int(2),
         Object::Int(3),
-    ])];
+    ]))];
 
     match lang_first(input) {
         Object::Int(i) => assert_eq!(i, 1),
@@ -440,7 +475,7 @@ fn test_lang_first_buildin_normal_array() {
 
 #[test]
 fn test_lang_first_buildin_empty_array() {
-    let input = vec![Object::Array(Vec::new())];
+    let input = vec![Object::Array(Rc::new(Vec::new()))];
 
     match lang_first(input) {
         Object::Null => (),
@@ -450,11 +485,11 @@ fn test_lang_first_buildin_empty_array() {
 
 #[test]
 fn test_lang_last_buildin_normal_array() {
-    let input = vec![Object::Array(vec![
+    let input = vec![Object::Array(Rc::new(vec![
         Object::Int(1),
         Object::Int(2),
         Object::Int(3),
-    ])];
+    ]))];
 
     match lang_last(input) {
         Object::Int(i) => assert_eq!(i, 3),
@@ -464,7 +499,7 @@ fn test_lang_last_buildin_normal_array() {
 
 #[test]
 fn test_lang_last_buildin_empty_array() {
-    let input = vec![Object::Array(Vec::new())];
+    let input = vec![Object::Array(Rc::new(Vec::new()))];
 
     match lang_last(input) {
         Object::Null => (),
@@ -474,22 +509,20 @@ fn test_lang_last_buildin_empty_array() {
 
 #[test]
 fn test_lang_tail_buildin_normal_array() {
-    let input = vec![Object::Array(vec![
+    let input = vec![Object::Array(Rc::new(vec![
         Object::Int(1),
         Object::Int(2),
         Object::Int(3),
-    ])];
+    ]))];
 
-    let input_tail = Object::Array(vec![Object::Int(2), Object::Int(3)]);
+    let input_tail = Object::Array(Rc::new(vec![Object::Int(2), Object::Int(3)]));
 
-    match lang_tail(input) {
-        o => assert_eq!(input_tail, o),
-    };
+    assert_eq!(input_tail, lang_tail(input));
 }
 
 #[test]
 fn test_lang_tail_buildin_empty_array() {
-    let input = vec![Object::Array(Vec::new())];
+    let input = vec![Object::Array(Rc::new(Vec::new()))];
 
     match lang_last(input) {
         Object::Null => (),
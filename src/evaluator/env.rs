@@ -2,14 +2,19 @@
 // Use of this source code is governed by a MIT
 // license that can be found in the LICENSE file
 
+use crate::ast::Slot;
 use crate::evaluator::object::*;
+use crate::gc::track_env;
 use std::cell::RefCell;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::rc::Rc;
 
 #[derive(PartialEq, Clone, Debug)]
 pub struct Env {
     store: HashMap<String, Object>,
+    consts: HashSet<String>,
+    // Slots of a function or `catch` scope, numbered by the resolver
+    locals: Vec<Option<Object>>,
     outer: Option<Rc<RefCell<Env>>>,
 }
 
@@ -17,17 +22,34 @@ impl Env {
     pub fn new() -> Self {
         Env {
             store: HashMap::new(),
+            consts: HashSet::new(),
+            locals: vec![],
             outer: None,
         }
     }
 
     pub fn from(store: HashMap<String, Object>) -> Self {
-        Env { store, outer: None }
+        Env {
+            store,
+            consts: HashSet::new(),
+            locals: vec![],
+            outer: None,
+        }
+    }
+
+    /// Moves the environment behind a shared pointer, where
+    /// [`collect_cycles`](crate::gc::collect_cycles) can reclaim it.
+    pub fn shared(self) -> Rc<RefCell<Env>> {
+        let env = Rc::new(RefCell::new(self));
+        track_env(&env);
+        env
     }
 
     pub fn new_with_outer(outer: Rc<RefCell<Env>>) -> Self {
         Env {
             store: HashMap::new(),
+            consts: HashSet::new(),
+            locals: vec![],
             outer: Some(outer),
         }
     }
@@ -42,9 +64,90 @@ impl Env {
         }
     }
 
+    /// Looks up a name in the outermost, global scope.
+    pub fn get_global(&self, name: &str) -> Option<Object> {
+        match self.outer {
+            Some(ref outer) => outer.borrow().get_global(name),
+            None => self.store.get(name).cloned(),
+        }
+    }
+
+    /// Names bound in the global scope.
+    pub fn global_names(&self) -> HashSet<String> {
+        match self.outer {
+            Some(ref outer) => outer.borrow().global_names(),
+            None => self.store.keys().cloned().collect(),
+        }
+    }
+
+    /// Reads a local variable, or `None` if its declaration has not run yet.
+    pub fn get_local(&self, slot: Slot) -> Option<Object> {
+        if slot.depth == 0 {
+            return self.locals.get(slot.index).cloned().flatten();
+        }
+
+        match self.outer {
+            Some(ref outer) => outer.borrow().get_local(Slot {
+                depth: slot.depth - 1,
+                index: slot.index,
+            }),
+            None => None,
+        }
+    }
+
+    /// Binds a local variable in this scope.
+    pub fn set_local(&mut self, index: usize, value: Object) {
+        if index >= self.locals.len() {
+            self.locals.resize(index + 1, None);
+        }
+        self.locals[index] = Some(value);
+    }
+
     pub fn set(&mut self, name: String, value: &Object) {
         self.store.insert(name, value.clone());
     }
+
+    /// Binds `name` in this scope, refusing to replace a constant.
+    ///
+    /// Constants can still be shadowed by bindings in an inner scope.
+    pub fn declare(&mut self, name: String, value: &Object, constant: bool) -> Result<(), String> {
+        if self.consts.contains(&name) {
+            return Err(format!("cannot redeclare constant: {}", name));
+        }
+
+        if constant {
+            self.consts.insert(name.clone());
+        }
+        self.set(name, value);
+        Ok(())
+    }
+
+    /// Binds `name` in this scope as a constant, replacing any earlier binding.
+    pub fn set_const(&mut self, name: String, value: &Object) {
+        self.consts.insert(name.clone());
+        self.set(name, value);
+    }
+
+    pub fn is_const(&self, name: &str) -> bool {
+        self.consts.contains(name)
+    }
+
+    /// Values bound in this scope, globals and locals alike.
+    pub(crate) fn values(&self) -> impl Iterator<Item = &Object> {
+        self.store.values().chain(self.locals.iter().flatten())
+    }
+
+    /// The scope this one is nested in.
+    pub(crate) fn outer(&self) -> Option<&Rc<RefCell<Env>>> {
+        self.outer.as_ref()
+    }
+
+    /// Marks every binding currently in this scope as constant.
+    ///
+    /// Used to protect the builtins and the prelude from scripts.
+    pub fn freeze(&mut self) {
+        self.consts.extend(self.store.keys().cloned());
+    }
 }
 
 impl Default for Env {
@@ -52,3 +155,36 @@ impl Default for Env {
         Self::new()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::evaluator::builtins::new_builtins;
+    use crate::evaluator::env::*;
+    use crate::evaluator::Evaluator;
+    use crate::lexer::Lexer;
+    use crate::parser::Parser;
+
+    fn parse(input: &str) -> Vec<crate::ast::Stmt> {
+        Parser::new(Lexer::new(input)).parse()
+    }
+
+    #[test]
+    fn test_reads_share_values() {
+        let env = Env::from(new_builtins()).shared();
+        let mut evaluator = Evaluator::new(Rc::clone(&env));
+        evaluator.eval(parse("let xs = [1, 2, 3]; let f = fun(ys) { [ys, ys] };"));
+
+        let arrays = |value| match value {
+            Some(Object::Array(items)) => items,
+            o => panic!("expected an array, got {:?}", o),
+        };
+        let global = arrays(env.borrow().get_global("xs"));
+        assert!(Rc::ptr_eq(&global, &arrays(env.borrow().get_global("xs"))));
+        assert!(Rc::ptr_eq(&global, &arrays(evaluator.eval(parse("xs")))));
+
+        // A parameter is read from its slot without copying the argument
+        let pair = arrays(evaluator.eval(parse("f(xs)")));
+        assert!(Rc::ptr_eq(&global, &arrays(Some(pair[0].clone()))));
+        assert!(Rc::ptr_eq(&global, &arrays(Some(pair[1].clone()))));
+    }
+}
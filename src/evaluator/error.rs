@@ -0,0 +1,134 @@
+// Copyright 2024 Dimitrios Papakonstantinou. All rights reserved.
+// Use of this source code is governed by a MIT
+// license that can be found in the LICENSE file
+
+use crate::ast::Span;
+use std::fmt;
+
+/// The category of a runtime error, reported as `e.kind` to scripts.
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum ErrorKind {
+    /// A value raised with `throw`.
+    Error,
+    TypeError,
+    NameError,
+    ArityError,
+    ValueError,
+    /// Indexing a value that can not be indexed, or a list out of range.
+    IndexError,
+    IOError,
+    /// Calls nested deeper than the evaluator's limit.
+    RecursionError,
+    /// An operation the interpreter's sandbox does not allow.
+    PermissionError,
+    /// A run used up its fuel, time or allocation budget. Can not be caught.
+    LimitError,
+    /// The run was stopped through an `InterruptHandle`. Can not be caught.
+    InterruptError,
+}
+
+impl ErrorKind {
+    /// Parses the name produced by `Display`, as found in a caught error's `kind`.
+    pub fn from_name(name: &str) -> Option<ErrorKind> {
+        match name {
+            "Error" => Some(ErrorKind::Error),
+            "TypeError" => Some(ErrorKind::TypeError),
+            "NameError" => Some(ErrorKind::NameError),
+            "ArityError" => Some(ErrorKind::ArityError),
+            "ValueError" => Some(ErrorKind::ValueError),
+            "IndexError" => Some(ErrorKind::IndexError),
+            "IOError" => Some(ErrorKind::IOError),
+            "RecursionError" => Some(ErrorKind::RecursionError),
+            "PermissionError" => Some(ErrorKind::PermissionError),
+            "LimitError" => Some(ErrorKind::LimitError),
+            "InterruptError" => Some(ErrorKind::InterruptError),
+            _ => None,
+        }
+    }
+
+    /// Whether the error aborts the run, unwinding past `catch` and `finally` blocks.
+    pub fn is_fatal(self) -> bool {
+        matches!(self, ErrorKind::LimitError | ErrorKind::InterruptError)
+    }
+}
+
+impl fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ErrorKind::Error => write!(f, "Error"),
+            ErrorKind::TypeError => write!(f, "TypeError"),
+            ErrorKind::NameError => write!(f, "NameError"),
+            ErrorKind::ArityError => write!(f, "ArityError"),
+            ErrorKind::ValueError => write!(f, "ValueError"),
+            ErrorKind::IndexError => write!(f, "IndexError"),
+            ErrorKind::IOError => write!(f, "IOError"),
+            ErrorKind::RecursionError => write!(f, "RecursionError"),
+            ErrorKind::PermissionError => write!(f, "PermissionError"),
+            ErrorKind::LimitError => write!(f, "LimitError"),
+            ErrorKind::InterruptError => write!(f, "InterruptError"),
+        }
+    }
+}
+
+/// A function call the error unwound through.
+#[derive(PartialEq, Clone, Debug)]
+pub struct Frame {
+    pub function: String,
+    pub span: Span,
+}
+
+impl fmt::Display for Frame {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "at {} ({})", self.function, self.span)
+    }
+}
+
+#[derive(PartialEq, Clone, Debug)]
+pub struct RuntimeError {
+    pub kind: ErrorKind,
+    pub message: String,
+    /// Calls the error unwound through, innermost first.
+    pub stack: Vec<Frame>,
+}
+
+impl RuntimeError {
+    pub fn new(kind: ErrorKind, message: String) -> Self {
+        RuntimeError {
+            kind,
+            message,
+            stack: vec![],
+        }
+    }
+
+    /// Formats the error with its call stack, most recent call last.
+    pub fn traceback(&self) -> String {
+        let mut result = String::new();
+        if !self.stack.is_empty() {
+            result.push_str("Traceback (most recent call last):\n");
+            let mut frames = self.stack.iter().rev().peekable();
+            while let Some(frame) = frames.next() {
+                result.push_str(&format!("  {}\n", frame));
+
+                // Collapse runs of the same call, as left by deep recursion
+                let mut repeated = 0;
+                while frames.next_if_eq(&frame).is_some() {
+                    repeated += 1;
+                }
+                if repeated > 0 {
+                    result.push_str(&format!(
+                        "  [previous frame repeated {} more times]\n",
+                        repeated
+                    ));
+                }
+            }
+        }
+        result.push_str(&self.to_string());
+        result
+    }
+}
+
+impl fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.kind, self.message)
+    }
+}
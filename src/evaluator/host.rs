@@ -0,0 +1,343 @@
+// Copyright 2024 Dimitrios Papakonstantinou. All rights reserved.
+// Use of this source code is governed by a MIT
+// license that can be found in the LICENSE file
+
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+use std::fs;
+use std::io::{self, BufRead};
+use std::path::{Component, Path, PathBuf};
+use std::rc::Rc;
+
+/// The outside world as seen by the `print`, `read`, `readFile` and
+/// `writeFile` builtins.
+///
+/// Embedders can implement this to send a script's output somewhere other
+/// than the process's stdout, or to give it a virtual filesystem. Methods take
+/// `&self` as the host is shared by the builtins, so a host that keeps state
+/// needs interior mutability, as [`MemoryHost`] does.
+pub trait Host {
+    /// Writes a line of output, as printed by `print`.
+    fn print_line(&self, line: &str);
+
+    /// Reads a line of input for `read`, with its line ending. Returns an
+    /// empty string at the end of input.
+    fn read_line(&self) -> io::Result<String>;
+
+    fn read_file(&self, path: &str) -> io::Result<String>;
+
+    /// Writes `contents` to `path`, replacing the file if it exists.
+    fn write_file(&self, path: &str, contents: &str) -> io::Result<()>;
+}
+
+/// Uses the process's stdin and stdout, and the real filesystem.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct StdHost;
+
+impl Host for StdHost {
+    fn print_line(&self, line: &str) {
+        println!("{}", line);
+    }
+
+    fn read_line(&self) -> io::Result<String> {
+        let mut line = String::new();
+        io::stdin().lock().read_line(&mut line)?;
+        Ok(line)
+    }
+
+    fn read_file(&self, path: &str) -> io::Result<String> {
+        fs::read_to_string(path)
+    }
+
+    fn write_file(&self, path: &str, contents: &str) -> io::Result<()> {
+        fs::write(path, contents)
+    }
+}
+
+#[derive(Debug, Default)]
+struct Memory {
+    output: String,
+    input: VecDeque<String>,
+    files: HashMap<String, String>,
+}
+
+/// Keeps input, output and files in memory, for tests and for capturing
+/// what a script prints.
+///
+/// Clones share the same state, so a clone can be handed to an interpreter
+/// and the original used to inspect the output afterwards.
+#[derive(Debug, Default, Clone)]
+pub struct MemoryHost {
+    memory: Rc<RefCell<Memory>>,
+}
+
+impl MemoryHost {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Queues a line to be returned by `read`.
+    pub fn push_input(&self, line: &str) {
+        self.memory
+            .borrow_mut()
+            .input
+            .push_back(format!("{}\n", line));
+    }
+
+    /// Everything printed so far, a line per `print`ed value.
+    pub fn output(&self) -> String {
+        self.memory.borrow().output.clone()
+    }
+
+    pub fn set_file(&self, path: &str, contents: &str) {
+        self.memory
+            .borrow_mut()
+            .files
+            .insert(path.to_string(), contents.to_string());
+    }
+
+    pub fn file(&self, path: &str) -> Option<String> {
+        self.memory.borrow().files.get(path).cloned()
+    }
+}
+
+impl Host for MemoryHost {
+    fn print_line(&self, line: &str) {
+        let mut memory = self.memory.borrow_mut();
+        memory.output.push_str(line);
+        memory.output.push('\n');
+    }
+
+    fn read_line(&self) -> io::Result<String> {
+        Ok(self
+            .memory
+            .borrow_mut()
+            .input
+            .pop_front()
+            .unwrap_or_default())
+    }
+
+    fn read_file(&self, path: &str) -> io::Result<String> {
+        self.file(path).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::NotFound, format!("no such file: {}", path))
+        })
+    }
+
+    fn write_file(&self, path: &str, contents: &str) -> io::Result<()> {
+        self.set_file(path, contents);
+        Ok(())
+    }
+}
+
+/// The capabilities granted to sandboxed scripts. Everything not granted is
+/// denied, so `Sandbox::default()` allows no input and no file access.
+///
+/// Paths are matched against the allowed prefixes after resolving both as the
+/// filesystem would, following symbolic links, so a link inside an allowed
+/// directory does not give access to where it points. The part of a path that
+/// does not exist yet, such as a file about to be written, is only normalized.
+#[derive(Debug, Clone, Default)]
+pub struct Sandbox {
+    /// Allows `read` to take input from stdin.
+    pub stdin: bool,
+    /// Directories and files `readFile` may read.
+    pub read_paths: Vec<PathBuf>,
+    /// Directories and files `writeFile` may write.
+    pub write_paths: Vec<PathBuf>,
+}
+
+impl Sandbox {
+    /// Checks that `path` is under one of `allowed`, with `action` naming the
+    /// attempted access in the error.
+    fn check(allowed: &[PathBuf], action: &str, path: &str) -> io::Result<()> {
+        let path = resolve(Path::new(path));
+        if allowed
+            .iter()
+            .any(|prefix| path.starts_with(resolve(prefix)))
+        {
+            Ok(())
+        } else {
+            Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!(
+                    "{} {} is not allowed by the sandbox",
+                    action,
+                    path.display()
+                ),
+            ))
+        }
+    }
+}
+
+/// Canonicalizes the longest leading part of `path` that exists, and
+/// normalizes the rest.
+fn resolve(path: &Path) -> PathBuf {
+    let mut rest = vec![];
+    let mut existing = path;
+    loop {
+        // A relative path is resolved from the current directory
+        let dir = if existing.as_os_str().is_empty() {
+            Path::new(".")
+        } else {
+            existing
+        };
+        if let Ok(mut resolved) = dir.canonicalize() {
+            resolved.extend(rest.iter().rev());
+            return normalize(&resolved);
+        }
+
+        match (existing.parent(), existing.components().next_back()) {
+            (Some(parent), Some(last)) => {
+                rest.push(last);
+                existing = parent;
+            }
+            _ => return normalize(path),
+        }
+    }
+}
+
+/// Resolves `.` and `..` components without touching the filesystem.
+fn normalize(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir
+                if matches!(
+                    normalized.components().next_back(),
+                    Some(Component::Normal(_))
+                ) =>
+            {
+                normalized.pop();
+            }
+            component => normalized.push(component),
+        }
+    }
+    normalized
+}
+
+/// Wraps a host, denying what the [`Sandbox`] does not allow.
+pub struct SandboxedHost<H> {
+    host: H,
+    sandbox: Sandbox,
+}
+
+impl<H: Host> SandboxedHost<H> {
+    pub fn new(host: H, sandbox: Sandbox) -> Self {
+        SandboxedHost { host, sandbox }
+    }
+}
+
+impl<H: Host> Host for SandboxedHost<H> {
+    fn print_line(&self, line: &str) {
+        self.host.print_line(line);
+    }
+
+    fn read_line(&self) -> io::Result<String> {
+        if !self.sandbox.stdin {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "reading input is not allowed by the sandbox",
+            ));
+        }
+        self.host.read_line()
+    }
+
+    fn read_file(&self, path: &str) -> io::Result<String> {
+        Sandbox::check(&self.sandbox.read_paths, "reading", path)?;
+        self.host.read_file(path)
+    }
+
+    fn write_file(&self, path: &str, contents: &str) -> io::Result<()> {
+        Sandbox::check(&self.sandbox.write_paths, "writing", path)?;
+        self.host.write_file(path, contents)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::evaluator::host::*;
+    use std::env;
+
+    #[test]
+    fn test_sandbox_paths() {
+        let allowed = vec![PathBuf::from("/srv/rules"), PathBuf::from("data")];
+        let tests = vec![
+            ("/srv/rules/a.be", true),
+            ("/srv/rules/sub/../b.be", true),
+            ("/srv/rules/../secret", false),
+            ("/srv/rules2/a.be", false),
+            ("/etc/passwd", false),
+            ("data/a.txt", true),
+            ("./data/a.txt", true),
+            ("data/../../data/a.txt", false),
+            ("../data/a.txt", false),
+        ];
+
+        for (path, expected) in tests {
+            let result = Sandbox::check(&allowed, "reading", path);
+            assert_eq!(expected, result.is_ok(), "{}", path);
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_sandbox_symlinks() {
+        use std::os::unix::fs::symlink;
+
+        let root = env::temp_dir().join(format!("beavieeer-sandbox-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        let allowed = root.join("allowed");
+        let outside = root.join("outside");
+        fs::create_dir_all(&allowed).unwrap();
+        fs::create_dir_all(&outside).unwrap();
+        fs::write(allowed.join("rules.be"), "1").unwrap();
+        fs::write(outside.join("secret.txt"), "hunter2").unwrap();
+        symlink(&outside, allowed.join("link")).unwrap();
+        symlink(&allowed, root.join("alias")).unwrap();
+
+        let path = |path: &Path| path.to_str().unwrap().to_string();
+        let prefixes = vec![allowed.clone()];
+        let tests = vec![
+            (allowed.join("rules.be"), true),
+            (allowed.join("new/file.txt"), true),
+            (allowed.join("link/secret.txt"), false),
+            (allowed.join("link/new.txt"), false),
+            (allowed.join("link/../outside/secret.txt"), false),
+            (root.join("alias/rules.be"), true),
+        ];
+        for (target, expected) in tests {
+            let result = Sandbox::check(&prefixes, "reading", &path(&target));
+            assert_eq!(expected, result.is_ok(), "{}", target.display());
+        }
+        assert!(Sandbox::check(
+            &[root.join("alias")],
+            "reading",
+            &path(&allowed.join("rules.be"))
+        )
+        .is_ok());
+
+        let host = SandboxedHost::new(
+            StdHost,
+            Sandbox {
+                read_paths: prefixes.clone(),
+                write_paths: prefixes,
+                ..Sandbox::default()
+            },
+        );
+        let secret = path(&allowed.join("link/secret.txt"));
+        let err = host.read_file(&secret).unwrap_err();
+        assert_eq!(io::ErrorKind::PermissionDenied, err.kind());
+        let err = host
+            .write_file(&path(&allowed.join("link/new.txt")), "x")
+            .unwrap_err();
+        assert_eq!(io::ErrorKind::PermissionDenied, err.kind());
+        assert!(!outside.join("new.txt").exists());
+        assert_eq!(
+            "1",
+            host.read_file(&path(&allowed.join("rules.be"))).unwrap()
+        );
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
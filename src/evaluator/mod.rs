@@ -2,44 +2,138 @@
 // Use of this source code is governed by a MIT
 // license that can be found in the LICENSE file
 
+pub mod budget;
 pub mod builtins;
 pub mod env;
+pub mod error;
+pub mod host;
 pub mod object;
+#[cfg(feature = "serde")]
+mod serialize;
 
 use crate::ast::*;
+use crate::evaluator::budget::{Budget, InterruptHandle, Limits};
 use crate::evaluator::env::*;
+use crate::evaluator::error::*;
 use crate::evaluator::object::*;
+use crate::resolver::Resolver;
 use std::cell::RefCell;
 use std::collections::HashMap;
 use std::rc::Rc;
 
+/// Outcome of a function body: a value, or a call in tail position still to be made.
+enum Tail {
+    Value(Option<Object>),
+    Call(Object, Vec<Object>, Frame),
+}
+
+/// Value of a member, index or call expression as a link of a chain such as `a?.b.c()`.
+enum Link {
+    Value(Option<Object>),
+    /// A `?.` or `?[` earlier in the chain met null, so the rest of it is skipped.
+    Skipped,
+}
+
+/// Nested calls allowed before a `RecursionError` is raised.
+pub const DEFAULT_MAX_DEPTH: usize = 1000;
+
+/// Stack that must be left before a call, or the call moves to a new segment.
+const STACK_RED_ZONE: usize = 256 * 1024;
+
+/// Size of the stack segments calls move to once the current one runs low.
+const STACK_SEGMENT: usize = 8 * 1024 * 1024;
+
+/// Runs `call` on a new stack segment if the current one is nearly used up.
+///
+/// Calls recurse on the native stack, so without this deep recursion would
+/// overflow the stack of the embedding thread before `max_depth` is reached.
+pub(crate) fn grow_stack<R>(call: impl FnOnce() -> R) -> R {
+    stacker::maybe_grow(STACK_RED_ZONE, STACK_SEGMENT, call)
+}
+
+/// The interpreter running a builtin, as the builtin sees it.
+pub trait Interp {
+    /// Calls any callable value with `args`, as a call in a script would.
+    ///
+    /// Functions, closures, builtins and struct or variant constructors are
+    /// all accepted, with their arity checked and `return` handled, so
+    /// higher-order builtins need no special cases.
+    fn apply(&mut self, func: Object, args: Vec<Object>) -> Object;
+
+    /// Looks up a name in the global scope.
+    fn get_global(&self, name: &str) -> Option<Object>;
+}
+
 #[derive(Debug)]
 pub struct Evaluator {
     env: Rc<RefCell<Env>>,
+    depth: usize,
+    max_depth: usize,
+    budget: Budget,
+    interrupt: InterruptHandle,
 }
 
 impl Evaluator {
     pub fn new(env: Rc<RefCell<Env>>) -> Self {
-        Evaluator { env }
+        Evaluator {
+            env,
+            depth: 0,
+            max_depth: DEFAULT_MAX_DEPTH,
+            budget: Budget::default(),
+            interrupt: InterruptHandle::new(),
+        }
+    }
+
+    /// Sets how deeply calls may nest before a `RecursionError` is raised.
+    ///
+    /// Calls get more stack as they need it, so any thread can run them.
+    pub fn set_max_depth(&mut self, max_depth: usize) {
+        self.max_depth = max_depth;
+    }
+
+    /// Starts a new run under `limits`, with the fuel, time and allocations
+    /// of earlier runs forgotten.
+    pub fn set_limits(&mut self, limits: &Limits) {
+        self.budget = Budget::new(limits);
     }
 
-    fn is_truthy(obj: Object) -> bool {
+    /// Makes calls stop with an `InterruptError` once `interrupt` is triggered.
+    pub fn set_interrupt(&mut self, interrupt: InterruptHandle) {
+        self.interrupt = interrupt;
+    }
+
+    /// Counts a list, hash or string just created against the allocation limit.
+    fn allocate(&mut self, obj: Object) -> Object {
+        match self.budget.allocate(&obj) {
+            Ok(()) => obj,
+            Err(err) => Object::Error(err),
+        }
+    }
+
+    pub(crate) fn is_truthy(obj: Object) -> bool {
         !matches!(obj, Object::Null | Object::Bool(false))
     }
 
-    fn error(msg: String) -> Object {
-        Object::Error(msg)
+    fn error(kind: ErrorKind, msg: String) -> Object {
+        Object::error(kind, msg)
     }
 
-    fn is_error(obj: &Object) -> bool {
-        matches!(obj, Object::Error(_))
+    /// Errors and early returns stop the evaluation of the enclosing expression.
+    fn is_abrupt(obj: &Object) -> bool {
+        matches!(obj, Object::Error(_) | Object::ReturnValue(_))
     }
 
     pub fn eval(&mut self, program: Program) -> Option<Object> {
+        let mut resolver = Resolver::new(self.env.borrow().global_names());
+        let program = resolver.resolve(program);
+        if let Some(err) = resolver.get_errors().into_iter().next() {
+            return Some(Object::Error(err));
+        }
+
         let mut result = None;
 
-        for stmt in program {
-            if stmt == Stmt::Blank {
+        for stmt in &program {
+            if *stmt == Stmt::Blank {
                 continue;
             }
 
@@ -53,11 +147,11 @@ impl Evaluator {
         result
     }
 
-    fn eval_block_stmt(&mut self, stmts: BlockStmt) -> Option<Object> {
+    fn eval_block_stmt(&mut self, stmts: &[Stmt]) -> Option<Object> {
         let mut result = None;
 
         for stmt in stmts {
-            if stmt == Stmt::Blank {
+            if *stmt == Stmt::Blank {
                 continue;
             }
 
@@ -71,24 +165,46 @@ impl Evaluator {
         result
     }
 
-    fn eval_stmt(&mut self, stmt: Stmt) -> Option<Object> {
+    fn eval_stmt(&mut self, stmt: &Stmt) -> Option<Object> {
         match stmt {
-            Stmt::Let(ident, expr) => {
-                let value = self.eval_expr(expr)?;
+            stmt @ (Stmt::Let(..) | Stmt::Const(..) | Stmt::Struct(..) | Stmt::Enum(..)) => {
+                let constant = matches!(stmt, Stmt::Const(..));
+                match self.eval_declaration(stmt) {
+                    Ok(bindings) => {
+                        for (name, value) in bindings {
+                            if let Some(err) = self.declare(name, &value, constant) {
+                                return Some(err);
+                            }
+                        }
+                        None
+                    }
+                    Err(o) => o,
+                }
+            }
+            Stmt::Local(slots, stmt) => match self.eval_declaration(stmt) {
+                Ok(bindings) => {
+                    let mut env = self.env.borrow_mut();
+                    for (&index, (_, value)) in slots.iter().zip(bindings) {
+                        env.set_local(index, value);
+                    }
+                    None
+                }
+                Err(o) => o,
+            },
+            Stmt::Expr(expr) => self.eval_expr(expr),
+            Stmt::Throw(expr) => {
+                let value = self.eval_expr(expr).unwrap_or(Object::Null);
 
-                if Self::is_error(&value) {
+                if Self::is_abrupt(&value) {
                     Some(value)
                 } else {
-                    let Ident(name) = ident;
-                    self.env.borrow_mut().set(name, &value);
-                    None
+                    Some(Object::Error(Self::thrown_error(value)))
                 }
             }
-            Stmt::Expr(expr) => self.eval_expr(expr),
             Stmt::Return(expr) => {
                 let value = self.eval_expr(expr)?;
 
-                if Self::is_error(&value) {
+                if Self::is_abrupt(&value) {
                     Some(value)
                 } else {
                     Some(Object::ReturnValue(Box::new(value)))
@@ -98,60 +214,210 @@ impl Evaluator {
         }
     }
 
+    /// Evaluates a declaration to the names it binds, in order.
+    ///
+    /// `Err` holds what the statement evaluates to instead: an error, an early
+    /// return, or nothing when the bound expression has no value.
+    fn eval_declaration(&mut self, stmt: &Stmt) -> Result<Vec<(String, Object)>, Option<Object>> {
+        match stmt {
+            Stmt::Let(Ident(name), expr) | Stmt::Const(Ident(name), expr) => {
+                let value = self.eval_expr(expr).ok_or(None)?;
+
+                if Self::is_abrupt(&value) {
+                    Err(Some(value))
+                } else {
+                    Ok(vec![(name.clone(), value)])
+                }
+            }
+            Stmt::Struct(Ident(name), fields) => {
+                let fields = fields.iter().map(|Ident(field)| field.clone()).collect();
+                let ctor = Object::StructCtor(name.clone(), fields);
+                Ok(vec![(name.clone(), ctor)])
+            }
+            Stmt::Enum(Ident(name), variants) => Ok(variants
+                .iter()
+                .map(|(Ident(tag), fields)| {
+                    let fields: Vec<String> = fields.iter().map(|Ident(f)| f.clone()).collect();
+                    // Variants without fields are values rather than constructors
+                    let value = if fields.is_empty() {
+                        Object::Variant(name.clone(), tag.clone(), vec![])
+                    } else {
+                        Object::VariantCtor(name.clone(), tag.clone(), fields)
+                    };
+                    (tag.clone(), value)
+                })
+                .collect()),
+            _ => Ok(vec![]),
+        }
+    }
+
+    fn declare(&mut self, name: String, value: &Object, constant: bool) -> Option<Object> {
+        match self.env.borrow_mut().declare(name, value, constant) {
+            Ok(()) => None,
+            Err(msg) => Some(Self::error(ErrorKind::NameError, msg)),
+        }
+    }
+
     #[inline]
-    fn eval_expr(&mut self, expr: Expr) -> Option<Object> {
+    fn eval_expr(&mut self, expr: &Expr) -> Option<Object> {
+        if let Err(err) = self.budget.step() {
+            return Some(Object::Error(err));
+        }
+
         match expr {
             Expr::Ident(ident) => Some(self.eval_ident(ident)),
+            Expr::Local(Ident(name), ..) => match self.lookup(expr) {
+                Some(value) => Some(value),
+                None => Some(Self::error(
+                    ErrorKind::NameError,
+                    format!("identifier not found: {}", name),
+                )),
+            },
             Expr::Literal(literal) => Some(self.eval_literal(literal)),
-            Expr::Prefix(prefix, right_expr) => self
-                .eval_expr(*right_expr)
-                .map(|right| self.eval_prefix_expr(prefix, right)),
-            Expr::Infix(infix, left_expr, right_expr) => {
-                let left = self.eval_expr(*left_expr);
-                let right = self.eval_expr(*right_expr);
-                if let (Some(l), Some(r)) = (left, right) {
-                    Some(self.eval_infix_expr(infix, l, r))
-                } else {
-                    None
+            Expr::Prefix(prefix, right_expr) => match self.eval_expr(right_expr)? {
+                right if Self::is_abrupt(&right) => Some(right),
+                right => Some(Self::eval_prefix_expr(*prefix, right)),
+            },
+            Expr::Infix(Infix::NullCoalesce, left_expr, right_expr) => {
+                match self.eval_expr(left_expr) {
+                    Some(Object::Null) | None => self.eval_expr(right_expr),
+                    left => left,
                 }
             }
-            Expr::Index(left_expr, index_expr) => {
-                let left = self.eval_expr(*left_expr);
-                let index = self.eval_expr(*index_expr);
-                if let (Some(l), Some(i)) = (left, index) {
-                    Some(self.eval_index_expr(l, i))
-                } else {
-                    None
-                }
+            Expr::Infix(infix, left_expr, right_expr) => {
+                let (l, r) = match self.eval_operands(left_expr, right_expr)? {
+                    Ok(operands) => operands,
+                    Err(abrupt) => return Some(abrupt),
+                };
+                Some(self.allocate(Self::eval_infix_expr(*infix, l, r)))
+            }
+            Expr::Index(..)
+            | Expr::Dot(..)
+            | Expr::OptionalIndex(..)
+            | Expr::OptionalDot(..)
+            | Expr::Call { .. } => match self.eval_link(expr) {
+                Link::Value(value) => value,
+                Link::Skipped => Some(Object::Null),
+            },
+            Expr::Method { .. } => {
+                unreachable!("methods are only resolved as the function of a call")
             }
             Expr::If {
                 cond,
                 consequence,
                 alternative,
-            } => self.eval_if_expr(*cond, consequence, alternative),
-            Expr::Func { params, body } => Some(Object::Func(params, body, Rc::clone(&self.env))),
-            Expr::Call { func, args } => Some(self.eval_call_expr(*func, args)),
+            } => self.eval_if_expr(cond, consequence, alternative.as_deref()),
+            Expr::Func { params, body } => Some(Object::Func(
+                Rc::clone(params),
+                Rc::clone(body),
+                Rc::clone(&self.env),
+            )),
+            Expr::Propagate(expr) => self.eval_expr(expr).map(Self::eval_propagate_expr),
+            Expr::Try {
+                body,
+                catch,
+                finally,
+            } => self.eval_try_expr(body, catch.as_ref(), finally.as_deref()),
+        }
+    }
+
+    /// Evaluates both operands of a binary expression, left to right.
+    ///
+    /// `Err` holds the first operand that was an error or early return.
+    fn eval_operands(
+        &mut self,
+        left: &Expr,
+        right: &Expr,
+    ) -> Option<Result<(Object, Object), Object>> {
+        let left = self.eval_expr(left)?;
+        if Self::is_abrupt(&left) {
+            return Some(Err(left));
+        }
+
+        let right = self.eval_expr(right)?;
+        if Self::is_abrupt(&right) {
+            return Some(Err(right));
+        }
+
+        Some(Ok((left, right)))
+    }
+
+    /// Evaluates a member, index or call expression, or any other expression
+    /// that starts a chain of them.
+    ///
+    /// Once a `?.` or `?[` meets null, the links after it are skipped, so that
+    /// `a?.b.c` is null rather than an error when `a` is null.
+    fn eval_link(&mut self, expr: &Expr) -> Link {
+        match expr {
+            Expr::Dot(left_expr, field) | Expr::OptionalDot(left_expr, field) => {
+                match self.eval_link(left_expr) {
+                    Link::Value(Some(Object::Null)) if matches!(expr, Expr::OptionalDot(..)) => {
+                        Link::Skipped
+                    }
+                    Link::Value(left) => {
+                        Link::Value(left.map(|left| Self::eval_dot_expr(left, field)))
+                    }
+                    Link::Skipped => Link::Skipped,
+                }
+            }
+            Expr::Index(left_expr, index_expr) | Expr::OptionalIndex(left_expr, index_expr) => {
+                let left = match self.eval_link(left_expr) {
+                    Link::Value(Some(Object::Null)) if matches!(expr, Expr::OptionalIndex(..)) => {
+                        return Link::Skipped
+                    }
+                    Link::Value(Some(left)) => left,
+                    link => return link,
+                };
+                if Self::is_abrupt(&left) {
+                    return Link::Value(Some(left));
+                }
+
+                Link::Value(self.eval_expr(index_expr).map(|index| {
+                    if Self::is_abrupt(&index) {
+                        index
+                    } else {
+                        Self::eval_index_expr(left, index)
+                    }
+                }))
+            }
+            Expr::Call { func, args, span } => self.eval_call_expr(func, args, *span),
+            expr => Link::Value(self.eval_expr(expr)),
+        }
+    }
+
+    /// Reads the variable `var` refers to, or `None` if it is not bound.
+    fn lookup(&self, var: &Expr) -> Option<Object> {
+        match *var {
+            Expr::Local(_, slot, ref fallback) => {
+                let value = self.env.borrow().get_local(slot);
+                value.or_else(|| fallback.as_ref().and_then(|var| self.lookup(var)))
+            }
+            Expr::Ident(Ident(ref name)) => self.env.borrow().get_global(name),
+            _ => None,
         }
     }
 
-    fn eval_ident(&mut self, ident: Ident) -> Object {
+    fn eval_ident(&mut self, ident: &Ident) -> Object {
         let Ident(name) = ident;
 
-        match self.env.borrow_mut().get(name.clone()) {
+        match self.env.borrow().get_global(name) {
             Some(value) => value,
-            None => Object::Error(format!("identifier not found: {}", name)),
+            None => Object::error(
+                ErrorKind::NameError,
+                format!("identifier not found: {}", name),
+            ),
         }
     }
 
-    fn eval_prefix_expr(&mut self, prefix: Prefix, right: Object) -> Object {
+    pub(crate) fn eval_prefix_expr(prefix: Prefix, right: Object) -> Object {
         match prefix {
-            Prefix::Not => self.eval_not_op_expr(right),
-            Prefix::Minus => self.eval_minus_prefix_op_expr(right),
-            Prefix::Plus => self.eval_plus_prefix_op_expr(right),
+            Prefix::Not => Self::eval_not_op_expr(right),
+            Prefix::Minus => Self::eval_minus_prefix_op_expr(right),
+            Prefix::Plus => Self::eval_plus_prefix_op_expr(right),
         }
     }
 
-    fn eval_not_op_expr(&mut self, right: Object) -> Object {
+    fn eval_not_op_expr(right: Object) -> Object {
         match right {
             Object::Bool(true) => Object::Bool(false),
             Object::Bool(false) => Object::Bool(true),
@@ -160,82 +426,154 @@ impl Evaluator {
         }
     }
 
-    fn eval_minus_prefix_op_expr(&mut self, right: Object) -> Object {
+    fn eval_minus_prefix_op_expr(right: Object) -> Object {
         match right {
             Object::Int(value) => Object::Int(-value),
-            _ => Self::error(format!("unknown operator: -{}", right)),
+            _ => Self::error(
+                ErrorKind::TypeError,
+                format!("unknown operator: -{}", right),
+            ),
         }
     }
 
-    fn eval_plus_prefix_op_expr(&mut self, right: Object) -> Object {
+    fn eval_plus_prefix_op_expr(right: Object) -> Object {
         match right {
             Object::Int(value) => Object::Int(value),
-            _ => Self::error(format!("unknown operator: {}", right)),
+            _ => Self::error(ErrorKind::TypeError, format!("unknown operator: {}", right)),
         }
     }
 
-    fn eval_infix_expr(&mut self, infix: Infix, left: Object, right: Object) -> Object {
+    pub(crate) fn eval_infix_expr(infix: Infix, left: Object, right: Object) -> Object {
+        if left == Object::Null || right == Object::Null {
+            return match infix {
+                Infix::Equal => Object::Bool(left == right),
+                Infix::NotEqual => Object::Bool(left != right),
+                _ => Self::error(
+                    ErrorKind::TypeError,
+                    format!("unknown operator: {} {} {}", left, infix, right),
+                ),
+            };
+        }
+
         match left {
             Object::Int(left_value) => {
                 if let Object::Int(right_value) = right {
-                    self.eval_infix_int_expr(infix, left_value, right_value)
+                    Self::eval_infix_int_expr(infix, left_value, right_value)
                 } else {
-                    Self::error(format!("type mismatch: {} {} {}", left, infix, right))
+                    Self::error(
+                        ErrorKind::TypeError,
+                        format!("type mismatch: {} {} {}", left, infix, right),
+                    )
                 }
             }
             Object::String(left_value) => {
                 if let Object::String(right_value) = right {
-                    self.eval_infix_string_expr(infix, left_value, right_value)
+                    Self::eval_infix_string_expr(infix, left_value, right_value)
                 } else {
-                    Self::error(format!("type mismatch: {} {} {}", left_value, infix, right))
+                    Self::error(
+                        ErrorKind::TypeError,
+                        format!("type mismatch: {} {} {}", left_value, infix, right),
+                    )
                 }
             }
             Object::Array(left_value) => {
                 if let Object::Array(right_value) = right {
-                    self.eval_infix_array_expr(infix, left_value, right_value)
+                    Self::eval_infix_array_expr(infix, &left_value, &right_value)
                 } else {
-                    Self::error("type mismatch".to_string())
+                    Self::error(ErrorKind::TypeError, "type mismatch".to_string())
                 }
             }
-            _ => Self::error(format!("unknown operator: {} {} {}", left, infix, right)),
+            Object::Struct(..) | Object::Variant(..) => match infix {
+                Infix::Equal => Object::Bool(left == right),
+                Infix::NotEqual => Object::Bool(left != right),
+                _ => Self::error(
+                    ErrorKind::TypeError,
+                    format!("unknown operator: {} {} {}", left, infix, right),
+                ),
+            },
+            _ => Self::error(
+                ErrorKind::TypeError,
+                format!("unknown operator: {} {} {}", left, infix, right),
+            ),
         }
     }
 
-    fn eval_index_expr(&mut self, left: Object, index: Object) -> Object {
+    pub(crate) fn eval_index_expr(left: Object, index: Object) -> Object {
         match left {
             Object::Array(ref array) => {
                 if let Object::Int(i) = index {
-                    self.eval_array_index_expr(array.clone(), i)
+                    Self::eval_array_index_expr(array, i)
                 } else {
-                    Self::error(format!("index operator not supported: {}", left))
+                    Self::error(
+                        ErrorKind::IndexError,
+                        format!("index operator not supported: {}", left),
+                    )
                 }
             }
             Object::Hash(ref hash) => match index {
-                Object::Int(_) | Object::Bool(_) | Object::String(_) => match hash.get(&index) {
+                Object::Int(_)
+                | Object::Bool(_)
+                | Object::String(_)
+                | Object::Struct(..)
+                | Object::Variant(..) => match hash.get(&index) {
                     Some(o) => o.clone(),
                     None => Object::Null,
                 },
                 Object::Error(_) => index,
-                _ => Self::error(format!("unusable as hash key: {}", index)),
+                _ => Self::error(
+                    ErrorKind::TypeError,
+                    format!("unusable as hash key: {}", index),
+                ),
             },
-            _ => Self::error(format!("uknown operator: {} {}", left, index)),
+            _ => Self::error(
+                ErrorKind::IndexError,
+                format!("uknown operator: {} {}", left, index),
+            ),
         }
     }
 
-    fn eval_array_index_expr(&mut self, array: Vec<Object>, index: i64) -> Object {
-        let max = array.len() as i64;
+    pub(crate) fn eval_dot_expr(left: Object, field: &Ident) -> Object {
+        let Ident(name) = field;
 
-        if index < 0 || index > max {
-            return Object::Null;
+        match left {
+            Object::ReturnValue(_) => left,
+            Object::Hash(ref hash) => match hash.get(&Object::String(name.clone())) {
+                Some(o) => o.clone(),
+                None => Object::Null,
+            },
+            Object::Struct(ref type_name, _) | Object::Variant(_, ref type_name, _) => {
+                match left.get_field(name) {
+                    Some(o) => o.clone(),
+                    None => Self::error(
+                        ErrorKind::NameError,
+                        format!("unknown field: {}.{}", type_name, name),
+                    ),
+                }
+            }
+            Object::Error(_) => left,
+            _ => Self::error(
+                ErrorKind::NameError,
+                format!("unknown field: {}.{}", left, name),
+            ),
         }
+    }
 
-        match array.get(index as usize) {
+    fn eval_array_index_expr(array: &[Object], index: i64) -> Object {
+        match usize::try_from(index).ok().and_then(|i| array.get(i)) {
             Some(o) => o.clone(),
-            None => Object::Null,
+            None => Self::error(
+                ErrorKind::IndexError,
+                format!(
+                    "index out of range: {} for a list of length {}",
+                    index,
+                    array.len()
+                ),
+            ),
         }
     }
 
-    fn eval_infix_int_expr(&mut self, infix: Infix, left: i64, right: i64) -> Object {
+    #[inline]
+    pub(crate) fn eval_infix_int_expr(infix: Infix, left: i64, right: i64) -> Object {
         match infix {
             Infix::Plus => Object::Int(left + right),
             Infix::Minus => Object::Int(left - right),
@@ -247,82 +585,106 @@ impl Evaluator {
             Infix::GreaterThanEqual => Object::Bool(left >= right),
             Infix::Equal => Object::Bool(left == right),
             Infix::NotEqual => Object::Bool(left != right),
+            // Only the left side is evaluated when it is not null
+            Infix::NullCoalesce => unreachable!("`??` is evaluated before its operands"),
         }
     }
 
-    fn eval_infix_string_expr(&mut self, infix: Infix, left: String, right: String) -> Object {
+    fn eval_infix_string_expr(infix: Infix, left: String, right: String) -> Object {
         match infix {
             Infix::Plus => Object::String(format!("{}{}", left, right)),
-            _ => Object::Error(format!("unknown operator: {} {} {}", left, infix, right)),
+            Infix::Equal => Object::Bool(left == right),
+            Infix::NotEqual => Object::Bool(left != right),
+            _ => Object::error(
+                ErrorKind::TypeError,
+                format!("unknown operator: {} {} {}", left, infix, right),
+            ),
         }
     }
 
-    fn eval_infix_array_expr(
-        &mut self,
-        infix: Infix,
-        left: Vec<Object>,
-        right: Vec<Object>,
-    ) -> Object {
+    fn eval_infix_array_expr(infix: Infix, left: &[Object], right: &[Object]) -> Object {
         match infix {
             Infix::Plus => {
                 let mut result = Vec::with_capacity(left.len() + right.len());
                 result.extend(left.iter().cloned());
                 result.extend(right.iter().cloned());
-                Object::Array(result)
+                Object::Array(Rc::new(result))
             }
-            _ => Object::Error(String::from("Error combining lists")),
+            _ => Object::error(ErrorKind::TypeError, String::from("Error combining lists")),
         }
     }
 
-    fn eval_literal(&mut self, literal: Literal) -> Object {
+    fn eval_literal(&mut self, literal: &Literal) -> Object {
         match literal {
-            Literal::Int(value) => Object::Int(value),
-            Literal::Bool(value) => Object::Bool(value),
-            Literal::String(value) => Object::String(value),
-            Literal::Array(objects) => self.eval_array_literal(objects),
-            Literal::Hash(pairs) => self.eval_hash_literal(pairs),
+            Literal::Int(value) => Object::Int(*value),
+            Literal::Bool(value) => Object::Bool(*value),
+            Literal::Null => Object::Null,
+            Literal::String(value) => Object::String(value.clone()),
+            Literal::Array(objects) => {
+                let array = self.eval_array_literal(objects);
+                self.allocate(array)
+            }
+            Literal::Hash(pairs) => {
+                let hash = self.eval_hash_literal(pairs);
+                self.allocate(hash)
+            }
         }
     }
 
-    fn eval_array_literal(&mut self, objects: Vec<Expr>) -> Object {
-        Object::Array(
-            objects
-                .iter()
-                .map(|e| self.eval_expr(e.clone()).unwrap_or(Object::Null))
-                .collect::<Vec<_>>(),
-        )
+    fn eval_array_literal(&mut self, objects: &[Expr]) -> Object {
+        match self.eval_exprs(objects) {
+            Ok(objects) => Object::Array(Rc::new(objects)),
+            Err(abrupt) => abrupt,
+        }
+    }
+
+    /// Evaluates expressions in order, stopping at the first error or early return.
+    fn eval_exprs(&mut self, exprs: &[Expr]) -> Result<Vec<Object>, Object> {
+        let mut objects = Vec::with_capacity(exprs.len());
+
+        for expr in exprs {
+            let object = self.eval_expr(expr).unwrap_or(Object::Null);
+            if Self::is_abrupt(&object) {
+                return Err(object);
+            }
+            objects.push(object);
+        }
+
+        Ok(objects)
     }
 
-    fn eval_hash_literal(&mut self, pairs: Vec<(Expr, Expr)>) -> Object {
+    fn eval_hash_literal(&mut self, pairs: &[(Expr, Expr)]) -> Object {
         #[allow(clippy::mutable_key_type)] //TODO: Reconsider
         let mut hash = HashMap::new();
 
         for (key_expr, value_expr) in pairs {
             let key = self.eval_expr(key_expr).unwrap_or(Object::Null);
-            if Self::is_error(&key) {
+            if Self::is_abrupt(&key) {
                 return key;
             }
 
             let value = self.eval_expr(value_expr).unwrap_or(Object::Null);
-            if Self::is_error(&value) {
+            if Self::is_abrupt(&value) {
                 return value;
             }
 
             hash.insert(key, value);
         }
 
-        Object::Hash(hash)
+        Object::Hash(Rc::new(hash))
     }
 
     fn eval_if_expr(
         &mut self,
-        cond: Expr,
-        consequence: BlockStmt,
-        alternative: Option<BlockStmt>,
+        cond: &Expr,
+        consequence: &[Stmt],
+        alternative: Option<&[Stmt]>,
     ) -> Option<Object> {
         let cond = self.eval_expr(cond)?;
 
-        if Self::is_truthy(cond) {
+        if Self::is_abrupt(&cond) {
+            Some(cond)
+        } else if Self::is_truthy(cond) {
             self.eval_block_stmt(consequence)
         } else if let Some(alt) = alternative {
             self.eval_block_stmt(alt)
@@ -331,56 +693,430 @@ impl Evaluator {
         }
     }
 
-    fn eval_call_expr(&mut self, func: Expr, args: Vec<Expr>) -> Object {
-        let args = args
+    /// Unwraps `Ok(v)` to `v`; `Err(e)` returns early from the current function.
+    pub(crate) fn eval_propagate_expr(result: Object) -> Object {
+        match result {
+            // A user-defined `Result` enum only counts if its variants look like `Ok` and `Err`
+            Object::Variant(ref name, ref tag, ref fields)
+                if name == "Result" && fields.len() == 1 && tag == "Ok" =>
+            {
+                fields[0].1.clone()
+            }
+            Object::Variant(ref name, ref tag, ref fields)
+                if name == "Result" && fields.len() == 1 && tag == "Err" =>
+            {
+                Object::ReturnValue(Box::new(result))
+            }
+            Object::Error(_) => result,
+            o => Self::error(
+                ErrorKind::TypeError,
+                format!("`?` expects a Result value, got {}", o),
+            ),
+        }
+    }
+
+    fn eval_try_expr(
+        &mut self,
+        body: &[Stmt],
+        catch: Option<&(Ident, BlockStmt)>,
+        finally: Option<&[Stmt]>,
+    ) -> Option<Object> {
+        let mut result = self.eval_block_stmt(body);
+        if Self::is_fatal_error(&result) {
+            return result;
+        }
+
+        if let (Some(Object::Error(err)), Some((_, handler))) = (&result, catch) {
+            // The caught error is only visible inside the catch block
+            let current_env = Rc::clone(&self.env);
+            let mut scoped_env = Env::new_with_outer(Rc::clone(&current_env));
+            scoped_env.set_local(0, Self::error_value(err));
+
+            self.env = scoped_env.shared();
+            result = self.eval_block_stmt(handler);
+            self.env = current_env;
+        }
+
+        if let Some(finally) = finally.filter(|_| !Self::is_fatal_error(&result)) {
+            // An error or return in `finally` replaces the outcome of the try
+            if let Some(o @ (Object::Error(_) | Object::ReturnValue(_))) =
+                self.eval_block_stmt(finally)
+            {
+                return Some(o);
+            }
+        }
+
+        result
+    }
+
+    /// Running out of a budget or being interrupted unwinds past `catch` and `finally` blocks.
+    fn is_fatal_error(result: &Option<Object>) -> bool {
+        matches!(result, Some(Object::Error(err)) if err.kind.is_fatal())
+    }
+
+    /// Builds the value bound by `catch (e)` from a runtime error.
+    ///
+    /// `stack` lists the frames outermost call first.
+    pub(crate) fn error_value(err: &RuntimeError) -> Object {
+        let stack = err
+            .stack
             .iter()
-            .map(|e| self.eval_expr(e.clone()).unwrap_or(Object::Null))
-            .collect::<Vec<_>>();
+            .rev()
+            .map(|frame| {
+                Object::Struct(
+                    String::from("Frame"),
+                    vec![
+                        (
+                            String::from("function"),
+                            Object::String(frame.function.clone()),
+                        ),
+                        (String::from("line"), Object::Int(frame.span.line as i64)),
+                        (
+                            String::from("column"),
+                            Object::Int(frame.span.column as i64),
+                        ),
+                    ],
+                )
+            })
+            .collect();
+
+        Object::Struct(
+            String::from("Error"),
+            vec![
+                (String::from("message"), Object::String(err.message.clone())),
+                (String::from("kind"), Object::String(err.kind.to_string())),
+                (String::from("stack"), Object::Array(Rc::new(stack))),
+            ],
+        )
+    }
 
-        let (params, body, env) = match self.eval_expr(func) {
-            Some(Object::Func(params, body, env)) => (params, body, env),
-            Some(Object::Builtin(expect_param_num, f)) => {
-                if expect_param_num < 0 || expect_param_num == args.len() as i32 {
-                    return f(args);
-                } else {
-                    return Self::error(format!(
-                        "wrong number of arguments. got={}, want={}",
-                        args.len(),
-                        expect_param_num,
-                    ));
-                }
+    /// Error raised by `throw value`. Rethrowing a caught error keeps its message and kind.
+    pub(crate) fn thrown_error(value: Object) -> RuntimeError {
+        match value {
+            Object::String(msg) => RuntimeError::new(ErrorKind::Error, msg),
+            Object::Struct(ref name, _) if name == "Error" => {
+                let kind = match value.get_field("kind") {
+                    Some(Object::String(kind)) => ErrorKind::from_name(kind),
+                    _ => None,
+                };
+                let message = match value.get_field("message") {
+                    Some(msg) => msg.to_string(),
+                    None => value.to_string(),
+                };
+                RuntimeError::new(kind.unwrap_or(ErrorKind::Error), message)
             }
-            Some(o) => return Self::error(format!("{} is not valid function", o)),
-            None => return Object::Null,
+            o => RuntimeError::new(ErrorKind::Error, o.to_string()),
+        }
+    }
+
+    fn eval_call_expr(&mut self, func: &Expr, args: &[Expr], span: Span) -> Link {
+        let frame = Frame {
+            function: Self::call_name(func),
+            span,
         };
 
-        if params.len() != args.len() {
-            return Self::error(format!(
-                "wrong number of arguments: {} expected but {} given",
-                params.len(),
-                args.len()
-            ));
+        match self.eval_callee(func, args) {
+            Ok((func, args)) => Link::Value(Some(self.call(func, args, Some(frame)))),
+            Err(link) => link,
+        }
+    }
+
+    /// Name reported in stack traces for a call to `func`.
+    pub(crate) fn call_name(func: &Expr) -> String {
+        match *func {
+            Expr::Ident(Ident(ref name))
+            | Expr::Local(Ident(ref name), ..)
+            | Expr::Dot(_, Ident(ref name))
+            | Expr::OptionalDot(_, Ident(ref name))
+            | Expr::Method {
+                name: Ident(ref name),
+                ..
+            } => name.clone(),
+            _ => String::from("<anonymous>"),
+        }
+    }
+
+    /// Evaluates the function and arguments of a call without applying it.
+    ///
+    /// `Err` holds the value of the whole call when it ends early: an error,
+    /// an early return, or a `?.` earlier in the chain that met null.
+    fn eval_callee(&mut self, func: &Expr, args: &[Expr]) -> Result<(Object, Vec<Object>), Link> {
+        let abrupt = |abrupt| Link::Value(Some(abrupt));
+        match func {
+            Expr::Method {
+                receiver,
+                name,
+                optional,
+                var,
+            } => {
+                let receiver = match self.eval_link(receiver) {
+                    Link::Value(receiver) => receiver.unwrap_or(Object::Null),
+                    Link::Skipped => return Err(Link::Skipped),
+                };
+                if Self::is_abrupt(&receiver) {
+                    return Err(abrupt(receiver));
+                }
+                if *optional && receiver == Object::Null {
+                    return Err(Link::Skipped);
+                }
+
+                let args = self.eval_exprs(args).map_err(abrupt)?;
+                self.resolve_method(receiver, name, var, args)
+                    .map_err(abrupt)
+            }
+            func => {
+                let args = self.eval_exprs(args).map_err(abrupt)?;
+                match self.eval_link(func) {
+                    Link::Value(Some(func)) if Self::is_abrupt(&func) => Err(abrupt(func)),
+                    Link::Value(Some(func)) => Ok((func, args)),
+                    Link::Value(None) => Err(abrupt(Object::Null)),
+                    Link::Skipped => Err(Link::Skipped),
+                }
+            }
+        }
+    }
+
+    /// Applies `func`, recording `frame`, if the call comes from a script, in
+    /// the stack trace of any error it raises.
+    fn call(&mut self, func: Object, args: Vec<Object>, frame: Option<Frame>) -> Object {
+        if self.depth >= self.max_depth {
+            return Object::Error(RuntimeError {
+                kind: ErrorKind::RecursionError,
+                message: String::from("maximum recursion depth exceeded"),
+                stack: frame.into_iter().collect(),
+            });
+        }
+
+        self.depth += 1;
+        let result = grow_stack(|| self.apply_func(func, args));
+        self.depth -= 1;
+
+        match result {
+            Object::Error(mut err) => {
+                err.stack.extend(frame);
+                Object::Error(err)
+            }
+            o => o,
+        }
+    }
+
+    /// Resolves `receiver.method(args)` to a function and its final arguments.
+    ///
+    /// A hash or struct whose `method` key holds a value is called with `args` unchanged,
+    /// otherwise the variable `var` the resolver found for `method` is called with the
+    /// receiver prepended, so `xs.map(f)` is the same as `map(xs, f)`.
+    fn resolve_method(
+        &mut self,
+        receiver: Object,
+        method: &Ident,
+        var: &Expr,
+        mut args: Vec<Object>,
+    ) -> Result<(Object, Vec<Object>), Object> {
+        let Ident(name) = method;
+
+        match receiver {
+            Object::Hash(ref hash) => {
+                if let Some(func) = hash.get(&Object::String(name.clone())) {
+                    return Ok((func.clone(), args));
+                }
+            }
+            Object::Struct(..) | Object::Variant(..) => {
+                if let Some(func) = receiver.get_field(name) {
+                    return Ok((func.clone(), args));
+                }
+            }
+            _ => {}
+        }
+
+        match self.lookup(var) {
+            Some(func) => {
+                args.insert(0, receiver);
+                Ok((func, args))
+            }
+            None => Err(Self::error(
+                ErrorKind::NameError,
+                format!("unknown method: {}", name),
+            )),
         }
+    }
 
-        let current_env = Rc::clone(&self.env);
-        let mut scoped_env = Env::new_with_outer(Rc::clone(&env));
-        let list = params.iter().zip(args.iter());
-        for (ident, o) in list {
-            let Ident(name) = ident.clone();
-            scoped_env.set(name, o);
+    fn apply_func(&mut self, func: Object, args: Vec<Object>) -> Object {
+        if let Err(err) = self.interrupt.check() {
+            return Object::Error(err);
         }
 
-        self.env = Rc::new(RefCell::new(scoped_env));
+        let (params, body, env) = match func {
+            Object::Func(params, body, env) => (params, body, env),
+            o => {
+                let result = Self::apply_native(self, o, args);
+                return self.allocate(result);
+            }
+        };
+
+        // Calls in tail position come back here instead of nesting, so loops
+        // written as tail recursion run in constant stack space
+        let (mut params, mut body, mut env, mut args) = (params, body, env, args);
+        let mut tail_frame = None;
+        let object = loop {
+            if tail_frame.is_some() {
+                if let Err(err) = self.interrupt.check() {
+                    break Some(Object::Error(err));
+                }
+            }
+
+            if params.len() != args.len() {
+                break Some(Self::error(
+                    ErrorKind::ArityError,
+                    format!(
+                        "wrong number of arguments: {} expected but {} given",
+                        params.len(),
+                        args.len()
+                    ),
+                ));
+            }
+
+            let current_env = Rc::clone(&self.env);
+            let mut scoped_env = Env::new_with_outer(Rc::clone(&env));
+            for (index, o) in args.into_iter().enumerate() {
+                scoped_env.set_local(index, o);
+            }
+
+            self.env = scoped_env.shared();
+
+            let tail = self.eval_tail_block(&body);
 
-        let object = self.eval_block_stmt(body);
+            self.env = current_env;
 
-        self.env = current_env;
+            match tail {
+                Tail::Call(Object::Func(p, b, e), a, frame) => {
+                    (params, body, env, args) = (p, b, e, a);
+                    tail_frame = Some(frame);
+                }
+                Tail::Call(..) => unreachable!("only functions are called in tail position"),
+                Tail::Value(object) => break object,
+            }
+        };
 
         match object {
+            Some(Object::ReturnValue(value)) => *value,
+            // Only the latest tail call is left to report
+            Some(Object::Error(mut err)) => {
+                err.stack.extend(tail_frame);
+                Object::Error(err)
+            }
             Some(o) => o,
             None => Object::Null,
         }
     }
+
+    /// Applies a builtin or a struct or variant constructor.
+    pub(crate) fn apply_native(interp: &mut dyn Interp, func: Object, args: Vec<Object>) -> Object {
+        match func {
+            Object::Builtin(expect_param_num, f) => {
+                if expect_param_num < 0 || expect_param_num == args.len() as i32 {
+                    f.call(interp, args)
+                } else {
+                    Self::error(
+                        ErrorKind::ArityError,
+                        format!(
+                            "wrong number of arguments. got={}, want={}",
+                            args.len(),
+                            expect_param_num,
+                        ),
+                    )
+                }
+            }
+            Object::StructCtor(name, fields) => {
+                if fields.len() != args.len() {
+                    return Self::error(
+                        ErrorKind::ArityError,
+                        format!(
+                            "wrong number of arguments: {} expected but {} given",
+                            fields.len(),
+                            args.len()
+                        ),
+                    );
+                }
+                Object::Struct(name, fields.into_iter().zip(args).collect())
+            }
+            Object::VariantCtor(name, tag, fields) => {
+                if fields.len() != args.len() {
+                    return Self::error(
+                        ErrorKind::ArityError,
+                        format!(
+                            "wrong number of arguments: {} expected but {} given",
+                            fields.len(),
+                            args.len()
+                        ),
+                    );
+                }
+                Object::Variant(name, tag, fields.into_iter().zip(args).collect())
+            }
+            o => Self::error(ErrorKind::TypeError, format!("{} is not valid function", o)),
+        }
+    }
+
+    /// Evaluates a function body, leaving a call in tail position to the caller.
+    fn eval_tail_block(&mut self, stmts: &[Stmt]) -> Tail {
+        let (last, init) = match stmts.iter().rposition(|stmt| *stmt != Stmt::Blank) {
+            Some(i) => (&stmts[i], &stmts[..i]),
+            None => return Tail::Value(None),
+        };
+
+        if let Some(o) = self.eval_block_stmt(init) {
+            if Self::is_abrupt(&o) {
+                return Tail::Value(Some(o));
+            }
+        }
+
+        match last {
+            Stmt::Expr(Expr::Call { func, args, span })
+            | Stmt::Return(Expr::Call { func, args, span }) => {
+                let frame = Frame {
+                    function: Self::call_name(func),
+                    span: *span,
+                };
+
+                match self.eval_callee(func, args) {
+                    Ok((func @ Object::Func(..), args)) => Tail::Call(func, args, frame),
+                    Ok((func, args)) => Tail::Value(Some(self.call(func, args, Some(frame)))),
+                    Err(Link::Value(value)) => Tail::Value(value),
+                    Err(Link::Skipped) => Tail::Value(Some(Object::Null)),
+                }
+            }
+            Stmt::Expr(Expr::If {
+                cond,
+                consequence,
+                alternative,
+            }) => {
+                let cond = match self.eval_expr(cond) {
+                    Some(cond) => cond,
+                    None => return Tail::Value(None),
+                };
+
+                if Self::is_abrupt(&cond) {
+                    Tail::Value(Some(cond))
+                } else if Self::is_truthy(cond) {
+                    self.eval_tail_block(consequence)
+                } else if let Some(alt) = alternative {
+                    self.eval_tail_block(alt)
+                } else {
+                    Tail::Value(None)
+                }
+            }
+            stmt => Tail::Value(self.eval_stmt(stmt)),
+        }
+    }
+}
+
+impl Interp for Evaluator {
+    fn apply(&mut self, func: Object, args: Vec<Object>) -> Object {
+        self.call(func, args, None)
+    }
+
+    fn get_global(&self, name: &str) -> Option<Object> {
+        self.env.borrow().get_global(name)
+    }
 }
 
 #[cfg(test)]
@@ -389,10 +1125,32 @@ mod tests {
     use crate::evaluator::*;
     use crate::lexer::Lexer;
     use crate::parser::Parser;
+    use crate::vm::Vm;
+
+    /// Evaluates `input`, checking that the bytecode VM agrees on the outcome.
+    fn eval_with_stack(input: &str) -> Option<Object> {
+        let expected = Evaluator::new(Rc::new(RefCell::new(Env::from(new_builtins()))))
+            .eval(Parser::new(Lexer::new(input)).parse());
+        let actual = Vm::new(Rc::new(RefCell::new(Env::from(new_builtins()))))
+            .eval(Parser::new(Lexer::new(input)).parse());
+
+        // Function values differ in representation between the two
+        if !matches!(expected, Some(Object::Func(..))) {
+            assert_eq!(expected, actual, "the VM disagrees on {}", input);
+        }
+        expected
+    }
 
+    /// Evaluates `input`, dropping the call stack of a resulting error
+    /// so tests can compare against `Object::error`.
     fn eval(input: &str) -> Option<Object> {
-        Evaluator::new(Rc::new(RefCell::new(Env::from(new_builtins()))))
-            .eval(Parser::new(Lexer::new(input)).parse())
+        match eval_with_stack(input) {
+            Some(Object::Error(mut err)) => {
+                err.stack.clear();
+                Some(Object::Error(err))
+            }
+            o => o,
+        }
     }
 
     #[test]
@@ -444,6 +1202,29 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_string_comparison() {
+        let tests = vec![
+            ("\"abc\" == \"abc\"", Some(Object::Bool(true))),
+            ("\"abc\" == \"abd\"", Some(Object::Bool(false))),
+            ("\"abc\" != \"abd\"", Some(Object::Bool(true))),
+            ("\"\" != \"\"", Some(Object::Bool(false))),
+            ("tagOf(Some(1)) == \"Some\"", Some(Object::Bool(true))),
+            (
+                "\"a\" < \"b\"",
+                Some(Object::error(
+                    ErrorKind::TypeError,
+                    String::from("unknown operator: a < b"),
+                )),
+            ),
+        ];
+
+        for (input, expect) in tests {
+            let input = format!("enum Option {{ Some(value), None }}; {}", input);
+            assert_eq!(expect, eval(&input), "{}", input);
+        }
+    }
+
     #[test]
     fn test_boolean_expr() {
         let tests = vec![
@@ -474,11 +1255,11 @@ mod tests {
         let input = "[1, 2 * 2, 3 + 3]";
 
         assert_eq!(
-            Some(Object::Array(vec![
+            Some(Object::Array(Rc::new(vec![
                 Object::Int(1),
                 Object::Int(4),
                 Object::Int(6),
-            ])),
+            ]))),
             eval(input),
         );
     }
@@ -499,8 +1280,34 @@ mod tests {
                 "let myArray = [1, 2, 3]; let i = myArray[0]; myArray[i];",
                 Some(Object::Int(2)),
             ),
-            ("[1, 2, 3][3]", Some(Object::Null)),
-            ("[1, 2, 3][-1]", Some(Object::Null)),
+            (
+                "[1, 2, 3][3]",
+                Some(Object::error(
+                    ErrorKind::IndexError,
+                    String::from("index out of range: 3 for a list of length 3"),
+                )),
+            ),
+            (
+                "[1, 2, 3][-1]",
+                Some(Object::error(
+                    ErrorKind::IndexError,
+                    String::from("index out of range: -1 for a list of length 3"),
+                )),
+            ),
+            (
+                "5[0]",
+                Some(Object::error(
+                    ErrorKind::IndexError,
+                    String::from("uknown operator: 5 0"),
+                )),
+            ),
+            (
+                "[1][\"a\"]",
+                Some(Object::error(
+                    ErrorKind::IndexError,
+                    String::from("index operator not supported: [1]"),
+                )),
+            ),
         ];
 
         for (input, expect) in tests {
@@ -522,6 +1329,7 @@ let two = "two";
 }
 "#;
 
+        #[allow(clippy::mutable_key_type)]
         let mut hash = HashMap::new();
         hash.insert(Object::String(String::from("one")), Object::Int(1));
         hash.insert(Object::String(String::from("two")), Object::Int(2));
@@ -530,7 +1338,7 @@ let two = "two";
         hash.insert(Object::Bool(true), Object::Int(5));
         hash.insert(Object::Bool(false), Object::Int(6));
 
-        assert_eq!(Some(Object::Hash(hash)), eval(input),);
+        assert_eq!(Some(Object::Hash(Rc::new(hash))), eval(input),);
     }
 
     #[test]
@@ -551,13 +1359,22 @@ let two = "two";
     }
 
     #[test]
-    fn test_not_operator() {
+    fn test_null_literal() {
         let tests = vec![
-            ("!true", Some(Object::Bool(false))),
-            ("!false", Some(Object::Bool(true))),
-            ("!!true", Some(Object::Bool(true))),
-            ("!!false", Some(Object::Bool(false))),
-            ("!!5", Some(Object::Bool(true))),
+            ("null", Some(Object::Null)),
+            ("null == null", Some(Object::Bool(true))),
+            ("first([]) == null", Some(Object::Bool(true))),
+            ("1 != null", Some(Object::Bool(true))),
+            ("isNull(null)", Some(Object::Bool(true))),
+            ("isNull(false)", Some(Object::Bool(false))),
+            ("isNull({}[\"missing\"])", Some(Object::Bool(true))),
+            (
+                "null + 1",
+                Some(Object::error(
+                    ErrorKind::TypeError,
+                    String::from("unknown operator: null + 1"),
+                )),
+            ),
         ];
 
         for (input, expect) in tests {
@@ -566,13 +1383,89 @@ let two = "two";
     }
 
     #[test]
-    fn test_if_else_expr() {
+    fn test_null_safe_operators() {
         let tests = vec![
-            ("if (true) { 10 }", Some(Object::Int(10))),
-            ("if (false) { 10 }", None),
-            ("if (1) { 10 }", Some(Object::Int(10))),
-            ("if (1 < 2) { 10 }", Some(Object::Int(10))),
-            ("if (1 > 2) { 10 }", None),
+            ("null ?? 5", Some(Object::Int(5))),
+            ("false ?? 5", Some(Object::Bool(false))),
+            ("{\"a\": 1}[\"b\"] ?? 0", Some(Object::Int(0))),
+            ("null ?? null ?? 3", Some(Object::Int(3))),
+            // Undefined names are reported before anything runs, even where
+            // they would not be evaluated
+            (
+                "1 ?? missing",
+                Some(Object::error(
+                    ErrorKind::NameError,
+                    String::from("identifier not found: missing"),
+                )),
+            ),
+            ("let a = null; a?.name", Some(Object::Null)),
+            (
+                "let a = {\"name\": \"Beaver\"}; a?.name",
+                Some(Object::String(String::from("Beaver"))),
+            ),
+            ("let a = null; a?[0]", Some(Object::Null)),
+            ("let a = [7]; a?[0]", Some(Object::Int(7))),
+            ("let a = null; a?.len()", Some(Object::Null)),
+            ("let a = [1]; a?.len()", Some(Object::Int(1))),
+            (
+                "let a = {\"b\": null}; a?.b?.c ?? \"none\"",
+                Some(Object::String(String::from("none"))),
+            ),
+            // A null left of `?.` skips the rest of the chain
+            ("let a = null; a?.b.c", Some(Object::Null)),
+            ("let a = null; a?.b?.c", Some(Object::Null)),
+            ("let a = null; a?.b.c[0].d", Some(Object::Null)),
+            ("let a = null; a?[0].b.len()", Some(Object::Null)),
+            ("let a = null; a?.b[0](1).c", Some(Object::Null)),
+            ("let a = null; (a?.b.c ?? 1) + 1", Some(Object::Int(2))),
+            ("fun(a) { a?.b[0](1) }(null)", Some(Object::Null)),
+            ("fun(a) { a?.b.c() }(null)", Some(Object::Null)),
+            (
+                "let a = {\"b\": {\"c\": [{\"d\": 4}]}}; a?.b.c[0].d",
+                Some(Object::Int(4)),
+            ),
+            (
+                "let fail = fun() { throw \"evaluated\" }; let a = null; [a?.b[fail()], a?.b.c(fail())]",
+                Some(Object::Array(Rc::new(vec![Object::Null, Object::Null]))),
+            ),
+            // Only a null left of the `?.` itself is skipped over
+            (
+                "let a = {\"b\": null}; a?.b.c",
+                Some(Object::error(
+                    ErrorKind::NameError,
+                    String::from("unknown field: null.c"),
+                )),
+            ),
+        ];
+
+        for (input, expect) in tests {
+            assert_eq!(expect, eval(input), "{}", input);
+        }
+    }
+
+    #[test]
+    fn test_not_operator() {
+        let tests = vec![
+            ("!true", Some(Object::Bool(false))),
+            ("!false", Some(Object::Bool(true))),
+            ("!!true", Some(Object::Bool(true))),
+            ("!!false", Some(Object::Bool(false))),
+            ("!!5", Some(Object::Bool(true))),
+        ];
+
+        for (input, expect) in tests {
+            assert_eq!(expect, eval(input));
+        }
+    }
+
+    #[test]
+    fn test_if_else_expr() {
+        let tests = vec![
+            ("if (true) { 10 }", Some(Object::Int(10))),
+            ("if (false) { 10 }", None),
+            ("if (1) { 10 }", Some(Object::Int(10))),
+            ("if (1 < 2) { 10 }", Some(Object::Int(10))),
+            ("if (1 > 2) { 10 }", None),
             ("if (1 > 2) { 10 } else { 20 }", Some(Object::Int(20))),
             ("if (1 < 2) { 10 } else { 20 }", Some(Object::Int(10))),
             ("if (1 <= 2) { 10 }", Some(Object::Int(10))),
@@ -586,6 +1479,54 @@ let two = "two";
         }
     }
 
+    #[test]
+    fn test_no_value() {
+        let not_found = |name: &str| {
+            Some(Object::error(
+                ErrorKind::NameError,
+                format!("identifier not found: {}", name),
+            ))
+        };
+        let tests = vec![
+            ("let x = if (false) { 1 }; x", not_found("x")),
+            ("fun() { let x = if (false) { 1 }; x }()", not_found("x")),
+            ("1 + if (false) { 1 }", None),
+            ("-if (false) { 1 }", None),
+            ("if (if (false) { true }) { 1 } else { 2 }", None),
+            (
+                "fun() { if (if (false) { true }) { 1 } else { 2 } }()",
+                Some(Object::Null),
+            ),
+            (
+                "fun(x){ if (x > 0) { return x; } -x }(-3)",
+                Some(Object::Null),
+            ),
+            (
+                "fun(x){ if (x > 0) { return x; } -x }(3)",
+                Some(Object::Int(3)),
+            ),
+            (
+                "fun() { return if (false) { 1 }; 2 }()",
+                Some(Object::Int(2)),
+            ),
+            ("fun() { let x = 1; }() == null", Some(Object::Bool(true))),
+            ("(if (false) { 1 }) ?? 3", Some(Object::Int(3))),
+            (
+                "[if (false) { 1 }, {}[1]]",
+                Some(Object::Array(Rc::new(vec![Object::Null, Object::Null]))),
+            ),
+            (
+                "try { throw if (false) { 1 } } catch (e) { e.message }",
+                Some(Object::String(String::from("null"))),
+            ),
+            ("try { if (false) { 1 } } finally { 2 }", None),
+        ];
+
+        for (input, expect) in tests {
+            assert_eq!(expect, eval(input), "{}", input);
+        }
+    }
+
     #[test]
     fn test_return_stmt() {
         let tests = vec![
@@ -627,6 +1568,68 @@ if (10 > 1) {
         }
     }
 
+    #[test]
+    fn test_const_stmt() {
+        let tests = vec![
+            ("const a = 5; a;", Some(Object::Int(5))),
+            (
+                "const a = 5; let f = fun() { let a = 1; a }; f() + a",
+                Some(Object::Int(6)),
+            ),
+            (
+                "const a = 5; let f = fun(a) { a }; f(2)",
+                Some(Object::Int(2)),
+            ),
+            ("let a = 5; const a = 6; a", Some(Object::Int(6))),
+        ];
+
+        for (input, expect) in tests {
+            assert_eq!(expect, eval(input));
+        }
+    }
+
+    #[test]
+    fn test_const_redeclared_at_runtime() {
+        let env = Rc::new(RefCell::new(Env::from(new_builtins())));
+        env.borrow_mut().freeze();
+        let mut evaluator = Evaluator::new(Rc::clone(&env));
+
+        let tests = vec![
+            (
+                "let len = 5;",
+                Some(Object::error(
+                    ErrorKind::NameError,
+                    String::from("cannot redeclare constant: len"),
+                )),
+            ),
+            (
+                "struct first { x }",
+                Some(Object::error(
+                    ErrorKind::NameError,
+                    String::from("cannot redeclare constant: first"),
+                )),
+            ),
+            ("const limit = 10;", None),
+            (
+                "let limit = 11;",
+                Some(Object::error(
+                    ErrorKind::NameError,
+                    String::from("cannot redeclare constant: limit"),
+                )),
+            ),
+            ("limit", Some(Object::Int(10))),
+            ("len([1, 2])", Some(Object::Int(2))),
+        ];
+
+        // Each line is parsed on its own, as in the REPL
+        for (input, expect) in tests {
+            assert_eq!(
+                expect,
+                evaluator.eval(Parser::new(Lexer::new(input)).parse())
+            );
+        }
+    }
+
     #[test]
     fn test_blank_stmt() {
         let tests = vec![
@@ -655,24 +1658,490 @@ identity(100);
         }
     }
 
+    #[test]
+    fn test_try_expr() {
+        let tests = vec![
+            ("try { 1 } catch (e) { 2 }", Some(Object::Int(1))),
+            ("try { foo } catch (e) { 2 }", Some(Object::Int(2))),
+            (
+                "try { foo } catch (e) { e.message }",
+                Some(Object::String(String::from("identifier not found: foo"))),
+            ),
+            (
+                "try { throw \"boom\"; 1 } catch (e) { e.message }",
+                Some(Object::String(String::from("boom"))),
+            ),
+            (
+                "try { throw 42; } catch (e) { e.message }",
+                Some(Object::String(String::from("42"))),
+            ),
+            (
+                "try { 1 + true } catch (e) { e.kind }",
+                Some(Object::String(String::from("TypeError"))),
+            ),
+            (
+                "try { throw \"x\"; } catch (e) { e.kind }",
+                Some(Object::String(String::from("Error"))),
+            ),
+            (
+                "try { try { len(1) } catch (e) { throw e; } } catch (e) { e.kind }",
+                Some(Object::String(String::from("TypeError"))),
+            ),
+            (
+                "try { throw \"x\"; } catch (e) { e.stack }",
+                Some(Object::Array(Rc::new(vec![]))),
+            ),
+            (
+                "let f = fun() { throw \"inner\"; }; try { f() } catch (e) { e.message }",
+                Some(Object::String(String::from("inner"))),
+            ),
+            (
+                "try { try { throw \"a\"; } catch (e) { throw e; } } catch (e) { e.message }",
+                Some(Object::String(String::from("a"))),
+            ),
+            (
+                "let e = 1; try { throw \"x\"; } catch (e) { 2 }; e",
+                Some(Object::Int(1)),
+            ),
+            (
+                "throw \"uncaught\"; 1",
+                Some(Object::error(ErrorKind::Error, String::from("uncaught"))),
+            ),
+            (
+                "let f = fun() { try { return 1; } finally { 2 } }; f()",
+                Some(Object::Int(1)),
+            ),
+            (
+                "try { 1 } finally { throw \"from finally\"; }",
+                Some(Object::error(
+                    ErrorKind::Error,
+                    String::from("from finally"),
+                )),
+            ),
+            (
+                "try { throw \"a\"; } finally { 2 }",
+                Some(Object::error(ErrorKind::Error, String::from("a"))),
+            ),
+        ];
+
+        for (input, expect) in tests {
+            assert_eq!(expect, eval(input));
+        }
+    }
+
+    #[test]
+    fn test_builtin_error_kinds() {
+        let tests = vec![
+            ("len([1], [2])", ErrorKind::ArityError),
+            ("len(1)", ErrorKind::TypeError),
+            ("parseNumber(\"one\")", ErrorKind::ValueError),
+            (
+                "readFile(\"/nonexistent/beavieeer/file\")",
+                ErrorKind::IOError,
+            ),
+            ("[1][1]", ErrorKind::IndexError),
+            ("missing", ErrorKind::NameError),
+        ];
+
+        for (input, kind) in tests {
+            match eval(input) {
+                Some(Object::Error(err)) => assert_eq!(kind, err.kind, "{}", input),
+                o => panic!("expected an error for {}, got {:?}", input, o),
+            }
+        }
+    }
+
+    #[test]
+    fn test_nested_traceback() {
+        let input = "let sum = fun(xs) {
+  if (len(xs) == 0) { 0 } else { first(xs) + sum(tail(xs)) }
+};
+let sums = fun(lists) { map(lists, sum) };
+sums([[1], [2, true]]);";
+
+        match eval_with_stack(input) {
+            // The call `map` makes to `sum` has no call site of its own
+            Some(Object::Error(err)) => assert_eq!(
+                "Traceback (most recent call last):
+  at sums (line 5, column 5)
+  at map (line 4, column 28)
+  at sum (line 2, column 49)
+TypeError: unknown operator: true + 0",
+                err.traceback()
+            ),
+            o => panic!("expected an error, got {:?}", o),
+        }
+    }
+
+    #[test]
+    fn test_error_stack_trace() {
+        let input = "let inner = fun(x) {
+  x + true
+};
+let outer = fun() {
+  inner(1)
+};
+outer();";
+
+        match eval_with_stack(input) {
+            Some(Object::Error(err)) => {
+                assert_eq!(ErrorKind::TypeError, err.kind);
+                assert_eq!(
+                    vec![
+                        Frame {
+                            function: String::from("inner"),
+                            span: Span::new(5, 8),
+                        },
+                        Frame {
+                            function: String::from("outer"),
+                            span: Span::new(7, 6),
+                        },
+                    ],
+                    err.stack
+                );
+                assert_eq!(
+                    "Traceback (most recent call last):
+  at outer (line 7, column 6)
+  at inner (line 5, column 8)
+TypeError: type mismatch: 1 + true",
+                    err.traceback()
+                );
+            }
+            o => panic!("expected an error, got {:?}", o),
+        }
+
+        let input = "let f = fun() { len(1) }; try { f() } catch (e) { e.stack }";
+        assert_eq!(
+            Some(Object::Array(Rc::new(vec![
+                Object::Struct(
+                    String::from("Frame"),
+                    vec![
+                        (String::from("function"), Object::String(String::from("f"))),
+                        (String::from("line"), Object::Int(1)),
+                        (String::from("column"), Object::Int(34)),
+                    ],
+                ),
+                Object::Struct(
+                    String::from("Frame"),
+                    vec![
+                        (
+                            String::from("function"),
+                            Object::String(String::from("len"))
+                        ),
+                        (String::from("line"), Object::Int(1)),
+                        (String::from("column"), Object::Int(20)),
+                    ],
+                ),
+            ]))),
+            eval(input)
+        );
+    }
+
+    #[test]
+    fn test_recursion_depth_limit() {
+        let mut evaluator = Evaluator::new(Rc::new(RefCell::new(Env::from(new_builtins()))));
+        evaluator.set_max_depth(10);
+        let mut eval = |input| evaluator.eval(Parser::new(Lexer::new(input)).parse());
+
+        let tests = vec![
+            (
+                "let down = fun(n) { if (n == 0) { 0 } else { 1 + down(n - 1) } }; down(9)",
+                Some(Object::Int(9)),
+            ),
+            (
+                "try { down(10) } catch (e) { e.kind + \": \" + e.message }",
+                Some(Object::String(String::from(
+                    "RecursionError: maximum recursion depth exceeded",
+                ))),
+            ),
+            ("down(5)", Some(Object::Int(5))),
+        ];
+
+        for (input, expect) in tests {
+            assert_eq!(expect, eval(input));
+        }
+
+        match eval("let forever = fun() { 1 + forever() }; forever()") {
+            Some(Object::Error(err)) => {
+                assert_eq!(ErrorKind::RecursionError, err.kind);
+                assert_eq!(11, err.stack.len());
+                assert!(err
+                    .traceback()
+                    .contains("[previous frame repeated 9 more times]"));
+            }
+            o => panic!("expected a recursion error, got {:?}", o),
+        }
+    }
+
+    #[test]
+    fn test_tail_calls() {
+        let tests = vec![
+            (
+                "let count = fun(n, acc) { if (n == 0) { acc } else { count(n - 1, acc + 1) } }; count(1000000, 0)",
+                Some(Object::Int(1000000)),
+            ),
+            (
+                "let even = fun(n) { if (n == 0) { return true; } odd(n - 1) };
+                 let odd = fun(n) { if (n == 0) { return false; } return even(n - 1); };
+                 even(100001)",
+                Some(Object::Bool(false)),
+            ),
+            (
+                "let fold = fun(f, init, lst) {
+                   if (len(lst) == 0) {
+                     init
+                   } else {
+                     let newInit = f(init, first(lst));
+                     fold(f, newInit, tail(lst));
+                   }
+                 };
+                 let range = fun(n, acc) { if (n == 0) { acc } else { range(n - 1, push(acc, n)) } };
+                 fold(fun(a, b) { a + b }, 0, range(1000, []))",
+                Some(Object::Int(500500)),
+            ),
+            (
+                "let h = {\"next\": fun(n) { if (n == 0) { \"done\" } else { h.next(n - 1) } }}; h.next(100000)",
+                Some(Object::String(String::from("done"))),
+            ),
+            (
+                "let f = fun(n) { if (n == 0) { len(1) } else { f(n - 1) } }; f(100000)",
+                Some(Object::error(
+                    ErrorKind::TypeError,
+                    String::from("argument to `len` not supported, got 1"),
+                )),
+            ),
+            ("foo(1)", Some(Object::error(ErrorKind::NameError, String::from("identifier not found: foo")))),
+        ];
+
+        for (input, expect) in tests {
+            assert_eq!(expect, eval(input));
+        }
+    }
+
+    #[test]
+    fn test_tail_call_stack_trace() {
+        let input = "let inner = fun(x) { x + true };
+let outer = fun(x) { inner(x) };
+outer(1);";
+
+        match eval_with_stack(input) {
+            Some(Object::Error(err)) => assert_eq!(
+                vec![
+                    Frame {
+                        function: String::from("inner"),
+                        span: Span::new(2, 27),
+                    },
+                    Frame {
+                        function: String::from("outer"),
+                        span: Span::new(3, 6),
+                    },
+                ],
+                err.stack
+            ),
+            o => panic!("expected an error, got {:?}", o),
+        }
+    }
+
+    #[test]
+    fn test_try_recovers_in_loop() {
+        let input = r#"
+let parse = fun(s) {
+  try {
+    if (s == "") { throw "empty"; }
+    parseNumber(s)
+  } catch (e) {
+    0
+  } finally {
+    "ignored"
+  }
+};
+map(["1", "", "x", "4"], parse)
+        "#;
+
+        assert_eq!(
+            Some(Object::Array(Rc::new(vec![
+                Object::Int(1),
+                Object::Int(0),
+                Object::Int(0),
+                Object::Int(4),
+            ]))),
+            eval(input)
+        );
+    }
+
+    #[test]
+    fn test_result_values() {
+        let tests = vec![
+            ("Ok(1)", Some(Object::ok(Object::Int(1)))),
+            (
+                "Err(\"bad\")",
+                Some(Object::err(Object::String(String::from("bad")))),
+            ),
+            ("Ok(1).value", Some(Object::Int(1))),
+            ("tagOf(Err(1))", Some(Object::String(String::from("Err")))),
+            (
+                "typeOf(Ok(1))",
+                Some(Object::String(String::from("Result"))),
+            ),
+            ("Ok(1)?", Some(Object::Int(1))),
+            (
+                "let half = fun(x) { if (x / 2 * 2 == x) { Ok(x / 2) } else { Err(\"odd\") } };
+                 let quarter = fun(x) { Ok(half(half(x)?)?) };
+                 [quarter(8), quarter(6)]",
+                Some(Object::Array(Rc::new(vec![
+                    Object::ok(Object::Int(2)),
+                    Object::err(Object::String(String::from("odd"))),
+                ]))),
+            ),
+            (
+                "let f = fun() { Err(1)?; 2 }; f()",
+                Some(Object::err(Object::Int(1))),
+            ),
+            (
+                "let f = fun() { let x = 1 + Err(2)?; [x, Err(3)?] }; f()",
+                Some(Object::err(Object::Int(2))),
+            ),
+            (
+                "5?",
+                Some(Object::error(
+                    ErrorKind::TypeError,
+                    String::from("`?` expects a Result value, got 5"),
+                )),
+            ),
+            // A user-defined enum named `Result` is not a result value
+            (
+                "enum Result { Nope, Other(a, b) }; let f = fun() { Nope? }; f()",
+                Some(Object::error(
+                    ErrorKind::TypeError,
+                    String::from("`?` expects a Result value, got Nope"),
+                )),
+            ),
+            (
+                "enum Result { Nope, Other(a, b) }; Other(1, 2)?",
+                Some(Object::error(
+                    ErrorKind::TypeError,
+                    String::from("`?` expects a Result value, got Other(1, 2)"),
+                )),
+            ),
+        ];
+
+        for (input, expect) in tests {
+            assert_eq!(expect, eval(input));
+        }
+    }
+
+    #[test]
+    fn test_return_only_leaves_callee() {
+        let input = "let f = fun() { return 1; }; let g = fun() { f(); 2 }; g()";
+
+        assert_eq!(Some(Object::Int(2)), eval(input));
+    }
+
     #[test]
     fn test_fn_object() {
         let input = "fun(x) { x + 2; };";
 
         assert_eq!(
             Some(Object::Func(
-                vec![Ident(String::from("x"))],
-                vec![Stmt::Expr(Expr::Infix(
+                Rc::new(vec![Ident(String::from("x"))]),
+                Rc::new(vec![Stmt::Expr(Expr::Infix(
                     Infix::Plus,
-                    Box::new(Expr::Ident(Ident(String::from("x")))),
+                    Box::new(Expr::Local(
+                        Ident(String::from("x")),
+                        Slot { depth: 0, index: 0 },
+                        None,
+                    )),
                     Box::new(Expr::Literal(Literal::Int(2))),
-                ))],
+                ))]),
                 Rc::new(RefCell::new(Env::from(new_builtins()))),
             )),
             eval(input),
         );
     }
 
+    #[test]
+    fn test_local_scopes() {
+        let tests = vec![
+            (
+                "let make = fun(a) { let b = a * 2; fun(c) { a + b + c } }; make(1)(10)",
+                Some(Object::Int(13)),
+            ),
+            (
+                "let f = fun() { let even = fun(n) { if (n == 0) { true } else { odd(n - 1) } }; let odd = fun(n) { if (n == 0) { false } else { even(n - 1) } }; even(4) }; f()",
+                Some(Object::Bool(true)),
+            ),
+            (
+                "let x = 1; let f = fun() { let x = 2; x }; f() + x",
+                Some(Object::Int(3)),
+            ),
+            (
+                "let f = fun(c) { if (c) { let y = 1; } y }; f(false)",
+                Some(Object::error(
+                    ErrorKind::NameError,
+                    String::from("identifier not found: y"),
+                )),
+            ),
+            (
+                "let x = 1; let f = fun() { let x = x + 1; x }; f()",
+                Some(Object::Int(2)),
+            ),
+            (
+                "let x = 1; let g = fun() { if (false) { let x = 3; } x }; g()",
+                Some(Object::Int(1)),
+            ),
+            (
+                "let f = fun(x) { fun(c) { if (c) { let x = 3; } x } }; [f(1)(false), f(1)(true)]",
+                Some(Object::Array(Rc::new(vec![Object::Int(1), Object::Int(3)]))),
+            ),
+            (
+                "let f = fun() { let g = fun() { let xs = [1, 2]; if (false) { let len = 0; } xs.len() }; g() }; f()",
+                Some(Object::Int(2)),
+            ),
+            (
+                "let f = fun() { enum Shape { Dot } struct P { x } [P(1).x, tagOf(Dot)] }; f()",
+                Some(Object::Array(Rc::new(vec![
+                    Object::Int(1),
+                    Object::String(String::from("Dot")),
+                ]))),
+            ),
+            (
+                "let f = fun() { let double = fun(x) { x * 2 }; 4.double() }; f()",
+                Some(Object::Int(8)),
+            ),
+        ];
+
+        for (input, expect) in tests {
+            assert_eq!(expect, eval(input));
+        }
+    }
+
+    #[test]
+    fn test_undefined_variable_before_execution() {
+        let not_found = Some(Object::error(
+            ErrorKind::NameError,
+            String::from("identifier not found: foo"),
+        ));
+        let tests = vec!["len(1); foo", "try { 1 } catch (e) { foo }"];
+
+        for input in tests {
+            assert_eq!(not_found, eval(input));
+        }
+
+        // Functions look globals up when they run
+        let tests = vec![
+            ("let f = fun() { foo }; len(1)", eval("len(1)")),
+            ("let f = fun() { foo }; f()", not_found),
+            (
+                "let f = fun() { foo }; let foo = 2; f()",
+                Some(Object::Int(2)),
+            ),
+        ];
+
+        for (input, expect) in tests {
+            assert_eq!(expect, eval(input), "{}", input);
+        }
+    }
+
     #[test]
     fn test_fn_application() {
         let tests = vec![
@@ -722,6 +2191,229 @@ addTwo(2);
         assert_eq!(Some(Object::Int(4)), eval(input));
     }
 
+    #[test]
+    fn test_dot_expr() {
+        let tests = vec![
+            (
+                "let person = {\"name\": \"Alice\"}; person.name",
+                Some(Object::String(String::from("Alice"))),
+            ),
+            ("{\"name\": \"Alice\"}.age", Some(Object::Null)),
+            ("{\"a\": {\"b\": 2}}.a.b", Some(Object::Int(2))),
+            (
+                "5.name",
+                Some(Object::error(
+                    ErrorKind::NameError,
+                    String::from("unknown field: 5.name"),
+                )),
+            ),
+        ];
+
+        for (input, expect) in tests {
+            assert_eq!(expect, eval(input));
+        }
+    }
+
+    #[test]
+    fn test_method_call_expr() {
+        let tests = vec![
+            (
+                "\"abc\".uppercase()",
+                Some(Object::String(String::from("ABC"))),
+            ),
+            ("[1, 2, 3].len()", Some(Object::Int(3))),
+            (
+                "[1, 2, 3].map(fun(x) { x * 2 }).filter(fun(x) { x > 2 })",
+                Some(Object::Array(Rc::new(vec![Object::Int(4), Object::Int(6)]))),
+            ),
+            (
+                "let add = fun(a, b) { a + b }; 1.add(2)",
+                Some(Object::Int(3)),
+            ),
+            (
+                "let obj = {\"double\": fun(x) { x * 2 }}; obj.double(4)",
+                Some(Object::Int(8)),
+            ),
+            (
+                "[1].nothing()",
+                Some(Object::error(
+                    ErrorKind::NameError,
+                    String::from("unknown method: nothing"),
+                )),
+            ),
+            (
+                "\"abc\".len(1)",
+                Some(Object::error(
+                    ErrorKind::ArityError,
+                    String::from("wrong number of arguments. got=2, want=1"),
+                )),
+            ),
+        ];
+
+        for (input, expect) in tests {
+            assert_eq!(expect, eval(input));
+        }
+    }
+
+    #[test]
+    fn test_struct() {
+        let point = |x, y| {
+            Object::Struct(
+                String::from("Point"),
+                vec![
+                    (String::from("x"), Object::Int(x)),
+                    (String::from("y"), Object::Int(y)),
+                ],
+            )
+        };
+        let tests = vec![
+            ("struct Point { x, y }; Point(1, 2)", Some(point(1, 2))),
+            ("struct Point { x, y }; Point(1, 2).y", Some(Object::Int(2))),
+            (
+                "struct Point { x, y }; Point(1, 2) == Point(1, 2)",
+                Some(Object::Bool(true)),
+            ),
+            (
+                "struct Point { x, y }; Point(1, 2) == Point(2, 1)",
+                Some(Object::Bool(false)),
+            ),
+            (
+                "struct Point { x, y }; typeOf(Point(1, 2))",
+                Some(Object::String(String::from("Point"))),
+            ),
+            (
+                "struct Point { x, y }; Point(1, 2).z",
+                Some(Object::error(
+                    ErrorKind::NameError,
+                    String::from("unknown field: Point.z"),
+                )),
+            ),
+            (
+                "struct Point { x, y }; Point(1)",
+                Some(Object::error(
+                    ErrorKind::ArityError,
+                    String::from("wrong number of arguments: 2 expected but 1 given"),
+                )),
+            ),
+            (
+                "struct Op { apply }; Op(fun(x) { x + 1 }).apply(1)",
+                Some(Object::Int(2)),
+            ),
+        ];
+
+        for (input, expect) in tests {
+            assert_eq!(expect, eval(input));
+        }
+    }
+
+    #[test]
+    fn test_struct_display() {
+        let input = "struct Point { x, y }; Point(1, \"a\")";
+
+        assert_eq!("Point { x: 1, y: a }", format!("{}", eval(input).unwrap()));
+    }
+
+    #[test]
+    fn test_enum() {
+        let tests = vec![
+            (
+                "enum Shape { Circle(r), Rect(w, h) }; Rect(1, 2)",
+                Some(Object::Variant(
+                    String::from("Shape"),
+                    String::from("Rect"),
+                    vec![
+                        (String::from("w"), Object::Int(1)),
+                        (String::from("h"), Object::Int(2)),
+                    ],
+                )),
+            ),
+            (
+                "enum Option { Some(v), None }; None",
+                Some(Object::Variant(
+                    String::from("Option"),
+                    String::from("None"),
+                    vec![],
+                )),
+            ),
+            (
+                "enum Shape { Circle(r), Rect(w, h) }; Circle(3).r",
+                Some(Object::Int(3)),
+            ),
+            (
+                "enum Shape { Circle(r), Rect(w, h) }; tagOf(Circle(3))",
+                Some(Object::String(String::from("Circle"))),
+            ),
+            (
+                "enum Shape { Circle(r), Rect(w, h) }; typeOf(Circle(3))",
+                Some(Object::String(String::from("Shape"))),
+            ),
+            (
+                "enum Shape { Circle(r), Rect(w, h) }; Circle(3) == Circle(3)",
+                Some(Object::Bool(true)),
+            ),
+            (
+                "enum Shape { Circle(r), Rect(w, h) }; Circle(3) != Circle(4)",
+                Some(Object::Bool(true)),
+            ),
+            (
+                "enum Shape { Circle(r), Rect(w, h) }; {Circle(1): \"one\"}[Circle(1)]",
+                Some(Object::String(String::from("one"))),
+            ),
+            (
+                "enum Shape { Circle(r), Rect(w, h) }; Circle(3).w",
+                Some(Object::error(
+                    ErrorKind::NameError,
+                    String::from("unknown field: Circle.w"),
+                )),
+            ),
+            (
+                "enum Shape { Circle(r), Rect(w, h) }; tagOf(Rect(1, 2)) == \"Rect\"",
+                Some(Object::Bool(true)),
+            ),
+            (
+                "tagOf(1)",
+                Some(Object::error(
+                    ErrorKind::TypeError,
+                    String::from("argument to `tagOf` must be an enum value. got 1"),
+                )),
+            ),
+        ];
+
+        for (input, expect) in tests {
+            assert_eq!(expect, eval(input));
+        }
+    }
+
+    #[test]
+    fn test_enum_display() {
+        let tests = vec![
+            ("enum Shape { Rect(w, h) }; Rect(1, 2)", "Rect(1, 2)"),
+            ("enum State { Idle, Busy(job) }; Idle", "Idle"),
+        ];
+
+        for (input, expect) in tests {
+            assert_eq!(expect, format!("{}", eval(input).unwrap()));
+        }
+    }
+
+    #[test]
+    fn test_type_of() {
+        let tests = vec![
+            ("typeOf(1)", "Int"),
+            ("typeOf(\"a\")", "String"),
+            ("typeOf(true)", "Bool"),
+            ("typeOf([])", "List"),
+            ("typeOf({})", "Hash"),
+            ("typeOf(fun() { 1 })", "Function"),
+            ("typeOf(len)", "Function"),
+            ("typeOf(first([]))", "Null"),
+        ];
+
+        for (input, expect) in tests {
+            assert_eq!(Some(Object::String(String::from(expect))), eval(input));
+        }
+    }
+
     #[test]
     fn test_builtin_functions() {
         let tests = vec![
@@ -732,123 +2424,140 @@ addTwo(2);
             ("len([1, 2, 3])", Some(Object::Int(3))),
             (
                 "len(1)",
-                Some(Object::Error(String::from(
-                    "argument to `len` not supported, got 1",
-                ))),
+                Some(Object::error(
+                    ErrorKind::TypeError,
+                    String::from("argument to `len` not supported, got 1"),
+                )),
             ),
             (
                 "len(\"one\", \"two\")",
-                Some(Object::Error(String::from(
-                    "wrong number of arguments. got=2, want=1",
-                ))),
+                Some(Object::error(
+                    ErrorKind::ArityError,
+                    String::from("wrong number of arguments. got=2, want=1"),
+                )),
             ),
             // first
             ("first([1, 2, 3])", Some(Object::Int(1))),
             ("first([])", Some(Object::Null)),
             (
                 "first([], [])",
-                Some(Object::Error(String::from(
-                    "wrong number of arguments. got=2, want=1",
-                ))),
+                Some(Object::error(
+                    ErrorKind::ArityError,
+                    String::from("wrong number of arguments. got=2, want=1"),
+                )),
             ),
             (
                 "first(\"string\")",
-                Some(Object::Error(String::from(
-                    "argument to `first` must be array. got string",
-                ))),
+                Some(Object::error(
+                    ErrorKind::TypeError,
+                    String::from("argument to `first` must be array. got string"),
+                )),
             ),
             (
                 "first(1)",
-                Some(Object::Error(String::from(
-                    "argument to `first` must be array. got 1",
-                ))),
+                Some(Object::error(
+                    ErrorKind::TypeError,
+                    String::from("argument to `first` must be array. got 1"),
+                )),
             ),
             // last
             ("last([1, 2, 3])", Some(Object::Int(3))),
             ("last([])", Some(Object::Null)),
             (
                 "last([], [])",
-                Some(Object::Error(String::from(
-                    "wrong number of arguments. got=2, want=1",
-                ))),
+                Some(Object::error(
+                    ErrorKind::ArityError,
+                    String::from("wrong number of arguments. got=2, want=1"),
+                )),
             ),
             (
                 "last(\"string\")",
-                Some(Object::Error(String::from(
-                    "argument to `last` must be array. got string",
-                ))),
+                Some(Object::error(
+                    ErrorKind::TypeError,
+                    String::from("argument to `last` must be array. got string"),
+                )),
             ),
             (
                 "last(1)",
-                Some(Object::Error(String::from(
-                    "argument to `last` must be array. got 1",
-                ))),
+                Some(Object::error(
+                    ErrorKind::TypeError,
+                    String::from("argument to `last` must be array. got 1"),
+                )),
             ),
             // tail
             (
                 "tail([1, 2, 3, 4])",
-                Some(Object::Array(vec![
+                Some(Object::Array(Rc::new(vec![
                     Object::Int(2),
                     Object::Int(3),
                     Object::Int(4),
-                ])),
+                ]))),
             ),
             (
                 "tail([2, 3, 4])",
-                Some(Object::Array(vec![Object::Int(3), Object::Int(4)])),
+                Some(Object::Array(Rc::new(vec![Object::Int(3), Object::Int(4)]))),
             ),
-            ("tail([4])", Some(Object::Array(vec![]))),
+            ("tail([4])", Some(Object::Array(Rc::new(vec![])))),
             ("tail([])", Some(Object::Null)),
             (
                 "tail([], [])",
-                Some(Object::Error(String::from(
-                    "wrong number of arguments. got=2, want=1",
-                ))),
+                Some(Object::error(
+                    ErrorKind::ArityError,
+                    String::from("wrong number of arguments. got=2, want=1"),
+                )),
             ),
             (
                 "tail(\"string\")",
-                Some(Object::Error(String::from(
-                    "argument to `tail` must be array. got string",
-                ))),
+                Some(Object::error(
+                    ErrorKind::TypeError,
+                    String::from("argument to `tail` must be array. got string"),
+                )),
             ),
             (
                 "tail(1)",
-                Some(Object::Error(String::from(
-                    "argument to `tail` must be array. got 1",
-                ))),
+                Some(Object::error(
+                    ErrorKind::TypeError,
+                    String::from("argument to `tail` must be array. got 1"),
+                )),
             ),
             // push
             (
                 "push([1, 2, 3], 4)",
-                Some(Object::Array(vec![
+                Some(Object::Array(Rc::new(vec![
                     Object::Int(1),
                     Object::Int(2),
                     Object::Int(3),
                     Object::Int(4),
-                ])),
+                ]))),
+            ),
+            (
+                "push([], 1)",
+                Some(Object::Array(Rc::new(vec![Object::Int(1)]))),
             ),
-            ("push([], 1)", Some(Object::Array(vec![Object::Int(1)]))),
             (
                 "let a = [1]; push(a, 2); a",
-                Some(Object::Array(vec![Object::Int(1)])),
+                Some(Object::Array(Rc::new(vec![Object::Int(1)]))),
             ),
             (
                 "push([], [], [])",
-                Some(Object::Error(String::from(
-                    "wrong number of arguments. got=3, want=2",
-                ))),
+                Some(Object::error(
+                    ErrorKind::ArityError,
+                    String::from("wrong number of arguments. got=3, want=2"),
+                )),
             ),
             (
                 "push(\"string\", 1)",
-                Some(Object::Error(String::from(
-                    "argument to `push` must be array. got string",
-                ))),
+                Some(Object::error(
+                    ErrorKind::TypeError,
+                    String::from("argument to `push` must be array. got string"),
+                )),
             ),
             (
                 "push(1, 1)",
-                Some(Object::Error(String::from(
-                    "argument to `push` must be array. got 1",
-                ))),
+                Some(Object::error(
+                    ErrorKind::TypeError,
+                    String::from("argument to `push` must be array. got 1"),
+                )),
             ),
         ];
 
@@ -862,33 +2571,45 @@ addTwo(2);
         let tests = vec![
             (
                 "5 + true",
-                Some(Object::Error(String::from("type mismatch: 5 + true"))),
+                Some(Object::error(
+                    ErrorKind::TypeError,
+                    String::from("type mismatch: 5 + true"),
+                )),
             ),
             (
                 "5 + true; 5;",
-                Some(Object::Error(String::from("type mismatch: 5 + true"))),
+                Some(Object::error(
+                    ErrorKind::TypeError,
+                    String::from("type mismatch: 5 + true"),
+                )),
             ),
             (
                 "-true",
-                Some(Object::Error(String::from("unknown operator: -true"))),
+                Some(Object::error(
+                    ErrorKind::TypeError,
+                    String::from("unknown operator: -true"),
+                )),
             ),
             (
                 "5; true + false; 5;",
-                Some(Object::Error(String::from(
-                    "unknown operator: true + false",
-                ))),
+                Some(Object::error(
+                    ErrorKind::TypeError,
+                    String::from("unknown operator: true + false"),
+                )),
             ),
             (
                 "if (10 > 1) { true + false; }",
-                Some(Object::Error(String::from(
-                    "unknown operator: true + false",
-                ))),
+                Some(Object::error(
+                    ErrorKind::TypeError,
+                    String::from("unknown operator: true + false"),
+                )),
             ),
             (
                 "\"Hello\" - \"World\"",
-                Some(Object::Error(String::from(
-                    "unknown operator: Hello - World",
-                ))),
+                Some(Object::error(
+                    ErrorKind::TypeError,
+                    String::from("unknown operator: Hello - World"),
+                )),
             ),
             (
                 r#"
@@ -898,19 +2619,24 @@ if (10 > 1) {
   }
   return 1;
 }"#,
-                Some(Object::Error(String::from(
-                    "unknown operator: true + false",
-                ))),
+                Some(Object::error(
+                    ErrorKind::TypeError,
+                    String::from("unknown operator: true + false"),
+                )),
             ),
             (
                 "foobar",
-                Some(Object::Error(String::from("identifier not found: foobar"))),
+                Some(Object::error(
+                    ErrorKind::NameError,
+                    String::from("identifier not found: foobar"),
+                )),
             ),
             (
                 "{\"name\": \"Beaver\"}[fun(x) { x }]",
-                Some(Object::Error(String::from(
-                    "unusable as hash key: fun(x) { ... }",
-                ))),
+                Some(Object::error(
+                    ErrorKind::TypeError,
+                    String::from("unusable as hash key: fun(x) { ... }"),
+                )),
             ),
         ];
 
@@ -4,26 +4,85 @@
 
 use crate::ast::*;
 use crate::evaluator::env::*;
+use crate::evaluator::error::*;
+use crate::evaluator::Interp;
+use crate::vm::code::Closure;
 use std::cell::RefCell;
 use std::collections::HashMap;
 use std::fmt;
 use std::hash::{Hash, Hasher};
 use std::rc::Rc;
 
-pub type BuiltinFunc = fn(Vec<Object>) -> Object;
+/// The signature of functions implemented in Rust.
+pub type NativeFn = dyn Fn(&mut dyn Interp, Vec<Object>) -> Object;
+
+/// A function implemented in Rust.
+///
+/// A native function gets the interpreter running it, so it can call back
+/// into the script, and may capture state of the host application.
+#[derive(Clone)]
+pub enum BuiltinFunc {
+    Plain(fn(Vec<Object>) -> Object),
+    /// A plain function that calls back into the script, like `map`.
+    HigherOrder(fn(&mut dyn Interp, Vec<Object>) -> Object),
+    Native(Rc<NativeFn>),
+}
+
+impl BuiltinFunc {
+    pub fn new(f: impl Fn(&mut dyn Interp, Vec<Object>) -> Object + 'static) -> Self {
+        BuiltinFunc::Native(Rc::new(f))
+    }
+
+    pub fn call(&self, interp: &mut dyn Interp, args: Vec<Object>) -> Object {
+        match *self {
+            BuiltinFunc::Plain(f) => f(args),
+            BuiltinFunc::HigherOrder(f) => f(interp, args),
+            BuiltinFunc::Native(ref f) => f(interp, args),
+        }
+    }
+}
+
+// A native function is only equal to itself
+#[allow(unpredictable_function_pointer_comparisons)]
+impl PartialEq for BuiltinFunc {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (BuiltinFunc::Plain(f), BuiltinFunc::Plain(g)) => f == g,
+            (BuiltinFunc::HigherOrder(f), BuiltinFunc::HigherOrder(g)) => f == g,
+            (BuiltinFunc::Native(f), BuiltinFunc::Native(g)) => Rc::ptr_eq(f, g),
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Debug for BuiltinFunc {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            BuiltinFunc::Plain(_) => f.write_str("Plain"),
+            BuiltinFunc::HigherOrder(_) => f.write_str("HigherOrder"),
+            BuiltinFunc::Native(_) => f.write_str("Native"),
+        }
+    }
+}
 
 #[derive(PartialEq, Clone, Debug)]
 pub enum Object {
     Int(i64),
     String(String),
     Bool(bool),
-    Array(Vec<Object>),
-    Hash(HashMap<Object, Object>),
-    Func(Vec<Ident>, BlockStmt, Rc<RefCell<Env>>),
+    Array(Rc<Vec<Object>>),
+    Hash(Rc<HashMap<Object, Object>>),
+    Func(Rc<Vec<Ident>>, Rc<BlockStmt>, Rc<RefCell<Env>>),
+    /// A function compiled by the bytecode VM.
+    Closure(Rc<Closure>),
     Builtin(i32, BuiltinFunc),
+    Struct(String, Vec<(String, Object)>),
+    StructCtor(String, Vec<String>),
+    Variant(String, String, Vec<(String, Object)>),
+    VariantCtor(String, String, Vec<String>),
     Null,
     ReturnValue(Box<Object>),
-    Error(String),
+    Error(RuntimeError),
 }
 
 impl fmt::Display for Object {
@@ -54,18 +113,41 @@ impl fmt::Display for Object {
                 }
                 write!(f, "{{{}}}", result)
             }
-            Object::Func(ref params, _, _) => {
+            Object::Func(ref params, _, _) => write_func(f, params),
+            Object::Closure(ref closure) => write_func(f, &closure.proto.params),
+            Object::Builtin(_, _) => write!(f, "[builtin function]"),
+            Object::Struct(ref name, ref fields) => {
+                if fields.is_empty() {
+                    return write!(f, "{} {{}}", name);
+                }
+
                 let mut result = String::new();
-                for (i, Ident(ref s)) in params.iter().enumerate() {
+                for (i, (field, value)) in fields.iter().enumerate() {
                     if i < 1 {
-                        result.push_str(&s.to_string());
+                        result.push_str(&format!("{}: {}", field, value));
                     } else {
-                        result.push_str(&format!(", {}", s));
+                        result.push_str(&format!(", {}: {}", field, value));
                     }
                 }
-                write!(f, "fun({}) {{ ... }}", result)
+                write!(f, "{} {{ {} }}", name, result)
             }
-            Object::Builtin(_, _) => write!(f, "[builtin function]"),
+            Object::StructCtor(ref name, _) => write!(f, "struct {}", name),
+            Object::Variant(_, ref tag, ref fields) => {
+                if fields.is_empty() {
+                    return write!(f, "{}", tag);
+                }
+
+                let mut result = String::new();
+                for (i, (_, value)) in fields.iter().enumerate() {
+                    if i < 1 {
+                        result.push_str(&format!("{}", value));
+                    } else {
+                        result.push_str(&format!(", {}", value));
+                    }
+                }
+                write!(f, "{}({})", tag, result)
+            }
+            Object::VariantCtor(ref name, ref tag, _) => write!(f, "{}.{}", name, tag),
             Object::Null => write!(f, "null"),
             Object::ReturnValue(ref value) => write!(f, "{}", value),
             Object::Error(ref value) => write!(f, "{}", value),
@@ -73,6 +155,103 @@ impl fmt::Display for Object {
     }
 }
 
+fn write_func(f: &mut fmt::Formatter, params: &[Ident]) -> fmt::Result {
+    let mut result = String::new();
+    for (i, Ident(ref s)) in params.iter().enumerate() {
+        if i < 1 {
+            result.push_str(&s.to_string());
+        } else {
+            result.push_str(&format!(", {}", s));
+        }
+    }
+    write!(f, "fun({}) {{ ... }}", result)
+}
+
+impl Object {
+    /// Returns the name of the value's type as reported by `typeOf`.
+    pub fn type_name(&self) -> String {
+        match *self {
+            Object::Int(_) => String::from("Int"),
+            Object::String(_) => String::from("String"),
+            Object::Bool(_) => String::from("Bool"),
+            Object::Array(_) => String::from("List"),
+            Object::Hash(_) => String::from("Hash"),
+            Object::Func(..)
+            | Object::Closure(..)
+            | Object::Builtin(..)
+            | Object::StructCtor(..)
+            | Object::VariantCtor(..) => String::from("Function"),
+            Object::Struct(ref name, _) | Object::Variant(ref name, _, _) => name.clone(),
+            Object::Null => String::from("Null"),
+            Object::ReturnValue(ref value) => value.type_name(),
+            Object::Error(_) => String::from("Error"),
+        }
+    }
+
+    /// Whether the value can be called: a function, builtin or constructor.
+    pub fn is_callable(&self) -> bool {
+        matches!(
+            *self,
+            Object::Func(..)
+                | Object::Closure(_)
+                | Object::Builtin(..)
+                | Object::StructCtor(..)
+                | Object::VariantCtor(..)
+        )
+    }
+
+    /// Wraps a plain function taking `arity` arguments, or any number if negative, as a builtin.
+    pub fn builtin(arity: i32, f: fn(Vec<Object>) -> Object) -> Object {
+        Object::Builtin(arity, BuiltinFunc::Plain(f))
+    }
+
+    /// Wraps a typed Rust function as a builtin named `name`.
+    ///
+    /// Arguments are converted with [`FromObject`] and the result with
+    /// [`IntoObject`], so the function does not need to check types itself.
+    pub fn typed_builtin<Args, F: TypedFn<Args> + 'static>(name: &str, f: F) -> Object {
+        let name = name.to_string();
+        Object::Builtin(
+            F::ARITY,
+            BuiltinFunc::new(move |_, args| f.call_typed(&name, args)),
+        )
+    }
+
+    /// Builds a runtime error value with an empty call stack.
+    pub fn error(kind: ErrorKind, message: String) -> Object {
+        Object::Error(RuntimeError::new(kind, message))
+    }
+
+    /// Wraps a value in the builtin `Result` enum's `Ok` variant.
+    pub fn ok(value: Object) -> Object {
+        Object::Variant(
+            String::from("Result"),
+            String::from("Ok"),
+            vec![(String::from("value"), value)],
+        )
+    }
+
+    /// Wraps an error in the builtin `Result` enum's `Err` variant.
+    pub fn err(error: Object) -> Object {
+        Object::Variant(
+            String::from("Result"),
+            String::from("Err"),
+            vec![(String::from("error"), error)],
+        )
+    }
+
+    /// Looks up a named field on a struct or enum variant.
+    pub fn get_field(&self, name: &str) -> Option<&Object> {
+        match *self {
+            Object::Struct(_, ref fields) | Object::Variant(_, _, ref fields) => fields
+                .iter()
+                .find(|(field, _)| field == name)
+                .map(|(_, value)| value),
+            _ => None,
+        }
+    }
+}
+
 impl Eq for Object {}
 
 impl Hash for Object {
@@ -81,7 +260,273 @@ impl Hash for Object {
             Object::Int(ref i) => i.hash(state),
             Object::Bool(ref b) => b.hash(state),
             Object::String(ref s) => s.hash(state),
+            Object::Struct(ref name, ref fields) => {
+                name.hash(state);
+                for (field, value) in fields {
+                    field.hash(state);
+                    value.hash(state);
+                }
+            }
+            Object::Variant(ref name, ref tag, ref fields) => {
+                name.hash(state);
+                tag.hash(state);
+                for (_, value) in fields {
+                    value.hash(state);
+                }
+            }
             _ => "".hash(state),
         }
     }
 }
+
+/// Converts a Rust value into an [`Object`] that can be passed to scripts.
+pub trait IntoObject {
+    fn into_object(self) -> Object;
+}
+
+/// Converts an [`Object`] from a script into a Rust value.
+///
+/// Fails with a `TypeError` if the object does not have the expected type.
+pub trait FromObject: Sized {
+    fn from_object(obj: Object) -> Result<Self, RuntimeError>;
+}
+
+fn expected(type_name: &str, obj: &Object) -> RuntimeError {
+    RuntimeError::new(
+        ErrorKind::TypeError,
+        format!("expected {}, got {}", type_name, obj.type_name()),
+    )
+}
+
+impl IntoObject for Object {
+    fn into_object(self) -> Object {
+        self
+    }
+}
+
+impl FromObject for Object {
+    fn from_object(obj: Object) -> Result<Self, RuntimeError> {
+        Ok(obj)
+    }
+}
+
+impl IntoObject for () {
+    fn into_object(self) -> Object {
+        Object::Null
+    }
+}
+
+impl IntoObject for i64 {
+    fn into_object(self) -> Object {
+        Object::Int(self)
+    }
+}
+
+impl FromObject for i64 {
+    fn from_object(obj: Object) -> Result<Self, RuntimeError> {
+        match obj {
+            Object::Int(i) => Ok(i),
+            o => Err(expected("Int", &o)),
+        }
+    }
+}
+
+impl IntoObject for bool {
+    fn into_object(self) -> Object {
+        Object::Bool(self)
+    }
+}
+
+impl FromObject for bool {
+    fn from_object(obj: Object) -> Result<Self, RuntimeError> {
+        match obj {
+            Object::Bool(b) => Ok(b),
+            o => Err(expected("Bool", &o)),
+        }
+    }
+}
+
+impl IntoObject for String {
+    fn into_object(self) -> Object {
+        Object::String(self)
+    }
+}
+
+impl IntoObject for &str {
+    fn into_object(self) -> Object {
+        Object::String(self.to_string())
+    }
+}
+
+impl FromObject for String {
+    fn from_object(obj: Object) -> Result<Self, RuntimeError> {
+        match obj {
+            Object::String(s) => Ok(s),
+            o => Err(expected("String", &o)),
+        }
+    }
+}
+
+impl<T: IntoObject> IntoObject for Vec<T> {
+    fn into_object(self) -> Object {
+        Object::Array(Rc::new(
+            self.into_iter().map(IntoObject::into_object).collect(),
+        ))
+    }
+}
+
+impl<T: FromObject> FromObject for Vec<T> {
+    fn from_object(obj: Object) -> Result<Self, RuntimeError> {
+        match obj {
+            Object::Array(items) => Rc::unwrap_or_clone(items)
+                .into_iter()
+                .map(T::from_object)
+                .collect(),
+            o => Err(expected("List", &o)),
+        }
+    }
+}
+
+impl<K: IntoObject, V: IntoObject> IntoObject for HashMap<K, V> {
+    fn into_object(self) -> Object {
+        Object::Hash(Rc::new(
+            self.into_iter()
+                .map(|(k, v)| (k.into_object(), v.into_object()))
+                .collect(),
+        ))
+    }
+}
+
+impl<K: FromObject + Eq + Hash, V: FromObject> FromObject for HashMap<K, V> {
+    fn from_object(obj: Object) -> Result<Self, RuntimeError> {
+        match obj {
+            Object::Hash(hash) => Rc::unwrap_or_clone(hash)
+                .into_iter()
+                .map(|(k, v)| Ok((K::from_object(k)?, V::from_object(v)?)))
+                .collect(),
+            o => Err(expected("Hash", &o)),
+        }
+    }
+}
+
+/// `None` is passed to scripts as `null`.
+impl<T: IntoObject> IntoObject for Option<T> {
+    fn into_object(self) -> Object {
+        match self {
+            Some(value) => value.into_object(),
+            None => Object::Null,
+        }
+    }
+}
+
+impl<T: FromObject> FromObject for Option<T> {
+    fn from_object(obj: Object) -> Result<Self, RuntimeError> {
+        match obj {
+            Object::Null => Ok(None),
+            o => T::from_object(o).map(Some),
+        }
+    }
+}
+
+/// An `Err` is raised in the script as an `Error` with the error's message.
+impl<T: IntoObject, E: fmt::Display> IntoObject for Result<T, E> {
+    fn into_object(self) -> Object {
+        match self {
+            Ok(value) => value.into_object(),
+            Err(err) => Object::error(ErrorKind::Error, err.to_string()),
+        }
+    }
+}
+
+// Tuples are passed as lists of a fixed length
+macro_rules! impl_tuple {
+    ($len:expr; $($t:ident),+) => {
+        impl<$($t: IntoObject),+> IntoObject for ($($t,)+) {
+            #[allow(non_snake_case)]
+            fn into_object(self) -> Object {
+                let ($($t,)+) = self;
+                Object::Array(Rc::new(vec![$($t.into_object()),+]))
+            }
+        }
+
+        impl<$($t: FromObject),+> FromObject for ($($t,)+) {
+            fn from_object(obj: Object) -> Result<Self, RuntimeError> {
+                match obj {
+                    Object::Array(ref items) if items.len() == $len => {
+                        let mut items = items.iter().cloned();
+                        Ok(($($t::from_object(items.next().unwrap_or(Object::Null))?,)+))
+                    }
+                    o => Err(expected(&format!("List of {} elements", $len), &o)),
+                }
+            }
+        }
+    };
+}
+
+impl_tuple!(1; A);
+impl_tuple!(2; A, B);
+impl_tuple!(3; A, B, C);
+impl_tuple!(4; A, B, C, D);
+
+/// A Rust function whose arguments and result convert to and from objects,
+/// such as `Fn(i64, String) -> Result<Vec<i64>, E>`.
+pub trait TypedFn<Args> {
+    /// The number of arguments the function takes.
+    const ARITY: i32;
+
+    /// Converts the arguments and calls the function, reporting an argument of
+    /// the wrong type as a `TypeError` that names the function `name`.
+    fn call_typed(&self, name: &str, args: Vec<Object>) -> Object;
+}
+
+macro_rules! impl_typed_fn {
+    ($arity:expr; $($t:ident),*) => {
+        impl<F, R, $($t),*> TypedFn<($($t,)*)> for F
+        where
+            F: Fn($($t),*) -> R,
+            R: IntoObject,
+            $($t: FromObject),*
+        {
+            const ARITY: i32 = $arity;
+
+            #[allow(non_snake_case, unused_mut, unused_variables)]
+            fn call_typed(&self, name: &str, args: Vec<Object>) -> Object {
+                let [$($t),*] = match <[Object; $arity]>::try_from(args) {
+                    Ok(args) => args,
+                    Err(args) => {
+                        return Object::error(
+                            ErrorKind::ArityError,
+                            format!(
+                                "wrong number of arguments. got={}, want={}",
+                                args.len(),
+                                $arity
+                            ),
+                        )
+                    }
+                };
+
+                let mut position = 0;
+                $(
+                    position += 1;
+                    let $t = match $t::from_object($t) {
+                        Ok(value) => value,
+                        Err(err) => {
+                            return Object::error(
+                                err.kind,
+                                format!("argument {} to `{}`: {}", position, name, err.message),
+                            )
+                        }
+                    };
+                )*
+                self($($t),*).into_object()
+            }
+        }
+    };
+}
+
+impl_typed_fn!(0;);
+impl_typed_fn!(1; A);
+impl_typed_fn!(2; A, B);
+impl_typed_fn!(3; A, B, C);
+impl_typed_fn!(4; A, B, C, D);
+impl_typed_fn!(5; A, B, C, D, E);
@@ -0,0 +1,231 @@
+// Copyright 2024 Dimitrios Papakonstantinou. All rights reserved.
+// Use of this source code is governed by a MIT
+// license that can be found in the LICENSE file
+
+//! Serde support for [`Object`], enabled by the `serde` feature.
+//!
+//! Lists map to sequences, hashes to maps and `null` to unit. Hash entries
+//! are written sorted by key, so the same hash always serializes the same
+//! way, whatever the order of its `HashMap`. Structs are
+//! written as maps of their fields and enum values like serde's externally
+//! tagged enums, so reading them back gives hashes and strings. Functions
+//! and errors can not be serialized, and numbers that are not `Int`s can
+//! not be deserialized.
+
+use crate::evaluator::object::Object;
+use serde::de::{self, Deserialize, Deserializer, MapAccess, SeqAccess, Visitor};
+use serde::ser::{self, Serialize, SerializeMap, SerializeSeq, Serializer};
+use std::collections::HashMap;
+use std::fmt;
+use std::rc::Rc;
+
+struct Fields<'a>(&'a [(String, Object)]);
+
+impl Serialize for Fields<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(Some(self.0.len()))?;
+        for (field, value) in self.0 {
+            map.serialize_entry(field, value)?;
+        }
+        map.end()
+    }
+}
+
+impl Serialize for Object {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match *self {
+            Object::Int(i) => serializer.serialize_i64(i),
+            Object::String(ref s) => serializer.serialize_str(s),
+            Object::Bool(b) => serializer.serialize_bool(b),
+            Object::Array(ref items) => {
+                let mut seq = serializer.serialize_seq(Some(items.len()))?;
+                for item in items.iter() {
+                    seq.serialize_element(item)?;
+                }
+                seq.end()
+            }
+            Object::Hash(ref hash) => {
+                let mut entries: Vec<_> = hash.iter().collect();
+                // Keys that print the same, like `1` and `"1"`, are told
+                // apart by their type
+                entries.sort_by_cached_key(|(k, _)| (k.to_string(), k.type_name()));
+                let mut map = serializer.serialize_map(Some(entries.len()))?;
+                for (k, v) in entries {
+                    map.serialize_entry(k, v)?;
+                }
+                map.end()
+            }
+            Object::Struct(_, ref fields) => Fields(fields).serialize(serializer),
+            Object::Variant(_, ref tag, ref fields) => {
+                if fields.is_empty() {
+                    return serializer.serialize_str(tag);
+                }
+                let mut map = serializer.serialize_map(Some(1))?;
+                map.serialize_entry(tag, &Fields(fields))?;
+                map.end()
+            }
+            Object::Null => serializer.serialize_unit(),
+            Object::ReturnValue(ref value) => value.serialize(serializer),
+            Object::Error(ref err) => Err(ser::Error::custom(format!(
+                "cannot serialize an error: {}",
+                err
+            ))),
+            Object::Func(..)
+            | Object::Closure(..)
+            | Object::Builtin(..)
+            | Object::StructCtor(..)
+            | Object::VariantCtor(..) => Err(ser::Error::custom("cannot serialize a function")),
+        }
+    }
+}
+
+struct ObjectVisitor;
+
+impl<'de> Visitor<'de> for ObjectVisitor {
+    type Value = Object;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("an integer, string, boolean, list, map or null")
+    }
+
+    fn visit_bool<E: de::Error>(self, v: bool) -> Result<Object, E> {
+        Ok(Object::Bool(v))
+    }
+
+    fn visit_i64<E: de::Error>(self, v: i64) -> Result<Object, E> {
+        Ok(Object::Int(v))
+    }
+
+    fn visit_u64<E: de::Error>(self, v: u64) -> Result<Object, E> {
+        match i64::try_from(v) {
+            Ok(i) => Ok(Object::Int(i)),
+            Err(_) => Err(E::custom(format!("integer {} is out of range", v))),
+        }
+    }
+
+    fn visit_f64<E: de::Error>(self, v: f64) -> Result<Object, E> {
+        Err(E::custom(format!(
+            "floating point number {} is not supported",
+            v
+        )))
+    }
+
+    fn visit_str<E: de::Error>(self, v: &str) -> Result<Object, E> {
+        Ok(Object::String(v.to_string()))
+    }
+
+    fn visit_string<E: de::Error>(self, v: String) -> Result<Object, E> {
+        Ok(Object::String(v))
+    }
+
+    fn visit_unit<E: de::Error>(self) -> Result<Object, E> {
+        Ok(Object::Null)
+    }
+
+    fn visit_none<E: de::Error>(self) -> Result<Object, E> {
+        Ok(Object::Null)
+    }
+
+    fn visit_some<D: Deserializer<'de>>(self, deserializer: D) -> Result<Object, D::Error> {
+        Object::deserialize(deserializer)
+    }
+
+    fn visit_newtype_struct<D: Deserializer<'de>>(
+        self,
+        deserializer: D,
+    ) -> Result<Object, D::Error> {
+        Object::deserialize(deserializer)
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Object, A::Error> {
+        let mut items = Vec::with_capacity(seq.size_hint().unwrap_or(0));
+        while let Some(item) = seq.next_element()? {
+            items.push(item);
+        }
+        Ok(Object::Array(Rc::new(items)))
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Object, A::Error> {
+        #[allow(clippy::mutable_key_type)]
+        let mut hash = HashMap::with_capacity(map.size_hint().unwrap_or(0));
+        while let Some((k, v)) = map.next_entry()? {
+            hash.insert(k, v);
+        }
+        Ok(Object::Hash(Rc::new(hash)))
+    }
+}
+
+impl<'de> Deserialize<'de> for Object {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_any(ObjectVisitor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::evaluator::object::Object;
+    use crate::interpreter::Interpreter;
+
+    #[test]
+    fn test_json_round_trip() {
+        let config = r#"{"name": "rules", "limits": [1, 2, 3], "strict": true, "owner": null}"#;
+        let obj: Object = serde_json::from_str(config).unwrap();
+
+        let mut interpreter = Interpreter::new();
+        interpreter.set_global("config", obj).unwrap();
+        let result = interpreter
+            .eval_str("{\"count\": len(config[\"limits\"]), \"owner\": config[\"owner\"], \"tags\": [config[\"name\"], config[\"strict\"]]}")
+            .unwrap();
+
+        let json: serde_json::Value = serde_json::to_value(&result).unwrap();
+        assert_eq!(
+            serde_json::json!({"count": 3, "owner": null, "tags": ["rules", true]}),
+            json
+        );
+    }
+
+    #[test]
+    fn test_hash_order() {
+        let mut interpreter = Interpreter::new();
+        let result = interpreter
+            .eval_str("let h = {\"b\": 2, \"a\": 1, \"c\": {\"z\": 0, \"y\": [1]}}; [h, h]")
+            .unwrap();
+        assert_eq!(
+            r#"[{"a":1,"b":2,"c":{"y":[1],"z":0}},{"a":1,"b":2,"c":{"y":[1],"z":0}}]"#,
+            serde_json::to_string(&result).unwrap()
+        );
+
+        let result = interpreter
+            .eval_str("{\"1\": \"string\", 2: \"int\", 1: \"int\", true: \"bool\"}")
+            .unwrap();
+        assert_eq!(
+            r#"{"1":"int","1":"string","2":"int","true":"bool"}"#,
+            serde_json::to_string(&result).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_structs_and_enums() {
+        let mut interpreter = Interpreter::new();
+        let result = interpreter
+            .eval_str("struct Point { x, y }; enum Shape { Dot, Circle(r) }; [Point(1, 2), Dot, Circle(3)]")
+            .unwrap();
+        assert_eq!(
+            r#"[{"x":1,"y":2},"Dot",{"Circle":{"r":3}}]"#,
+            serde_json::to_string(&result).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_rejected_values() {
+        let mut interpreter = Interpreter::new();
+        let func = interpreter.eval_str("[1, fun(x) { x }]").unwrap();
+        let err = serde_json::to_string(&func).unwrap_err();
+        assert_eq!("cannot serialize a function", err.to_string());
+
+        let err = serde_json::from_str::<Object>("[1.5]").unwrap_err();
+        assert!(err
+            .to_string()
+            .starts_with("floating point number 1.5 is not supported"));
+    }
+}
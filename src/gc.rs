@@ -0,0 +1,394 @@
+// Copyright 2024 Dimitrios Papakonstantinou. All rights reserved.
+// Use of this source code is governed by a MIT
+// license that can be found in the LICENSE file
+
+//! Reclaiming environments and VM upvalues kept alive by reference cycles.
+
+use crate::evaluator::env::Env;
+use crate::evaluator::object::Object;
+use crate::vm::code::{Closure, Upvalue};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::{Rc, Weak};
+
+/// Registered environments are collected once this many are alive, at the least.
+const MIN_COLLECT_THRESHOLD: usize = 4096;
+
+/// Something that may be part of a reference cycle.
+enum Tracked {
+    Env(Weak<RefCell<Env>>),
+    Upvalue(Weak<RefCell<Upvalue>>),
+}
+
+impl Tracked {
+    fn is_alive(&self) -> bool {
+        match *self {
+            Tracked::Env(ref env) => env.strong_count() > 0,
+            Tracked::Upvalue(ref upvalue) => upvalue.strong_count() > 0,
+        }
+    }
+}
+
+struct Registry {
+    tracked: Vec<Tracked>,
+    threshold: usize,
+}
+
+thread_local! {
+    static REGISTRY: RefCell<Registry> = const {
+        RefCell::new(Registry {
+            tracked: Vec::new(),
+            threshold: MIN_COLLECT_THRESHOLD,
+        })
+    };
+}
+
+/// Registers an environment with the cycle collector.
+///
+/// Functions hold the environment they were defined in, which usually holds
+/// them in turn, so environments are only freed once [`collect_cycles`] runs.
+/// It runs on its own whenever the number of registered environments and
+/// upvalues still alive has doubled since the last collection.
+pub fn track_env(env: &Rc<RefCell<Env>>) {
+    track(Tracked::Env(Rc::downgrade(env)));
+}
+
+/// Registers a variable captured by a VM closure, which may hold the closure itself.
+pub fn track_upvalue(upvalue: &Rc<RefCell<Upvalue>>) {
+    track(Tracked::Upvalue(Rc::downgrade(upvalue)));
+}
+
+fn track(tracked: Tracked) {
+    let collect = REGISTRY.with_borrow_mut(|registry| {
+        registry.tracked.push(tracked);
+        if registry.tracked.len() < registry.threshold {
+            return false;
+        }
+
+        // Most scopes are freed as soon as their call returns
+        registry.tracked.retain(Tracked::is_alive);
+        registry.threshold = MIN_COLLECT_THRESHOLD.max(2 * registry.tracked.len());
+        2 * registry.tracked.len() >= registry.threshold
+    });
+
+    if collect {
+        collect_cycles();
+        REGISTRY.with_borrow_mut(|registry| {
+            registry.threshold = MIN_COLLECT_THRESHOLD.max(2 * registry.tracked.len());
+        });
+    }
+}
+
+/// Frees the registered environments and upvalues that are only reachable
+/// through reference cycles, returning how many environments were freed.
+///
+/// This is trial deletion: every shared value reachable from a registered
+/// one is visited and the references between them are counted. A value with
+/// more references than that is held from elsewhere, such as the running
+/// evaluator, and keeps everything it reaches alive. The bindings of the
+/// other environments are dropped, breaking their cycles.
+pub fn collect_cycles() -> usize {
+    let tracked = REGISTRY.with_borrow_mut(|registry| {
+        registry.tracked.retain(Tracked::is_alive);
+        registry
+            .tracked
+            .iter()
+            .map(|tracked| match *tracked {
+                Tracked::Env(ref env) => Tracked::Env(Weak::clone(env)),
+                Tracked::Upvalue(ref upvalue) => Tracked::Upvalue(Weak::clone(upvalue)),
+            })
+            .collect::<Vec<_>>()
+    });
+
+    let mut heap = Heap::default();
+    for tracked in tracked {
+        match tracked {
+            Tracked::Env(env) => {
+                if let Some(env) = env.upgrade() {
+                    heap.visit(Node::Env(env));
+                }
+            }
+            Tracked::Upvalue(upvalue) => {
+                if let Some(upvalue) = upvalue.upgrade() {
+                    heap.visit(Node::Upvalue(upvalue));
+                }
+            }
+        }
+    }
+    heap.trace();
+    heap.mark();
+    heap.sweep()
+}
+
+/// A shared value the collector traces through.
+enum Node {
+    Env(Rc<RefCell<Env>>),
+    Upvalue(Rc<RefCell<Upvalue>>),
+    Array(Rc<Vec<Object>>),
+    Hash(Rc<HashMap<Object, Object>>),
+    Closure(Rc<Closure>),
+}
+
+impl Node {
+    fn addr(&self) -> *const () {
+        match *self {
+            Node::Env(ref env) => Rc::as_ptr(env) as *const (),
+            Node::Upvalue(ref upvalue) => Rc::as_ptr(upvalue) as *const (),
+            Node::Array(ref array) => Rc::as_ptr(array) as *const (),
+            Node::Hash(ref hash) => Rc::as_ptr(hash) as *const (),
+            Node::Closure(ref closure) => Rc::as_ptr(closure) as *const (),
+        }
+    }
+
+    fn strong_count(&self) -> usize {
+        match *self {
+            Node::Env(ref env) => Rc::strong_count(env),
+            Node::Upvalue(ref upvalue) => Rc::strong_count(upvalue),
+            Node::Array(ref array) => Rc::strong_count(array),
+            Node::Hash(ref hash) => Rc::strong_count(hash),
+            Node::Closure(ref closure) => Rc::strong_count(closure),
+        }
+    }
+}
+
+struct Visited {
+    node: Node,
+    /// References to the node from other visited nodes.
+    internal: usize,
+    /// The node is in use and could not be traced.
+    busy: bool,
+    edges: Vec<usize>,
+    marked: bool,
+}
+
+#[derive(Default)]
+struct Heap {
+    nodes: Vec<Visited>,
+    index: HashMap<*const (), usize>,
+}
+
+impl Heap {
+    /// Adds a node unless it was already visited, returning its index.
+    fn visit(&mut self, node: Node) -> usize {
+        if let Some(&index) = self.index.get(&node.addr()) {
+            return index;
+        }
+
+        self.index.insert(node.addr(), self.nodes.len());
+        self.nodes.push(Visited {
+            node,
+            internal: 0,
+            busy: false,
+            edges: vec![],
+            marked: false,
+        });
+        self.nodes.len() - 1
+    }
+
+    /// Counts the references between all nodes reachable from the visited ones.
+    fn trace(&mut self) {
+        let mut next = 0;
+        while next < self.nodes.len() {
+            let mut children = vec![];
+            let busy = match self.nodes[next].node {
+                Node::Env(ref env) => match env.try_borrow() {
+                    Ok(env) => {
+                        for value in env.values() {
+                            Self::children(value, &mut children);
+                        }
+                        if let Some(outer) = env.outer() {
+                            children.push(Node::Env(Rc::clone(outer)));
+                        }
+                        false
+                    }
+                    Err(_) => true,
+                },
+                Node::Upvalue(ref upvalue) => match upvalue.try_borrow() {
+                    Ok(upvalue) => {
+                        if let Upvalue::Closed(ref value) = *upvalue {
+                            Self::children(value, &mut children);
+                        }
+                        false
+                    }
+                    Err(_) => true,
+                },
+                Node::Array(ref array) => {
+                    for value in array.iter() {
+                        Self::children(value, &mut children);
+                    }
+                    false
+                }
+                Node::Hash(ref hash) => {
+                    for (key, value) in hash.iter() {
+                        Self::children(key, &mut children);
+                        Self::children(value, &mut children);
+                    }
+                    false
+                }
+                Node::Closure(ref closure) => {
+                    for upvalue in &closure.upvalues {
+                        children.push(Node::Upvalue(Rc::clone(upvalue)));
+                    }
+                    children.push(Node::Env(Rc::clone(&closure.globals)));
+                    false
+                }
+            };
+
+            self.nodes[next].busy = busy;
+            for child in children {
+                let index = self.visit(child);
+                self.nodes[index].internal += 1;
+                self.nodes[next].edges.push(index);
+            }
+            next += 1;
+        }
+    }
+
+    /// Collects the shared values directly held by `value`.
+    fn children(value: &Object, children: &mut Vec<Node>) {
+        match *value {
+            Object::Array(ref array) => children.push(Node::Array(Rc::clone(array))),
+            Object::Hash(ref hash) => children.push(Node::Hash(Rc::clone(hash))),
+            Object::Func(_, _, ref env) => children.push(Node::Env(Rc::clone(env))),
+            Object::Closure(ref closure) => children.push(Node::Closure(Rc::clone(closure))),
+            Object::Struct(_, ref fields) | Object::Variant(_, _, ref fields) => {
+                for (_, value) in fields {
+                    Self::children(value, children);
+                }
+            }
+            Object::ReturnValue(ref value) => Self::children(value, children),
+            _ => {}
+        }
+    }
+
+    /// Marks the nodes held from outside the heap and all they reach.
+    fn mark(&mut self) {
+        // The heap holds one reference to each node itself
+        let mut pending: Vec<usize> = (0..self.nodes.len())
+            .filter(|&i| {
+                let visited = &self.nodes[i];
+                visited.busy || visited.node.strong_count() - 1 > visited.internal
+            })
+            .collect();
+        while let Some(i) = pending.pop() {
+            if self.nodes[i].marked {
+                continue;
+            }
+            self.nodes[i].marked = true;
+            pending.extend(self.nodes[i].edges.iter().copied());
+        }
+    }
+
+    /// Empties the unmarked environments and upvalues, returning how many environments were freed.
+    ///
+    /// The heap still holds every node, so nothing is freed until it is dropped.
+    fn sweep(self) -> usize {
+        let mut freed = 0;
+        for visited in self.nodes.iter().filter(|visited| !visited.marked) {
+            match visited.node {
+                Node::Env(ref env) => {
+                    *env.borrow_mut() = Env::new();
+                    freed += 1;
+                }
+                Node::Upvalue(ref upvalue) => {
+                    *upvalue.borrow_mut() = Upvalue::Closed(Object::Null);
+                }
+                _ => {}
+            }
+        }
+        freed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::evaluator::builtins::new_builtins;
+    use crate::evaluator::env::Env;
+    use crate::evaluator::Evaluator;
+    use crate::gc::*;
+    use crate::lexer::Lexer;
+    use crate::parser::Parser;
+    use crate::vm::Vm;
+
+    const MAKE: &str = "
+let make = fun(n) {
+  let f = fun(k) { if (k == 0) { n } else { f(k - 1) } };
+  f
+};
+let repeat = fun(n) { if (n == 0) { 0 } else { make(n); repeat(n - 1) } };
+";
+
+    fn parse(input: &str) -> Vec<crate::ast::Stmt> {
+        Parser::new(Lexer::new(input)).parse()
+    }
+
+    /// Environments and upvalues registered on this thread that are still alive.
+    fn alive() -> usize {
+        REGISTRY.with_borrow(|registry| {
+            registry
+                .tracked
+                .iter()
+                .filter(|tracked| tracked.is_alive())
+                .count()
+        })
+    }
+
+    #[test]
+    fn test_collect_closure_cycles() {
+        let env = Env::from(new_builtins()).shared();
+        let mut evaluator = Evaluator::new(Rc::clone(&env));
+        evaluator.eval(parse(MAKE));
+        evaluator.eval(parse("let keep = make(7);"));
+
+        assert_eq!(Some(Object::Int(0)), evaluator.eval(parse("repeat(1000)")));
+        collect_cycles();
+        // The globals and the scope of `keep`
+        assert_eq!(2, alive());
+        assert_eq!(Some(Object::Int(7)), evaluator.eval(parse("keep(3)")));
+
+        let scope = match env.borrow().get_global("keep") {
+            Some(Object::Func(_, _, scope)) => Rc::downgrade(&scope),
+            o => panic!("expected a function, got {:?}", o),
+        };
+        evaluator.eval(parse("let keep = null;"));
+        assert_eq!(1, collect_cycles());
+        assert!(scope.upgrade().is_none());
+
+        drop(evaluator);
+        let globals = Rc::downgrade(&env);
+        drop(env);
+        assert_eq!(1, collect_cycles());
+        assert!(globals.upgrade().is_none());
+        assert_eq!(0, alive());
+    }
+
+    #[test]
+    fn test_collect_while_running() {
+        let mut evaluator = Evaluator::new(Env::from(new_builtins()).shared());
+        evaluator.eval(parse(MAKE));
+
+        let input = "repeat(5 * MIN_COLLECT_THRESHOLD)"
+            .replace("MIN_COLLECT_THRESHOLD", &MIN_COLLECT_THRESHOLD.to_string());
+        assert_eq!(Some(Object::Int(0)), evaluator.eval(parse(&input)));
+        assert!(alive() <= 2 * MIN_COLLECT_THRESHOLD);
+    }
+
+    #[test]
+    fn test_collect_vm_upvalue_cycles() {
+        let env = Env::from(new_builtins()).shared();
+        let mut vm = Vm::new(Rc::clone(&env));
+        vm.eval(parse(MAKE));
+        vm.eval(parse("let keep = make(7);"));
+
+        assert_eq!(Some(Object::Int(0)), vm.eval(parse("repeat(1000)")));
+        collect_cycles();
+        // The globals and the upvalues of `keep` for `n` and `f`
+        assert_eq!(3, alive());
+        assert_eq!(Some(Object::Int(7)), vm.eval(parse("keep(3)")));
+
+        drop(vm);
+        drop(env);
+        assert_eq!(1, collect_cycles());
+        assert_eq!(0, alive());
+    }
+}
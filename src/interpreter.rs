@@ -0,0 +1,1178 @@
+// Copyright 2024 Dimitrios Papakonstantinou. All rights reserved.
+// Use of this source code is governed by a MIT
+// license that can be found in the LICENSE file
+
+use crate::ast::Program;
+use crate::evaluator::budget::{InterruptHandle, Limits};
+use crate::evaluator::builtins::{enable_result_mode, new_builtins, use_host};
+use crate::evaluator::env::Env;
+use crate::evaluator::error::{ErrorKind, RuntimeError};
+use crate::evaluator::host::{Host, Sandbox, SandboxedHost, StdHost};
+use crate::evaluator::object::{BuiltinFunc, Object, TypedFn};
+use crate::evaluator::{Evaluator, Interp, DEFAULT_MAX_DEPTH};
+use crate::lexer::Lexer;
+use crate::optimizer::Optimizer;
+use crate::parser::{ParseErrors, Parser};
+use crate::snapshot;
+use crate::vm::Vm;
+use std::cell::RefCell;
+use std::collections::HashSet;
+use std::path::Path;
+use std::rc::Rc;
+use std::{fmt, fs, io};
+
+const STAND_PRELUDE: &str = "
+let fold = fun(f, init, lst) {
+  if (len(lst) == 0) {
+    init
+  } else {
+    let newInit = f(init, first(lst));
+    fold(f, newInit, tail(lst));
+  }
+};
+";
+
+/// Options controlling how scripts and REPL sessions are run.
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// Fallible builtins return `Ok`/`Err` values instead of aborting.
+    pub result_mode: bool,
+    /// Nested calls allowed before a `RecursionError` is raised.
+    pub max_depth: usize,
+    /// Run code on the bytecode VM instead of the tree-walking evaluator.
+    pub vm: bool,
+    /// Simplify programs with the [`Optimizer`] before running them.
+    pub optimize: bool,
+    /// Restricts what scripts can access, or `None` to allow everything.
+    pub sandbox: Option<Sandbox>,
+    /// Fuel, time and allocations each run may use.
+    pub limits: Limits,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            result_mode: false,
+            max_depth: DEFAULT_MAX_DEPTH,
+            vm: false,
+            optimize: true,
+            sandbox: None,
+            limits: Limits::default(),
+        }
+    }
+}
+
+/// Why running code through an [`Interpreter`] failed.
+#[derive(Debug)]
+pub enum Error {
+    /// The source did not parse; nothing was run.
+    Parse(ParseErrors),
+    /// An error was raised and not caught by the script.
+    Runtime(RuntimeError),
+    /// A script file could not be read.
+    Io(io::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Parse(errors) => {
+                let lines: Vec<String> = errors.iter().map(|err| err.to_string()).collect();
+                write!(f, "{}", lines.join("\n"))
+            }
+            Error::Runtime(err) => write!(f, "{}", err),
+            Error::Io(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+/// The engine chosen by [`Config::vm`].
+enum Engine {
+    Evaluator(Evaluator),
+    Vm(Vm),
+}
+
+/// An interpreter with the builtins and the prelude loaded, for
+/// running scripts and embedding the language in Rust applications.
+///
+/// # Examples
+///
+/// ```
+/// use beavieeer::evaluator::object::Object;
+/// use beavieeer::interpreter::Interpreter;
+/// use std::cell::Cell;
+///
+/// let mut interpreter = Interpreter::new();
+/// let calls = Cell::new(0);
+/// interpreter.register("tick", 0, move |_, _| {
+///     calls.set(calls.get() + 1);
+///     Object::Int(calls.get())
+/// });
+///
+/// interpreter.eval_str("let double = fun(x) { x * 2 };").unwrap();
+/// let result = interpreter.call_function("double", vec![Object::Int(21)]);
+/// assert_eq!(Object::Int(42), result.unwrap());
+/// assert_eq!(Object::Int(2), interpreter.eval_str("tick(); tick()").unwrap());
+/// ```
+pub struct Interpreter {
+    env: Rc<RefCell<Env>>,
+    engine: Engine,
+    optimizer: Option<Optimizer>,
+    limits: Limits,
+    interrupt: InterruptHandle,
+    /// Names defined by the interpreter or the host rather than by scripts,
+    /// which snapshots leave out.
+    builtins: HashSet<String>,
+}
+
+impl Default for Interpreter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Interpreter {
+    pub fn new() -> Self {
+        Self::with_config(&Config::default())
+    }
+
+    /// Creates an interpreter using the given [`Config`].
+    ///
+    /// The builtins and the prelude are frozen so scripts can not redeclare them.
+    pub fn with_config(config: &Config) -> Self {
+        Self::with_host(config, StdHost)
+    }
+
+    /// Creates an interpreter whose I/O builtins go through `host`.
+    pub fn with_host(config: &Config, host: impl Host + 'static) -> Self {
+        let host: Rc<dyn Host> = match config.sandbox {
+            Some(ref sandbox) => Rc::new(SandboxedHost::new(host, sandbox.clone())),
+            None => Rc::new(host),
+        };
+        let mut builtins = new_builtins();
+        use_host(&mut builtins, host);
+        if config.result_mode {
+            enable_result_mode(&mut builtins);
+        }
+
+        let env = Env::from(builtins).shared();
+        let interrupt = InterruptHandle::new();
+        let engine = if config.vm {
+            let mut vm = Vm::new(Rc::clone(&env));
+            vm.set_max_depth(config.max_depth);
+            vm.set_interrupt(interrupt.clone());
+            Engine::Vm(vm)
+        } else {
+            let mut evaluator = Evaluator::new(Rc::clone(&env));
+            evaluator.set_max_depth(config.max_depth);
+            evaluator.set_interrupt(interrupt.clone());
+            Engine::Evaluator(evaluator)
+        };
+        let mut interpreter = Interpreter {
+            env,
+            engine,
+            optimizer: config.optimize.then(Optimizer::new),
+            limits: config.limits,
+            interrupt,
+            builtins: HashSet::new(),
+        };
+        interpreter.load_prelude();
+        interpreter.env.borrow_mut().freeze();
+        interpreter.builtins = interpreter.env.borrow().global_names();
+        interpreter
+    }
+
+    /// Defines a builtin backed by a Rust closure, which may capture state.
+    ///
+    /// The builtin takes `arity` arguments, or any number if negative. It is
+    /// called with the running interpreter, through which it can call back
+    /// into the script, and scripts can not redeclare it.
+    pub fn register<F>(&mut self, name: &str, arity: i32, f: F)
+    where
+        F: Fn(&mut dyn Interp, Vec<Object>) -> Object + 'static,
+    {
+        let builtin = Object::Builtin(arity, BuiltinFunc::new(f));
+        self.env
+            .borrow_mut()
+            .set_const(String::from(name), &builtin);
+        self.builtins.insert(String::from(name));
+    }
+
+    /// Defines a builtin from a typed Rust function, such as
+    /// `Fn(i64, String) -> Result<Vec<i64>, E>`.
+    ///
+    /// The number of arguments is checked, and arguments of the wrong type are
+    /// reported to the script as a `TypeError`.
+    pub fn register_fn<Args, F: TypedFn<Args> + 'static>(&mut self, name: &str, f: F) {
+        let builtin = Object::typed_builtin(name, f);
+        self.env
+            .borrow_mut()
+            .set_const(String::from(name), &builtin);
+        self.builtins.insert(String::from(name));
+    }
+
+    /// Runs a program, returning the value of its last statement.
+    ///
+    /// Each run gets the full [`Config::limits`].
+    pub fn eval(&mut self, program: Program) -> Option<Object> {
+        let program = match self.optimizer {
+            Some(ref mut optimizer) => optimizer.optimize(program),
+            None => program,
+        };
+        self.start_run();
+        match self.engine {
+            Engine::Evaluator(ref mut evaluator) => evaluator.eval(program),
+            Engine::Vm(ref mut vm) => vm.eval(program),
+        }
+    }
+
+    /// Parses and runs a piece of source, returning the value of its last
+    /// statement, or `Null` if it has none.
+    pub fn eval_str(&mut self, input: &str) -> Result<Object, Error> {
+        let mut parser = Parser::new(Lexer::new(input));
+        let program = parser.parse();
+        let errors = parser.get_errors();
+        if !errors.is_empty() {
+            return Err(Error::Parse(errors));
+        }
+
+        Self::into_result(self.eval(program).unwrap_or(Object::Null))
+    }
+
+    /// Reads a script from `path` and runs it like [`Interpreter::eval_str`].
+    pub fn eval_file<P: AsRef<Path>>(&mut self, path: P) -> Result<Object, Error> {
+        let input = fs::read_to_string(path).map_err(Error::Io)?;
+        self.eval_str(&input)
+    }
+
+    /// Looks up a global binding, such as a function defined by a script.
+    pub fn get_global(&self, name: &str) -> Option<Object> {
+        self.env.borrow().get_global(name)
+    }
+
+    /// Writes the globals defined so far as a script that defines them again.
+    ///
+    /// Data values are written as literals and functions by their source.
+    /// Builtins are left out, as are closures over local variables, which are
+    /// noted in a comment.
+    pub fn snapshot(&self) -> String {
+        snapshot::snapshot(&self.env, &self.builtins)
+    }
+
+    /// Saves a [`snapshot`](Interpreter::snapshot) of the globals to `path`.
+    pub fn save_snapshot<P: AsRef<Path>>(&self, path: P) -> Result<(), Error> {
+        fs::write(path, self.snapshot()).map_err(Error::Io)
+    }
+
+    /// Restores globals saved with [`Interpreter::save_snapshot`], such as a
+    /// session to pick up again or state prepared ahead of time for a warm start.
+    pub fn load_snapshot<P: AsRef<Path>>(&mut self, path: P) -> Result<(), Error> {
+        self.eval_file(path).map(|_| ())
+    }
+
+    /// Binds a global variable visible to scripts run afterwards.
+    ///
+    /// Fails with a `NameError` if `name` is a constant, such as a builtin.
+    pub fn set_global(&mut self, name: &str, value: Object) -> Result<(), Error> {
+        self.env
+            .borrow_mut()
+            .declare(String::from(name), &value, false)
+            .map_err(|msg| Error::Runtime(RuntimeError::new(ErrorKind::NameError, msg)))
+    }
+
+    /// Calls the global function `name`, which may be defined by a script or
+    /// be a builtin.
+    pub fn call_function(&mut self, name: &str, args: Vec<Object>) -> Result<Object, Error> {
+        let func = match self.get_global(name) {
+            Some(func) => func,
+            None => {
+                return Err(Error::Runtime(RuntimeError::new(
+                    ErrorKind::NameError,
+                    format!("identifier not found: {}", name),
+                )))
+            }
+        };
+
+        self.start_run();
+        let result = match self.engine {
+            Engine::Evaluator(ref mut evaluator) => evaluator.apply(func, args),
+            Engine::Vm(ref mut vm) => vm.apply(func, args),
+        };
+        Self::into_result(result)
+    }
+
+    /// A handle through which another thread can stop the script being run.
+    ///
+    /// Only a run in progress is stopped; interrupts that come in between runs
+    /// are dropped when the next run starts.
+    pub fn interrupt_handle(&self) -> InterruptHandle {
+        self.interrupt.clone()
+    }
+
+    fn start_run(&mut self) {
+        self.interrupt.clear();
+        match self.engine {
+            Engine::Evaluator(ref mut evaluator) => evaluator.set_limits(&self.limits),
+            Engine::Vm(ref mut vm) => vm.set_limits(&self.limits),
+        }
+    }
+
+    fn into_result(obj: Object) -> Result<Object, Error> {
+        match obj {
+            Object::Error(err) => Err(Error::Runtime(err)),
+            obj => Ok(obj),
+        }
+    }
+
+    fn load_prelude(&mut self) {
+        let mut parser = Parser::new(Lexer::new(STAND_PRELUDE));
+        let program = parser.parse();
+        let errors = parser.get_errors();
+
+        if !errors.is_empty() {
+            for err in errors {
+                println!("Prelude Error: {}", err);
+            }
+        }
+
+        if let Some(evaluated) = self.eval(program) {
+            println!("{}\n", evaluated);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::evaluator::error::ErrorKind;
+    use crate::evaluator::host::MemoryHost;
+    use crate::evaluator::object::{FromObject, IntoObject, Object};
+    use crate::interpreter::*;
+    use std::cell::Cell;
+    use std::collections::HashMap;
+    use std::path::PathBuf;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    fn eval(interpreter: &mut Interpreter, input: &str) -> Option<Object> {
+        interpreter.eval(Parser::new(Lexer::new(input)).parse())
+    }
+
+    /// Interpreters for both engines.
+    fn interpreters() -> Vec<Interpreter> {
+        vec![
+            Interpreter::new(),
+            Interpreter::with_config(&Config {
+                vm: true,
+                ..Config::default()
+            }),
+        ]
+    }
+
+    #[test]
+    fn test_register_stateful_native() {
+        for mut interpreter in interpreters() {
+            let total = Rc::new(Cell::new(0));
+            let sum = Rc::clone(&total);
+            interpreter.register("add", 1, move |_, args| match args[0] {
+                Object::Int(n) => {
+                    sum.set(sum.get() + n);
+                    Object::Int(sum.get())
+                }
+                ref o => Object::error(ErrorKind::TypeError, format!("not a number: {}", o)),
+            });
+
+            assert_eq!(
+                Some(Object::Int(6)),
+                eval(&mut interpreter, "add(1); add(2); add(3)")
+            );
+            assert_eq!(6, total.get());
+            match eval(&mut interpreter, "add(1, 2)") {
+                Some(Object::Error(err)) => assert_eq!(ErrorKind::ArityError, err.kind),
+                o => panic!("expected an error, got {:?}", o),
+            }
+            match eval(&mut interpreter, "let add = 1;") {
+                Some(Object::Error(err)) => assert_eq!(ErrorKind::NameError, err.kind),
+                o => panic!("expected an error, got {:?}", o),
+            }
+        }
+    }
+
+    #[test]
+    fn test_native_calls_back() {
+        for mut interpreter in interpreters() {
+            interpreter.register("twice", 2, |interp, mut args| {
+                let x = args.pop().unwrap_or(Object::Null);
+                let f = args.pop().unwrap_or(Object::Null);
+                let once = interp.apply(f.clone(), vec![x]);
+                interp.apply(f, vec![once])
+            });
+            interpreter.register("lookup", 1, |interp, args| match args[0] {
+                Object::String(ref name) => interp.get_global(name).unwrap_or(Object::Null),
+                _ => Object::Null,
+            });
+
+            let input = "let inc = fun(x) { x + 1 }; [twice(inc, 1), lookup(\"inc\")(0)]";
+            assert_eq!(
+                Some(Object::Array(Rc::new(vec![Object::Int(3), Object::Int(1)]))),
+                eval(&mut interpreter, input)
+            );
+            match eval(&mut interpreter, "twice(len, [1])") {
+                Some(Object::Error(err)) => assert_eq!(ErrorKind::TypeError, err.kind),
+                o => panic!("expected an error, got {:?}", o),
+            }
+        }
+    }
+
+    #[test]
+    fn test_native_in_filter() {
+        for mut interpreter in interpreters() {
+            let seen = Rc::new(Cell::new(0));
+            let count = Rc::clone(&seen);
+            interpreter.register("isEven", 1, move |_, args| {
+                count.set(count.get() + 1);
+                match args[0] {
+                    Object::Int(n) => Object::Bool(n % 2 == 0),
+                    ref o => Object::error(ErrorKind::TypeError, format!("not a number: {}", o)),
+                }
+            });
+            interpreter.register("between", 3, |_, _| Object::Bool(true));
+
+            assert_eq!(
+                Some(vec![Object::Int(2), Object::Int(4)].into_object()),
+                eval(&mut interpreter, "filter([1, 2, 3, 4], isEven)")
+            );
+            assert_eq!(4, seen.get());
+            match eval(&mut interpreter, "filter([1, \"a\", 3], isEven)") {
+                Some(Object::Error(err)) => {
+                    assert_eq!(ErrorKind::TypeError, err.kind);
+                    assert_eq!("not a number: a", err.message);
+                }
+                o => panic!("expected an error, got {:?}", o),
+            }
+            // The arity is checked before the closure runs
+            match eval(&mut interpreter, "filter([1], between)") {
+                Some(Object::Error(err)) => assert_eq!(ErrorKind::ArityError, err.kind),
+                o => panic!("expected an error, got {:?}", o),
+            }
+        }
+    }
+
+    #[test]
+    fn test_recursion_on_default_stack() {
+        let inputs = [
+            "let f = fun(n) { f(n + 1) + 1 }; f(0)",
+            "let g = fun(n) { map([n], fun(x) { g(x + 1) }) }; g(0)",
+        ];
+        for vm in [false, true] {
+            for max_depth in [DEFAULT_MAX_DEPTH, 10 * DEFAULT_MAX_DEPTH] {
+                // A plain thread only gets the default stack
+                let kinds = thread::spawn(move || {
+                    let mut interpreter = Interpreter::with_config(&Config {
+                        vm,
+                        max_depth,
+                        ..Config::default()
+                    });
+                    inputs.map(|input| match interpreter.eval_str(input) {
+                        Err(Error::Runtime(err)) => Some(err.kind),
+                        _ => None,
+                    })
+                })
+                .join()
+                .unwrap();
+                assert_eq!([Some(ErrorKind::RecursionError); 2], kinds, "vm: {}", vm);
+            }
+        }
+    }
+
+    #[test]
+    fn test_forward_reference_across_calls() {
+        for mut interpreter in interpreters() {
+            interpreter
+                .eval_str("let greet = fun() { \"hello \" + name };")
+                .unwrap();
+            match interpreter.eval_str("greet()") {
+                Err(Error::Runtime(err)) => {
+                    assert_eq!(ErrorKind::NameError, err.kind);
+                    assert_eq!("identifier not found: name", err.message);
+                }
+                o => panic!("expected a NameError, got {:?}", o),
+            }
+
+            interpreter.eval_str("let name = \"world\";").unwrap();
+            assert_eq!(
+                Object::String(String::from("hello world")),
+                interpreter.eval_str("greet()").unwrap()
+            );
+        }
+    }
+
+    #[test]
+    fn test_eval_str() {
+        for mut interpreter in interpreters() {
+            assert_eq!(
+                Object::Int(3),
+                interpreter.eval_str("let x = 1; x + 2").unwrap()
+            );
+            assert_eq!(Object::Null, interpreter.eval_str("let y = 1;").unwrap());
+            assert!(matches!(
+                interpreter.eval_str("let = 1;"),
+                Err(Error::Parse(_))
+            ));
+            match interpreter.eval_str("x + true") {
+                Err(Error::Runtime(err)) => assert_eq!(ErrorKind::TypeError, err.kind),
+                o => panic!("expected an error, got {:?}", o),
+            }
+            assert!(matches!(
+                interpreter.eval_file("no/such/script.bvr"),
+                Err(Error::Io(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_globals_and_calls() {
+        for mut interpreter in interpreters() {
+            interpreter.set_global("limit", Object::Int(10)).unwrap();
+            interpreter
+                .eval_str("let check = fun(n) { if (n > limit) { throw \"too big\" } else { n } };")
+                .unwrap();
+            assert_eq!(Some(Object::Int(10)), interpreter.get_global("limit"));
+            assert_eq!(None, interpreter.get_global("missing"));
+
+            let check = |interpreter: &mut Interpreter, n| {
+                interpreter.call_function("check", vec![Object::Int(n)])
+            };
+            assert_eq!(Object::Int(5), check(&mut interpreter, 5).unwrap());
+            match check(&mut interpreter, 11) {
+                Err(Error::Runtime(err)) => assert_eq!("too big", err.message),
+                o => panic!("expected an error, got {:?}", o),
+            }
+            interpreter.set_global("limit", Object::Int(20)).unwrap();
+            assert_eq!(Object::Int(11), check(&mut interpreter, 11).unwrap());
+
+            assert_eq!(
+                Object::Int(2),
+                interpreter
+                    .call_function("len", vec![Object::String(String::from("ab"))])
+                    .unwrap()
+            );
+            match interpreter.call_function("missing", vec![]) {
+                Err(Error::Runtime(err)) => assert_eq!(ErrorKind::NameError, err.kind),
+                o => panic!("expected an error, got {:?}", o),
+            }
+            match interpreter.call_function("check", vec![]) {
+                Err(Error::Runtime(err)) => assert_eq!(ErrorKind::ArityError, err.kind),
+                o => panic!("expected an error, got {:?}", o),
+            }
+            match interpreter.set_global("len", Object::Null) {
+                Err(Error::Runtime(err)) => assert_eq!(ErrorKind::NameError, err.kind),
+                o => panic!("expected an error, got {:?}", o),
+            }
+        }
+    }
+
+    #[test]
+    fn test_register_fn() {
+        for mut interpreter in interpreters() {
+            interpreter.register_fn("repeat", |n: i64, s: String| {
+                if n < 0 {
+                    return Err(format!("negative count: {}", n));
+                }
+                Ok(vec![s.len() as i64; n as usize])
+            });
+            interpreter.register_fn("lookup", |map: HashMap<String, i64>, key: String| {
+                map.get(&key).copied()
+            });
+            interpreter.register_fn("swap", |(a, b): (i64, String)| (b, a));
+
+            let result = interpreter.eval_str("[repeat(2, \"ab\"), lookup({\"a\": 1}, \"a\"), lookup({}, \"a\"), swap([1, \"x\"])]");
+            assert_eq!(
+                Object::Array(Rc::new(vec![
+                    vec![2i64, 2].into_object(),
+                    Object::Int(1),
+                    Object::Null,
+                    ("x", 1i64).into_object(),
+                ])),
+                result.unwrap()
+            );
+
+            let errors = [
+                ("repeat(-1, \"ab\")", ErrorKind::Error, "negative count: -1"),
+                (
+                    "repeat(1)",
+                    ErrorKind::ArityError,
+                    "wrong number of arguments. got=1, want=2",
+                ),
+                (
+                    "repeat(\"1\", \"ab\")",
+                    ErrorKind::TypeError,
+                    "argument 1 to `repeat`: expected Int, got String",
+                ),
+                (
+                    "lookup({\"a\": true}, \"a\")",
+                    ErrorKind::TypeError,
+                    "argument 1 to `lookup`: expected Int, got Bool",
+                ),
+                (
+                    "swap([1])",
+                    ErrorKind::TypeError,
+                    "argument 1 to `swap`: expected List of 2 elements, got List",
+                ),
+            ];
+            for (input, kind, message) in errors {
+                match interpreter.eval_str(input) {
+                    Err(Error::Runtime(err)) => {
+                        assert_eq!(kind, err.kind);
+                        assert_eq!(message, err.message);
+                    }
+                    o => panic!("expected an error for {}, got {:?}", input, o),
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_object_conversions() {
+        let map = HashMap::from([(String::from("a"), vec![Some(true), None])]);
+        assert_eq!(
+            map,
+            HashMap::from_object(map.clone().into_object()).unwrap()
+        );
+        assert_eq!(
+            (1i64, String::from("b")),
+            <(i64, String)>::from_object((1i64, "b").into_object()).unwrap()
+        );
+        assert_eq!(Object::Null, ().into_object());
+        assert!(Vec::<i64>::from_object(vec!["a"].into_object()).is_err());
+    }
+
+    #[test]
+    fn test_memory_host() {
+        for vm in [false, true] {
+            let host = MemoryHost::new();
+            host.push_input("world");
+            host.set_file("in.txt", "42");
+            let config = Config {
+                vm,
+                result_mode: true,
+                ..Config::default()
+            };
+            let mut interpreter = Interpreter::with_host(&config, host.clone());
+
+            let input = "
+                print(\"hello\", read());
+                let n = parseNumber(readFile(\"in.txt\").value).value;
+                writeFile(\"out.txt\", \"answer: \" + readFile(\"in.txt\").value);
+                [n, readFile(\"missing.txt\")]
+            ";
+            assert_eq!(
+                vec![
+                    Object::Int(42),
+                    Object::err(Object::String(String::from(
+                        "Error opening file: no such file: missing.txt"
+                    ))),
+                ]
+                .into_object(),
+                interpreter.eval_str(input).unwrap()
+            );
+            assert_eq!("hello\nworld\n", host.output());
+            assert_eq!(Some(String::from("answer: 42")), host.file("out.txt"));
+        }
+    }
+
+    #[test]
+    fn test_result_mode_errors() {
+        for vm in [false, true] {
+            let host = MemoryHost::new();
+            host.set_file("/etc/passwd", "root");
+            let config = Config {
+                vm,
+                result_mode: true,
+                sandbox: Some(Sandbox {
+                    read_paths: vec![PathBuf::from("/srv")],
+                    ..Sandbox::default()
+                }),
+                ..Config::default()
+            };
+            let mut interpreter = Interpreter::with_host(&config, host);
+
+            let results = [
+                (
+                    "parseNumber(\"ten\")",
+                    Object::err(Object::String(String::from("could not parse int"))),
+                ),
+                (
+                    "readFile(\"/srv/missing.txt\")",
+                    Object::err(Object::String(String::from(
+                        "Error opening file: no such file: /srv/missing.txt",
+                    ))),
+                ),
+            ];
+            for (input, expect) in results {
+                assert_eq!(expect, interpreter.eval_str(input).unwrap(), "{}", input);
+            }
+
+            // Bugs in the call and denied accesses are not turned into `Err`
+            let raised = [
+                ("readFile(1)", ErrorKind::TypeError),
+                ("parseNumber(\"1\", \"2\")", ErrorKind::ArityError),
+                ("readFile(\"/etc/passwd\")", ErrorKind::PermissionError),
+                (
+                    "let f = fun() { Ok(readFile(\"/etc/passwd\")?) }; f()",
+                    ErrorKind::PermissionError,
+                ),
+            ];
+            for (input, kind) in raised {
+                match interpreter.eval_str(input) {
+                    Err(Error::Runtime(err)) => assert_eq!(kind, err.kind, "{}", input),
+                    o => panic!("expected an error for {}, got {:?}", input, o),
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_host_output_order() {
+        for vm in [false, true] {
+            let host = MemoryHost::new();
+            let config = Config {
+                vm,
+                ..Config::default()
+            };
+            let mut interpreter = Interpreter::with_host(&config, host.clone());
+
+            interpreter
+                .eval_str("print(\"a\", 1); let f = fun(x) { print(x); x }; map([2, 3], f);")
+                .unwrap();
+            interpreter.eval_str("print(\"b\"); f(4)").unwrap();
+            assert_eq!("a\n1\n2\n3\nb\n4\n", host.output());
+        }
+    }
+
+    #[test]
+    fn test_host_input_exhausted() {
+        for vm in [false, true] {
+            let host = MemoryHost::new();
+            host.push_input("only");
+            let config = Config {
+                vm,
+                ..Config::default()
+            };
+            let mut interpreter = Interpreter::with_host(&config, host.clone());
+
+            assert_eq!(
+                vec!["only", "", ""].into_object(),
+                interpreter.eval_str("[read(), read(), read()]").unwrap()
+            );
+        }
+    }
+
+    /// Fails every write, as a full disk would.
+    struct ReadOnlyHost(MemoryHost);
+
+    impl Host for ReadOnlyHost {
+        fn print_line(&self, line: &str) {
+            self.0.print_line(line);
+        }
+
+        fn read_line(&self) -> io::Result<String> {
+            self.0.read_line()
+        }
+
+        fn read_file(&self, path: &str) -> io::Result<String> {
+            self.0.read_file(path)
+        }
+
+        fn write_file(&self, _path: &str, _contents: &str) -> io::Result<()> {
+            Err(io::Error::other("no space left on device"))
+        }
+    }
+
+    #[test]
+    fn test_host_write_errors() {
+        let message = "Failed to write to file: no space left on device";
+        for vm in [false, true] {
+            let host = MemoryHost::new();
+            let config = Config {
+                vm,
+                ..Config::default()
+            };
+            let mut interpreter = Interpreter::with_host(&config, ReadOnlyHost(host.clone()));
+
+            match interpreter.eval_str("writeFile(\"out.txt\", \"x\"); print(\"after\")") {
+                Err(Error::Runtime(err)) => {
+                    assert_eq!(ErrorKind::IOError, err.kind);
+                    assert_eq!(message, err.message);
+                }
+                o => panic!("expected an error, got {:?}", o),
+            }
+            assert_eq!("", host.output());
+            assert_eq!(
+                vec!["IOError", message].into_object(),
+                interpreter
+                    .eval_str(
+                        "try { writeFile(\"out.txt\", \"x\") } catch (e) { [e.kind, e.message] }"
+                    )
+                    .unwrap()
+            );
+
+            let config = Config {
+                result_mode: true,
+                ..config
+            };
+            let mut interpreter = Interpreter::with_host(&config, ReadOnlyHost(host.clone()));
+            assert_eq!(
+                Object::err(Object::String(String::from(message))),
+                interpreter
+                    .eval_str("writeFile(\"out.txt\", \"x\")")
+                    .unwrap()
+            );
+            assert_eq!(None, host.file("out.txt"));
+        }
+    }
+
+    #[test]
+    fn test_sandbox() {
+        let host = MemoryHost::new();
+        host.push_input("secret");
+        host.set_file("/etc/passwd", "root");
+        host.set_file("/srv/rules/limits.txt", "10");
+        let config = Config {
+            sandbox: Some(Sandbox {
+                read_paths: vec![PathBuf::from("/srv/rules")],
+                write_paths: vec![PathBuf::from("/tmp/out")],
+                ..Sandbox::default()
+            }),
+            ..Config::default()
+        };
+        let mut interpreter = Interpreter::with_host(&config, host.clone());
+
+        assert_eq!(
+            Object::String(String::from("10")),
+            interpreter
+                .eval_str(
+                    "writeFile(\"/tmp/out/a.txt\", \"ok\"); readFile(\"/srv/rules/limits.txt\")"
+                )
+                .unwrap()
+        );
+        assert_eq!(Some(String::from("ok")), host.file("/tmp/out/a.txt"));
+
+        let denied = [
+            ("read()", "Error reading input: reading input is not allowed by the sandbox"),
+            ("readFile(\"/etc/passwd\")", "Error opening file: reading /etc/passwd is not allowed by the sandbox"),
+            ("readFile(\"/srv/rules/../../etc/passwd\")", "Error opening file: reading /etc/passwd is not allowed by the sandbox"),
+            ("writeFile(\"/srv/rules/limits.txt\", \"0\")", "Failed to write to file: writing /srv/rules/limits.txt is not allowed by the sandbox"),
+        ];
+        for (input, message) in denied {
+            match interpreter.eval_str(input) {
+                Err(Error::Runtime(err)) => {
+                    assert_eq!(ErrorKind::PermissionError, err.kind);
+                    assert_eq!(message, err.message);
+                }
+                o => panic!("expected an error for {}, got {:?}", input, o),
+            }
+        }
+        assert_eq!(Some(String::from("10")), host.file("/srv/rules/limits.txt"));
+        assert_eq!(
+            Object::String(String::from("PermissionError")),
+            interpreter
+                .eval_str("try { readFile(\"/etc/passwd\") } catch (e) { e.kind }")
+                .unwrap()
+        );
+    }
+
+    #[test]
+    fn test_limits() {
+        let limited = |limits: Limits, vm: bool| {
+            let mut interpreter = Interpreter::with_config(&Config {
+                vm,
+                limits,
+                ..Config::default()
+            });
+            interpreter
+                .eval_str(
+                    "let spin = fun(n) { spin(n + 1) }; let grow = fun(xs) { grow(xs + xs) };",
+                )
+                .unwrap();
+            interpreter
+        };
+        let fuel = Limits {
+            fuel: Some(10_000),
+            ..Limits::default()
+        };
+        let timeout = Limits {
+            timeout: Some(Duration::from_millis(50)),
+            ..Limits::default()
+        };
+        let alloc = Limits {
+            max_alloc: Some(1000),
+            ..Limits::default()
+        };
+        let tests = vec![
+            (fuel, "spin(0)", "ran out of fuel after 10000 steps"),
+            (
+                fuel,
+                "try { spin(0) } catch (e) { 0 } finally { 1 }",
+                "ran out of fuel after 10000 steps",
+            ),
+            (timeout, "spin(0)", "ran out of time"),
+            (alloc, "grow([1])", "exceeded the allocation limit of 1000"),
+            (
+                alloc,
+                "map(explode(\"ab\"), fun(c) { grow([c]) })",
+                "exceeded the allocation limit of 1000",
+            ),
+            (
+                alloc,
+                "let double = fun(s) { double(s + s) }; double(\"ab\")",
+                "exceeded the allocation limit of 1000",
+            ),
+            (
+                alloc,
+                "let nest = fun(h, n) { nest({\"inner\": h, \"n\": n}, n + 1) }; nest({}, 0)",
+                "exceeded the allocation limit of 1000",
+            ),
+        ];
+
+        for vm in [false, true] {
+            for (limits, input, message) in &tests {
+                let mut interpreter = limited(*limits, vm);
+                match interpreter.eval_str(input) {
+                    Err(Error::Runtime(err)) => {
+                        assert_eq!(ErrorKind::LimitError, err.kind, "{}", input);
+                        assert_eq!(*message, err.message, "{}", input);
+                    }
+                    o => panic!("expected an error for {}, got {:?}", input, o),
+                }
+                // Each run starts with a fresh budget
+                assert_eq!(Object::Int(3), interpreter.eval_str("1 + 2").unwrap());
+            }
+        }
+    }
+
+    #[test]
+    fn test_fuel_counts_tail_calls() {
+        for vm in [false, true] {
+            let with_fuel = |fuel| {
+                let mut interpreter = Interpreter::with_config(&Config {
+                    vm,
+                    max_depth: 100,
+                    limits: Limits {
+                        fuel: Some(fuel),
+                        ..Limits::default()
+                    },
+                    ..Config::default()
+                });
+                interpreter
+                    .eval_str("let count = fun(n) { if (n == 0) { 0 } else { count(n - 1) } };")
+                    .unwrap();
+                interpreter
+            };
+
+            // The calls run on the trampoline, far deeper than `max_depth`
+            let mut interpreter = with_fuel(1_000_000);
+            assert_eq!(Object::Int(0), interpreter.eval_str("count(1000)").unwrap());
+
+            // but each of them still takes fuel
+            let mut interpreter = with_fuel(1000);
+            match interpreter.eval_str("count(1000)") {
+                Err(Error::Runtime(err)) => {
+                    assert_eq!(ErrorKind::LimitError, err.kind);
+                    assert_eq!("ran out of fuel after 1000 steps", err.message);
+                }
+                o => panic!("expected an error, got {:?}", o),
+            }
+        }
+    }
+
+    #[test]
+    fn test_interrupt() {
+        for mut interpreter in interpreters() {
+            interpreter.eval_str("let answer = 42;").unwrap();
+
+            // Interrupt until the run is over, as the run clears earlier interrupts
+            let interrupt = interpreter.interrupt_handle();
+            let done = Arc::new(AtomicBool::new(false));
+            let interrupter = {
+                let done = Arc::clone(&done);
+                thread::spawn(move || {
+                    while !done.load(Ordering::Relaxed) {
+                        interrupt.interrupt();
+                        thread::sleep(Duration::from_millis(10));
+                    }
+                })
+            };
+            let result = interpreter.eval_str(
+                "let spin = fun(n) { spin(n + 1) }; try { spin(0) } catch (e) { 0 } finally { 1 }",
+            );
+            done.store(true, Ordering::Relaxed);
+            interrupter.join().unwrap();
+
+            match result {
+                Err(Error::Runtime(err)) => assert_eq!(ErrorKind::InterruptError, err.kind),
+                o => panic!("expected an interrupt, got {:?}", o),
+            }
+            // The session goes on with its globals
+            assert_eq!(Object::Int(43), interpreter.eval_str("answer + 1").unwrap());
+        }
+    }
+
+    #[test]
+    fn test_snapshot() {
+        let input = r#"
+struct Point { x, y }
+enum Shape { Circle(r), Empty }
+let origin = Point(0, -1);
+const shapes = [Circle(2), Empty];
+let table = {"a": [1, true, null], "b": {"c": "d"}};
+let area = fun(shape) {
+  if (tagOf(shape) == "Circle") { 3 * shape.r * shape.r } else { 0 }
+};
+let total = fun(xs) { fold(fun(acc, s) { acc + area(s) }, 0, xs) };
+let size = len;
+let counter = fun() { let n = 1; fun() { n } }();
+"#;
+        let dir = std::env::temp_dir();
+
+        for (i, mut interpreter) in interpreters().into_iter().enumerate() {
+            interpreter.register_fn("twice", |n: i64| n * 2);
+            interpreter.eval_str(input).unwrap();
+            interpreter.set_global("quote", "\"".into_object()).unwrap();
+
+            let snapshot = interpreter.snapshot();
+            assert!(snapshot.contains("// counter was not saved: it captures local variables"));
+            assert!(snapshot.contains("// quote was not saved: strings with quotes"));
+            assert!(!snapshot.contains("twice"));
+
+            let path = dir.join(format!(
+                "beavieeer_snapshot_{}_{}.be",
+                std::process::id(),
+                i
+            ));
+            interpreter.save_snapshot(&path).unwrap();
+            let mut restored = Interpreter::with_config(&Config {
+                vm: i == 1,
+                ..Config::default()
+            });
+            restored.load_snapshot(&path).unwrap();
+            std::fs::remove_file(&path).unwrap();
+
+            for check in [
+                "origin",
+                "shapes",
+                "table",
+                "total(shapes)",
+                "size(shapes)",
+                "typeOf(Empty)",
+            ] {
+                assert_eq!(
+                    interpreter.eval_str(check).unwrap(),
+                    restored.eval_str(check).unwrap(),
+                    "{}",
+                    check
+                );
+            }
+            assert_eq!(Object::Int(12), restored.eval_str("total(shapes)").unwrap());
+            assert!(restored.eval_str("let shapes = [];").is_err());
+        }
+    }
+
+    #[test]
+    fn test_higher_order_builtins() {
+        let strings = |items: &[&str]| {
+            items
+                .iter()
+                .map(|s| s.to_string())
+                .collect::<Vec<_>>()
+                .into_object()
+        };
+        let tests = vec![
+            ("map([\"a\", \"b\"], uppercase)", Ok(strings(&["A", "B"]))),
+            (
+                "[\" a\", \"b \"].map(trim).filter(fun(s) { s != \"a\" })",
+                Ok(strings(&["b"])),
+            ),
+            (
+                "struct Box { v }; map([1], Box)",
+                Ok(vec![Object::Struct(
+                    String::from("Box"),
+                    vec![(String::from("v"), Object::Int(1))],
+                )]
+                .into_object()),
+            ),
+            (
+                "filter([1, 2, 3], fun(x) { if (x == 2) { return false; } true })",
+                Ok(vec![Object::Int(1), Object::Int(3)].into_object()),
+            ),
+            (
+                "map([[1], [], [2, 3]], len)",
+                Ok(vec![Object::Int(1), Object::Int(0), Object::Int(2)].into_object()),
+            ),
+            (
+                "map([1], fun(a, b) { a })",
+                Err((
+                    ErrorKind::ArityError,
+                    "wrong number of arguments: 2 expected but 1 given",
+                )),
+            ),
+            (
+                "map([\"a\"], replaceString)",
+                Err((
+                    ErrorKind::ArityError,
+                    "wrong number of arguments. got=1, want=3",
+                )),
+            ),
+            (
+                "filter([\"a\"], replaceN)",
+                Err((
+                    ErrorKind::ArityError,
+                    "wrong number of arguments. got=1, want=4",
+                )),
+            ),
+            (
+                "map([1], 2)",
+                Err((
+                    ErrorKind::TypeError,
+                    "second argument to `map` must be a function, got 2",
+                )),
+            ),
+            (
+                "let f = fun(xs) { map(xs, fun(x) { f([x]) }) }; f([1])",
+                Err((
+                    ErrorKind::RecursionError,
+                    "maximum recursion depth exceeded",
+                )),
+            ),
+        ];
+
+        for vm in [false, true] {
+            // Nested callbacks count towards the depth limit too
+            let mut interpreter = Interpreter::with_config(&Config {
+                vm,
+                max_depth: 100,
+                ..Config::default()
+            });
+            for (input, expect) in &tests {
+                match (interpreter.eval_str(input), expect) {
+                    (Ok(value), Ok(expect)) => assert_eq!(*expect, value, "{}", input),
+                    (Err(Error::Runtime(err)), Err((kind, message))) => {
+                        assert_eq!(*kind, err.kind, "{}", input);
+                        assert_eq!(*message, err.message, "{}", input);
+                    }
+                    (o, _) => panic!("unexpected result for {}: {:?}", input, o),
+                }
+            }
+        }
+    }
+}
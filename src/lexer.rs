@@ -6,14 +6,18 @@
 ///
 /// The `Lexer` struct scans through the input and produces tokens
 /// based on the Beavieeer programming language's syntax.
+use crate::ast::Span;
 use crate::token::Token;
 
 /// Represents the lexical analyzer (lexer) for tokenizing input.
 pub struct Lexer<'a> {
     input: &'a str,
-    pos: usize,      // Current position in input (points to current character)
-    next_pos: usize, // Next reading position in input
-    ch: u8,          // Current character being examined
+    pos: usize,        // Current position in input (points to current character)
+    next_pos: usize,   // Next reading position in input
+    ch: u8,            // Current character being examined
+    line: usize,       // Line of the current character
+    line_start: usize, // Position in input where the current line starts
+    span: Span,        // Where the last returned token starts
 }
 
 impl<'a> Lexer<'a> {
@@ -26,6 +30,9 @@ impl<'a> Lexer<'a> {
             pos: 0,
             next_pos: 0,
             ch: 0,
+            line: 1,
+            line_start: 0,
+            span: Span::default(),
         };
 
         lexer.read_char();
@@ -34,6 +41,11 @@ impl<'a> Lexer<'a> {
 
     /// Reads the next character from the input and advances position markers.
     fn read_char(&mut self) {
+        if self.ch == b'\n' {
+            self.line += 1;
+            self.line_start = self.next_pos;
+        }
+
         if self.next_pos >= self.input.len() {
             self.ch = 0; // End of file (EOF)
         } else {
@@ -82,9 +94,15 @@ impl<'a> Lexer<'a> {
         }
     }
 
+    /// Returns the position where the last token returned by `next_token` starts.
+    pub fn span(&self) -> Span {
+        self.span
+    }
+
     /// Retrieves the next token from the input.
     pub fn next_token(&mut self) -> Token {
         self.skip_whitespace();
+        self.span = Span::new(self.line, self.pos - self.line_start + 1);
 
         let tok = match self.ch {
             b'=' => {
@@ -132,6 +150,21 @@ impl<'a> Lexer<'a> {
             b',' => Token::Comma,
             b';' => Token::Semicolon,
             b':' => Token::Colon,
+            b'.' => Token::Dot,
+            b'?' => {
+                if self.nextch_is(b'?') {
+                    self.read_char();
+                    Token::NullCoalesce
+                } else if self.nextch_is(b'.') {
+                    self.read_char();
+                    Token::QuestionDot
+                } else if self.nextch_is(b'[') {
+                    self.read_char();
+                    Token::QuestionLbracket
+                } else {
+                    Token::Question
+                }
+            }
             b'a'..=b'z' | b'A'..=b'Z' | b'_' => return self.consume_identifier(),
             b'0'..=b'9' => return self.consume_number(),
             b'"' => return self.consume_string(),
@@ -163,11 +196,19 @@ impl<'a> Lexer<'a> {
         match literal {
             "fun" => Token::Func,
             "let" => Token::Let,
+            "const" => Token::Const,
             "true" => Token::Bool(true),
             "false" => Token::Bool(false),
+            "null" => Token::Null,
             "if" => Token::If,
             "else" => Token::Else,
             "return" => Token::Return,
+            "struct" => Token::Struct,
+            "enum" => Token::Enum,
+            "try" => Token::Try,
+            "catch" => Token::Catch,
+            "finally" => Token::Finally,
+            "throw" => Token::Throw,
             _ => Token::Ident(String::from(literal)),
         }
     }
@@ -4,7 +4,13 @@
 
 pub mod ast;
 pub mod evaluator;
+pub mod gc;
+pub mod interpreter;
 pub mod lexer;
+pub mod optimizer;
 pub mod parser;
 pub mod repl;
+pub mod resolver;
+pub mod snapshot;
 pub mod token;
+pub mod vm;
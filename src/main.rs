@@ -2,22 +2,67 @@
 // Use of this source code is governed by a MIT
 // license that can be found in the LICENSE file
 
-use beavieeer::repl;
+use beavieeer::evaluator::host::Sandbox;
+use beavieeer::repl::{self, Config};
 use std::env;
 use std::fs;
 use std::io::stdout;
 
 fn main() {
-    let args: Vec<String> = env::args().collect();
-    let stdout = stdout();
-    let mut stdout_lock = stdout.lock();
+    let mut config = Config::default();
+    let mut files = Vec::new();
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--result-mode" => config.result_mode = true,
+            "--vm" => config.vm = true,
+            "--no-optimize" => config.optimize = false,
+            "--sandbox" => {
+                sandbox(&mut config);
+            }
+            "--allow-stdin" => sandbox(&mut config).stdin = true,
+            "--allow-read" => match args.next() {
+                Some(path) => sandbox(&mut config).read_paths.push(path.into()),
+                None => {
+                    println!("--allow-read expects a path");
+                    return;
+                }
+            },
+            "--allow-write" => match args.next() {
+                Some(path) => sandbox(&mut config).write_paths.push(path.into()),
+                None => {
+                    println!("--allow-write expects a path");
+                    return;
+                }
+            },
+            "--max-depth" => match args.next().and_then(|n| n.parse().ok()) {
+                Some(max_depth) => config.max_depth = max_depth,
+                None => {
+                    println!("--max-depth expects a number");
+                    return;
+                }
+            },
+            _ => files.push(arg),
+        }
+    }
+
+    run(config, files);
+}
+
+/// The sandbox to grant capabilities on, turning it on if needed.
+fn sandbox(config: &mut Config) -> &mut Sandbox {
+    config.sandbox.get_or_insert_with(Sandbox::default)
+}
 
-    if args.len() == 1 {
-        repl::start_repl(&mut stdout_lock);
-    } else if args.len() == 2 {
+fn run(config: Config, files: Vec<String>) {
+    if files.is_empty() {
+        // Not locked for the whole session, so that the Ctrl-C handler can
+        // print at the prompt
+        repl::start_repl_with(&mut stdout(), &config);
+    } else if files.len() == 1 {
         let contents =
-            fs::read_to_string(args[1].clone()).expect("Should have been able to read the file");
-        repl::run_file(&contents);
+            fs::read_to_string(&files[0]).expect("Should have been able to read the file");
+        repl::run_file_with(&contents, &config);
     } else {
         println!("Invalid arguments");
     }
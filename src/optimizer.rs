@@ -0,0 +1,575 @@
+// Copyright 2024 Dimitrios Papakonstantinou. All rights reserved.
+// Use of this source code is governed by a MIT
+// license that can be found in the LICENSE file
+
+use crate::ast::*;
+use crate::evaluator::object::Object;
+use crate::evaluator::Evaluator;
+use crate::resolver::Resolver;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+/// Simplifies a program before it runs, without changing what it does.
+///
+/// Arithmetic, comparisons and string concatenation on literals are folded,
+/// `if` branches that can never run are dropped, names bound by `let` or
+/// `const` to a literal are replaced by the literal where the binding is known
+/// to hold, and statements after a `return` or `throw` are removed.
+///
+/// Code that declares names is kept even when it can not run, as the resolver
+/// still binds those names in the enclosing scope. Folding that would raise an
+/// error or overflow is left for the evaluator, so it is reported as usual.
+pub struct Optimizer {
+    /// Names bound to a literal at the point of the code being optimized.
+    constants: HashMap<String, Literal>,
+}
+
+impl Default for Optimizer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Optimizer {
+    pub fn new() -> Self {
+        Optimizer {
+            constants: HashMap::new(),
+        }
+    }
+
+    pub fn optimize(&mut self, program: Program) -> Program {
+        self.constants.clear();
+        self.optimize_block(program)
+    }
+
+    /// Runs `f`, restoring the known constants afterwards.
+    fn scoped<T>(&mut self, f: impl FnOnce(&mut Self) -> T) -> T {
+        let constants = self.constants.clone();
+        let result = f(self);
+        self.constants = constants;
+        result
+    }
+
+    /// Forgets the constants that `stmts` may declare again.
+    fn forget_block(&mut self, stmts: &[Stmt]) {
+        let mut names = vec![];
+        Resolver::hoist_block(stmts, &mut names);
+        for name in names {
+            self.constants.remove(&name);
+        }
+    }
+
+    fn optimize_block(&mut self, stmts: BlockStmt) -> BlockStmt {
+        let len = stmts.len();
+        let mut block = Vec::with_capacity(len);
+
+        for (index, stmt) in stmts.into_iter().enumerate() {
+            let last = index + 1 == len;
+            match self.optimize_stmt(stmt) {
+                // Blocks of an `if` share the scope around them, so one that
+                // always runs can take the place of the `if`, unless it is
+                // empty and the value of the block is needed
+                Stmt::Expr(Expr::If {
+                    cond,
+                    consequence,
+                    alternative: None,
+                }) if Self::literal_cond(&cond) == Some(true)
+                    && !(last && Self::is_empty(&consequence)) =>
+                {
+                    block.extend(consequence)
+                }
+                Stmt::Expr(Expr::If {
+                    cond,
+                    consequence,
+                    alternative: None,
+                }) if Self::literal_cond(&cond) == Some(false)
+                    && !last
+                    && !Self::declares(&consequence) => {}
+                stmt => block.push(stmt),
+            }
+        }
+
+        Self::drop_unreachable(block)
+    }
+
+    fn optimize_stmt(&mut self, stmt: Stmt) -> Stmt {
+        let stmt = match stmt {
+            Stmt::Let(name, expr) => Stmt::Let(name, self.optimize_expr(expr)),
+            Stmt::Const(name, expr) => Stmt::Const(name, self.optimize_expr(expr)),
+            Stmt::Return(expr) => Stmt::Return(self.optimize_expr(expr)),
+            Stmt::Throw(expr) => Stmt::Throw(self.optimize_expr(expr)),
+            Stmt::Expr(expr) => Stmt::Expr(self.optimize_expr(expr)),
+            stmt => stmt,
+        };
+
+        for name in Resolver::declared_names(&stmt) {
+            self.constants.remove(&name);
+        }
+        match stmt {
+            Stmt::Let(Ident(ref name), Expr::Literal(ref literal))
+            | Stmt::Const(Ident(ref name), Expr::Literal(ref literal))
+                if Self::literal_value(literal).is_some() =>
+            {
+                self.constants.insert(name.clone(), literal.clone());
+            }
+            _ => {}
+        }
+        stmt
+    }
+
+    fn optimize_expr(&mut self, expr: Expr) -> Expr {
+        match expr {
+            Expr::Ident(Ident(name)) => match self.constants.get(&name) {
+                Some(literal) => Expr::Literal(literal.clone()),
+                None => Expr::Ident(Ident(name)),
+            },
+            Expr::Literal(Literal::Array(items)) => {
+                Expr::Literal(Literal::Array(self.optimize_exprs(items)))
+            }
+            Expr::Literal(Literal::Hash(pairs)) => Expr::Literal(Literal::Hash(
+                pairs
+                    .into_iter()
+                    .map(|(key, value)| (self.optimize_expr(key), self.optimize_expr(value)))
+                    .collect(),
+            )),
+            Expr::Prefix(prefix, right) => {
+                let right = self.optimize_expr(*right);
+                match Self::fold_prefix(prefix, &right) {
+                    Some(literal) => Expr::Literal(literal),
+                    None => Expr::Prefix(prefix, Box::new(right)),
+                }
+            }
+            Expr::Infix(Infix::NullCoalesce, left, right) => {
+                let left = self.optimize_expr(*left);
+                let right = self.optimize_expr(*right);
+                match left {
+                    Expr::Literal(Literal::Null) => right,
+                    Expr::Literal(ref literal)
+                        if Self::literal_value(literal).is_some() && !Self::declares_in(&right) =>
+                    {
+                        left
+                    }
+                    left => Expr::Infix(Infix::NullCoalesce, Box::new(left), Box::new(right)),
+                }
+            }
+            Expr::Infix(infix, left, right) => {
+                let left = self.optimize_expr(*left);
+                let right = self.optimize_expr(*right);
+                match Self::fold_infix(infix, &left, &right) {
+                    Some(literal) => Expr::Literal(literal),
+                    None => Expr::Infix(infix, Box::new(left), Box::new(right)),
+                }
+            }
+            Expr::Index(left, index) => {
+                Expr::Index(self.optimize_boxed(*left), self.optimize_boxed(*index))
+            }
+            Expr::OptionalIndex(left, index) => {
+                Expr::OptionalIndex(self.optimize_boxed(*left), self.optimize_boxed(*index))
+            }
+            Expr::Dot(left, field) => Expr::Dot(self.optimize_boxed(*left), field),
+            Expr::OptionalDot(left, field) => Expr::OptionalDot(self.optimize_boxed(*left), field),
+            Expr::If {
+                cond,
+                consequence,
+                alternative,
+            } => {
+                let cond = self.optimize_expr(*cond);
+                let consequence = self.scoped(|this| this.optimize_block(consequence));
+                let alternative =
+                    alternative.map(|alt| self.scoped(|this| this.optimize_block(alt)));
+                self.forget_block(&consequence);
+                if let Some(ref alt) = alternative {
+                    self.forget_block(alt);
+                }
+                Self::fold_if(cond, consequence, alternative)
+            }
+            Expr::Func { params, body } => {
+                // Functions may run after the names they see are declared again
+                let body = self.scoped(|this| {
+                    this.constants.clear();
+                    this.optimize_block(Rc::unwrap_or_clone(body))
+                });
+                Expr::Func {
+                    params,
+                    body: Rc::new(body),
+                }
+            }
+            Expr::Call { func, args, span } => {
+                // Arguments run before the function, whose name shows in stack traces
+                let args = self.optimize_exprs(args);
+                let func = match *func {
+                    Expr::Ident(_) => func,
+                    func => self.optimize_boxed(func),
+                };
+                Expr::Call { func, args, span }
+            }
+            Expr::Propagate(expr) => Expr::Propagate(self.optimize_boxed(*expr)),
+            Expr::Try {
+                body,
+                catch,
+                finally,
+            } => {
+                // The body may stop anywhere, so what it declares is unknown after it
+                let body = self.scoped(|this| this.optimize_block(body));
+                self.forget_block(&body);
+                let catch = catch.map(|(name, handler)| {
+                    let handler = self.scoped(|this| {
+                        this.constants.remove(&name.0);
+                        this.forget_block(&handler);
+                        this.optimize_block(handler)
+                    });
+                    (name, handler)
+                });
+                let finally =
+                    finally.map(|finally| self.scoped(|this| this.optimize_block(finally)));
+                if let Some(ref finally) = finally {
+                    self.forget_block(finally);
+                }
+                Expr::Try {
+                    body,
+                    catch,
+                    finally,
+                }
+            }
+            expr => expr,
+        }
+    }
+
+    fn optimize_boxed(&mut self, expr: Expr) -> Box<Expr> {
+        Box::new(self.optimize_expr(expr))
+    }
+
+    fn optimize_exprs(&mut self, exprs: Vec<Expr>) -> Vec<Expr> {
+        exprs
+            .into_iter()
+            .map(|expr| self.optimize_expr(expr))
+            .collect()
+    }
+
+    /// Drops the branch of an `if` with a literal condition that can not run.
+    ///
+    /// The `if` becomes the expression of the remaining branch when that is
+    /// all the branch holds, otherwise an `if` on `true` or `false` without an
+    /// `else`, so a literal condition always has its branch in `consequence`.
+    fn fold_if(cond: Expr, consequence: BlockStmt, alternative: Option<BlockStmt>) -> Expr {
+        let runs = Self::literal_cond(&cond);
+        let dropped = match runs {
+            Some(true) => alternative.as_deref(),
+            _ => Some(consequence.as_slice()),
+        };
+        if runs.is_none() || dropped.is_some_and(Self::declares) {
+            return Expr::If {
+                cond: Box::new(cond),
+                consequence,
+                alternative,
+            };
+        }
+
+        let taken = match runs {
+            Some(true) => Some(consequence),
+            _ => alternative,
+        };
+        match taken {
+            Some(mut block) if matches!(block.as_slice(), [Stmt::Expr(_)]) => match block.pop() {
+                Some(Stmt::Expr(expr)) => expr,
+                _ => unreachable!("block holds one expression"),
+            },
+            Some(block) => Expr::If {
+                cond: Box::new(Expr::Literal(Literal::Bool(true))),
+                consequence: block,
+                alternative: None,
+            },
+            None => Expr::If {
+                cond: Box::new(Expr::Literal(Literal::Bool(false))),
+                consequence: vec![],
+                alternative: None,
+            },
+        }
+    }
+
+    fn fold_prefix(prefix: Prefix, right: &Expr) -> Option<Literal> {
+        let Expr::Literal(right) = right else {
+            return None;
+        };
+        if let (Prefix::Minus, &Literal::Int(right)) = (prefix, right) {
+            right.checked_neg()?;
+        }
+
+        let value = Evaluator::eval_prefix_expr(prefix, Self::literal_value(right)?);
+        Self::value_literal(value)
+    }
+
+    fn fold_infix(infix: Infix, left: &Expr, right: &Expr) -> Option<Literal> {
+        let (Expr::Literal(left), Expr::Literal(right)) = (left, right) else {
+            return None;
+        };
+        if let (&Literal::Int(left), &Literal::Int(right)) = (left, right) {
+            match infix {
+                Infix::Plus => left.checked_add(right)?,
+                Infix::Minus => left.checked_sub(right)?,
+                Infix::Multiply => left.checked_mul(right)?,
+                Infix::Divide => left.checked_div(right)?,
+                _ => 0,
+            };
+        }
+
+        let value = Evaluator::eval_infix_expr(
+            infix,
+            Self::literal_value(left)?,
+            Self::literal_value(right)?,
+        );
+        Self::value_literal(value)
+    }
+
+    /// Whether a condition is a literal that is always or never truthy.
+    fn literal_cond(cond: &Expr) -> Option<bool> {
+        match *cond {
+            Expr::Literal(ref literal) => Self::literal_value(literal).map(Evaluator::is_truthy),
+            _ => None,
+        }
+    }
+
+    /// The value of a literal that evaluates to the same value every time.
+    fn literal_value(literal: &Literal) -> Option<Object> {
+        match *literal {
+            Literal::Int(i) => Some(Object::Int(i)),
+            Literal::String(ref s) => Some(Object::String(s.clone())),
+            Literal::Bool(b) => Some(Object::Bool(b)),
+            Literal::Null => Some(Object::Null),
+            Literal::Array(_) | Literal::Hash(_) => None,
+        }
+    }
+
+    fn value_literal(value: Object) -> Option<Literal> {
+        match value {
+            Object::Int(i) => Some(Literal::Int(i)),
+            Object::String(s) => Some(Literal::String(s)),
+            Object::Bool(b) => Some(Literal::Bool(b)),
+            Object::Null => Some(Literal::Null),
+            _ => None,
+        }
+    }
+
+    fn declares(stmts: &[Stmt]) -> bool {
+        let mut names = vec![];
+        Resolver::hoist_block(stmts, &mut names);
+        !names.is_empty()
+    }
+
+    fn declares_in(expr: &Expr) -> bool {
+        let mut names = vec![];
+        Resolver::hoist_expr(expr, &mut names);
+        !names.is_empty()
+    }
+
+    fn is_empty(stmts: &[Stmt]) -> bool {
+        stmts.iter().all(|stmt| *stmt == Stmt::Blank)
+    }
+
+    /// Removes the statements after a `return` or `throw`, unless they declare names.
+    fn drop_unreachable(mut block: BlockStmt) -> BlockStmt {
+        let end = block
+            .iter()
+            .position(|stmt| matches!(stmt, Stmt::Return(_) | Stmt::Throw(_)));
+        if let Some(end) = end {
+            if !Self::declares(&block[end + 1..]) {
+                block.truncate(end + 1);
+            }
+        }
+        block
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ast::*;
+    use crate::evaluator::builtins::new_builtins;
+    use crate::evaluator::env::Env;
+    use crate::evaluator::object::Object;
+    use crate::evaluator::Evaluator;
+    use crate::lexer::Lexer;
+    use crate::optimizer::Optimizer;
+    use crate::parser::Parser;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    fn parse(input: &str) -> Program {
+        Parser::new(Lexer::new(input)).parse()
+    }
+
+    fn optimize(input: &str) -> Program {
+        Optimizer::new().optimize(parse(input))
+    }
+
+    fn eval(program: Program) -> Option<Object> {
+        Evaluator::new(Rc::new(RefCell::new(Env::from(new_builtins())))).eval(program)
+    }
+
+    fn int(i: i64) -> Expr {
+        Expr::Literal(Literal::Int(i))
+    }
+
+    fn ident(name: &str) -> Expr {
+        Expr::Ident(Ident(String::from(name)))
+    }
+
+    #[test]
+    fn test_fold_constants() {
+        let tests = vec![
+            ("1 + 2 * 3", Stmt::Expr(int(7))),
+            (
+                "-(4 / 2) < 0",
+                Stmt::Expr(Expr::Literal(Literal::Bool(true))),
+            ),
+            ("!0", Stmt::Expr(Expr::Literal(Literal::Bool(false)))),
+            (
+                "\"foo\" + \"bar\"",
+                Stmt::Expr(Expr::Literal(Literal::String(String::from("foobar")))),
+            ),
+            ("null ?? 1 + 1", Stmt::Expr(int(2))),
+            ("1 ?? x", Stmt::Expr(int(1))),
+            (
+                "x + (1 + 1)",
+                Stmt::Expr(Expr::Infix(
+                    Infix::Plus,
+                    Box::new(ident("x")),
+                    Box::new(int(2)),
+                )),
+            ),
+            (
+                "1 + true",
+                Stmt::Expr(Expr::Infix(
+                    Infix::Plus,
+                    Box::new(int(1)),
+                    Box::new(Expr::Literal(Literal::Bool(true))),
+                )),
+            ),
+            (
+                "1 / 0",
+                Stmt::Expr(Expr::Infix(
+                    Infix::Divide,
+                    Box::new(int(1)),
+                    Box::new(int(0)),
+                )),
+            ),
+            (
+                "9223372036854775807 + 1",
+                Stmt::Expr(Expr::Infix(
+                    Infix::Plus,
+                    Box::new(int(i64::MAX)),
+                    Box::new(int(1)),
+                )),
+            ),
+        ];
+
+        for (input, expected) in tests {
+            assert_eq!(vec![expected], optimize(input), "optimizing {}", input);
+        }
+    }
+
+    #[test]
+    fn test_dead_branches() {
+        let tests = vec![
+            ("if (true) { x } else { y }", vec![Stmt::Expr(ident("x"))]),
+            (
+                "if (0) { x }; y",
+                vec![Stmt::Expr(ident("x")), Stmt::Expr(ident("y"))],
+            ),
+            ("if (null) { x }; y", vec![Stmt::Expr(ident("y"))]),
+            (
+                "let a = if (false) { x } else { y };",
+                vec![Stmt::Let(Ident(String::from("a")), ident("y"))],
+            ),
+            (
+                "fun() { return 1; 2; 3 }",
+                vec![Stmt::Expr(Expr::Func {
+                    params: Rc::new(vec![]),
+                    body: Rc::new(vec![Stmt::Return(int(1))]),
+                })],
+            ),
+        ];
+
+        for (input, expected) in tests {
+            assert_eq!(expected, optimize(input), "optimizing {}", input);
+        }
+
+        // A declaration in a dead branch still binds its name
+        assert_eq!(2, optimize("if (false) { let x = 1; }; y").len());
+    }
+
+    #[test]
+    fn test_inline_constants() {
+        let tests = vec![
+            (
+                "let x = 2; let y = x * 3; y",
+                vec![
+                    Stmt::Let(Ident(String::from("x")), int(2)),
+                    Stmt::Let(Ident(String::from("y")), int(6)),
+                    Stmt::Expr(int(6)),
+                ],
+            ),
+            // Functions may run after `x` is declared again
+            (
+                "let x = 1; fun() { x }",
+                vec![
+                    Stmt::Let(Ident(String::from("x")), int(1)),
+                    Stmt::Expr(Expr::Func {
+                        params: Rc::new(vec![]),
+                        body: Rc::new(vec![Stmt::Expr(ident("x"))]),
+                    }),
+                ],
+            ),
+        ];
+
+        for (input, expected) in tests {
+            assert_eq!(expected, optimize(input), "optimizing {}", input);
+        }
+
+        // The `catch` block may run after part of the `try` body did
+        match optimize("let x = 1; try { let x = f(); g() } catch (e) { x }").pop() {
+            Some(Stmt::Expr(Expr::Try {
+                catch: Some((_, handler)),
+                ..
+            })) => assert_eq!(vec![Stmt::Expr(ident("x"))], handler),
+            o => panic!("expected a try expression, got {:?}", o),
+        }
+    }
+
+    #[test]
+    fn test_same_result_as_unoptimized() {
+        let tests = vec![
+            "1 + 2 * 3 - 4 / 2",
+            "\"a\" + \"b\" == \"ab\"",
+            "let x = 1; let f = fun() { x }; let x = 2; f()",
+            "let x = 5; let y = x + 1; if (y > 5) { \"big\" } else { \"small\" }",
+            "1; if (true) { }",
+            "1; if (false) { 2 }",
+            "if (true) { let a = 1; }",
+            "if (false) { let foo = 1; }; try { foo } catch (e) { e.message }",
+            "let f = fun() { return 1; let late = 2; }; f()",
+            "let f = fun(n) { if (n > 0) { return n; 3 } else { throw \"neg\"; 4 } }; [f(1), try { f(-1) } catch (e) { e.message }]",
+            "let x = 1; try { let x = 2; throw \"e\"; } catch (e) { x }",
+            "let x = 1; try { throw \"e\" } catch (e) { let r = x; let x = 3; r }",
+            "let x = 1; try { throw \"e\" } catch (x) { x.message }",
+            "null ?? 1 + 1",
+            "let f = fun() { 3 ?? f() }; f()",
+            "let x = \"s\"; x(1)",
+            "let f = fun() { 1 + true }; let g = fun() { f() + 1 }; g()",
+            "const limit = 10; let down = fun(n) { if (n == 0) { limit } else { down(n - 1) } }; down(limit)",
+            "struct P { x }; let one = 1; P(one).x + one",
+            "let count = fun(n) { if (n == 0) { 0 } else { count(n - 1 + 1 - 1) } }; count(1000)",
+            "let fib = fun(n) { if (n < 2) { n } else { fib(n - 1) + fib(n - 2) } }; fib(15)",
+            "let x = 1; let y = if (x == 1) { let x = 2; x } else { 0 }; [x, y]",
+            "let x = 1; let y = [if (false) { let x = 2; }, x]; y",
+        ];
+
+        for input in tests {
+            assert_eq!(
+                eval(parse(input)),
+                eval(optimize(input)),
+                "optimizing changed the result of {}",
+                input
+            );
+        }
+    }
+}
@@ -5,17 +5,21 @@
 use crate::ast::*;
 use crate::lexer::Lexer;
 use crate::token::Token;
+use std::collections::HashSet;
 use std::fmt;
+use std::rc::Rc;
 
 #[derive(Debug, Clone)]
 pub enum ParseErrorKind {
     UnexpectedToken,
+    InvalidDeclaration,
 }
 
 impl fmt::Display for ParseErrorKind {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match *self {
             ParseErrorKind::UnexpectedToken => write!(f, "Unexpected Token"),
+            ParseErrorKind::InvalidDeclaration => write!(f, "Invalid Declaration"),
         }
     }
 }
@@ -44,7 +48,11 @@ pub struct Parser<'a> {
     lexer: Lexer<'a>,
     current_token: Token,
     next_token: Token,
+    current_span: Span,
+    next_span: Span,
     errors: ParseErrors,
+    // Constants declared in each enclosing function scope, innermost last
+    const_scopes: Vec<HashSet<String>>,
 }
 
 impl<'a> Parser<'a> {
@@ -53,7 +61,10 @@ impl<'a> Parser<'a> {
             lexer,
             current_token: Token::Eof,
             next_token: Token::Eof,
+            current_span: Span::default(),
+            next_span: Span::default(),
             errors: vec![],
+            const_scopes: vec![HashSet::new()],
         };
 
         parser.bump();
@@ -64,13 +75,15 @@ impl<'a> Parser<'a> {
 
     fn token_to_precedence(tok: &Token) -> Precedence {
         match tok {
+            Token::NullCoalesce => Precedence::Coalesce,
             Token::Equal | Token::NotEqual => Precedence::Equals,
             Token::LessThan | Token::LessThanEqual => Precedence::LessGreater,
             Token::GreaterThan | Token::GreaterThanEqual => Precedence::LessGreater,
             Token::Plus | Token::Minus => Precedence::Sum,
             Token::Slash | Token::Asterisk => Precedence::Product,
-            Token::Lbracket => Precedence::Index,
-            Token::Lparen => Precedence::Call,
+            Token::Lbracket | Token::Dot => Precedence::Index,
+            Token::QuestionLbracket | Token::QuestionDot => Precedence::Index,
+            Token::Lparen | Token::Question => Precedence::Call,
             _ => Precedence::Lowest,
         }
     }
@@ -81,7 +94,9 @@ impl<'a> Parser<'a> {
 
     fn bump(&mut self) {
         self.current_token = self.next_token.clone();
+        self.current_span = self.next_span;
         self.next_token = self.lexer.next_token();
+        self.next_span = self.lexer.span();
     }
 
     fn current_token_is(&mut self, tok: Token) -> bool {
@@ -162,7 +177,11 @@ impl<'a> Parser<'a> {
     fn parse_stmt(&mut self) -> Option<Stmt> {
         match self.current_token {
             Token::Let => self.parse_let_stmt(),
+            Token::Const => self.parse_const_stmt(),
             Token::Return => self.parse_return_stmt(),
+            Token::Throw => self.parse_throw_stmt(),
+            Token::Struct => self.parse_struct_stmt(),
+            Token::Enum => self.parse_enum_stmt(),
             Token::Blank => Some(Stmt::Blank),
             _ => self.parse_expr_stmt(),
         }
@@ -173,6 +192,26 @@ impl<'a> Parser<'a> {
     /// A `let` statement assigns a value to an identifier.
     /// This function ensures proper syntax and returns a `Stmt::Let` variant if parsing is successful.
     fn parse_let_stmt(&mut self) -> Option<Stmt> {
+        let (name, expr) = self.parse_binding()?;
+
+        Some(Stmt::Let(name, expr))
+    }
+
+    /// Parses a `const` statement.
+    ///
+    /// Works like `let`, but the name can not be declared again in the same scope.
+    fn parse_const_stmt(&mut self) -> Option<Stmt> {
+        let (name, expr) = self.parse_binding()?;
+
+        if let Some(scope) = self.const_scopes.last_mut() {
+            scope.insert(name.0.clone());
+        }
+
+        Some(Stmt::Const(name, expr))
+    }
+
+    /// Parses the `name = expr` part shared by `let` and `const`.
+    fn parse_binding(&mut self) -> Option<(Ident, Expr)> {
         match &self.next_token {
             Token::Ident(_) => self.bump(),
             _ => return None,
@@ -180,6 +219,19 @@ impl<'a> Parser<'a> {
 
         let name = self.parse_ident()?;
 
+        // The rest of the statement is still parsed, so that it is not
+        // reported as a statement of its own
+        let redeclared = self
+            .const_scopes
+            .last()
+            .is_some_and(|scope| scope.contains(&name.0));
+        if redeclared {
+            self.errors.push(ParseError::new(
+                ParseErrorKind::InvalidDeclaration,
+                format!("cannot redeclare constant: {}", name.0),
+            ));
+        }
+
         if !self.expect_next_token(Token::Assign) {
             return None;
         }
@@ -192,7 +244,7 @@ impl<'a> Parser<'a> {
             self.bump();
         }
 
-        Some(Stmt::Let(name, expr))
+        (!redeclared).then_some((name, expr))
     }
 
     /// Parses a `return` statement.
@@ -211,6 +263,144 @@ impl<'a> Parser<'a> {
         Some(Stmt::Return(expr))
     }
 
+    /// Parses a `struct` declaration.
+    ///
+    /// Example: `struct Point { x, y }`. Field names must be unique.
+    fn parse_struct_stmt(&mut self) -> Option<Stmt> {
+        match &self.next_token {
+            Token::Ident(_) => self.bump(),
+            _ => {
+                self.error_next_token(Token::Ident(String::new()));
+                return None;
+            }
+        };
+
+        let name = self.parse_ident()?;
+
+        if !self.expect_next_token(Token::Lbrace) {
+            return None;
+        }
+
+        let mut fields: Vec<Ident> = vec![];
+
+        while !self.next_token_is(&Token::Rbrace) {
+            match &self.next_token {
+                Token::Ident(_) => self.bump(),
+                _ => {
+                    self.error_next_token(Token::Ident(String::new()));
+                    return None;
+                }
+            };
+
+            let field = self.parse_ident()?;
+            if fields.contains(&field) {
+                let Ident(ref field_name) = field;
+                self.errors.push(ParseError::new(
+                    ParseErrorKind::InvalidDeclaration,
+                    format!("duplicate field `{}` in struct {}", field_name, name.0),
+                ));
+                return None;
+            }
+            fields.push(field);
+
+            if !self.next_token_is(&Token::Rbrace) && !self.expect_next_token(Token::Comma) {
+                return None;
+            }
+        }
+
+        self.bump();
+
+        if self.next_token_is(&Token::Semicolon) {
+            self.bump();
+        }
+
+        Some(Stmt::Struct(name, fields))
+    }
+
+    /// Parses an `enum` declaration.
+    ///
+    /// Example: `enum Shape { Circle(r), Rect(w, h), Empty }`. Variants without
+    /// parentheses carry no fields.
+    fn parse_enum_stmt(&mut self) -> Option<Stmt> {
+        match &self.next_token {
+            Token::Ident(_) => self.bump(),
+            _ => {
+                self.error_next_token(Token::Ident(String::new()));
+                return None;
+            }
+        };
+
+        let name = self.parse_ident()?;
+
+        if !self.expect_next_token(Token::Lbrace) {
+            return None;
+        }
+
+        let mut variants: Vec<(Ident, Vec<Ident>)> = vec![];
+
+        while !self.next_token_is(&Token::Rbrace) {
+            match &self.next_token {
+                Token::Ident(_) => self.bump(),
+                _ => {
+                    self.error_next_token(Token::Ident(String::new()));
+                    return None;
+                }
+            };
+
+            let tag = self.parse_ident()?;
+            if variants.iter().any(|(t, _)| *t == tag) {
+                self.errors.push(ParseError::new(
+                    ParseErrorKind::InvalidDeclaration,
+                    format!("duplicate variant `{}` in enum {}", tag.0, name.0),
+                ));
+                return None;
+            }
+
+            let mut fields = vec![];
+            if self.next_token_is(&Token::Lparen) {
+                self.bump();
+                fields = self.parse_func_params()?;
+                for (i, field) in fields.iter().enumerate() {
+                    if fields[..i].contains(field) {
+                        self.errors.push(ParseError::new(
+                            ParseErrorKind::InvalidDeclaration,
+                            format!("duplicate field `{}` in variant {}", field.0, tag.0),
+                        ));
+                        return None;
+                    }
+                }
+            }
+            variants.push((tag, fields));
+
+            if !self.next_token_is(&Token::Rbrace) && !self.expect_next_token(Token::Comma) {
+                return None;
+            }
+        }
+
+        self.bump();
+
+        if self.next_token_is(&Token::Semicolon) {
+            self.bump();
+        }
+
+        Some(Stmt::Enum(name, variants))
+    }
+
+    /// Parses a `throw` statement.
+    ///
+    /// A `throw` statement raises an error that unwinds to the nearest `try`.
+    fn parse_throw_stmt(&mut self) -> Option<Stmt> {
+        self.bump();
+
+        let expr = self.parse_expr(Precedence::Lowest)?;
+
+        if self.next_token_is(&Token::Semicolon) {
+            self.bump();
+        }
+
+        Some(Stmt::Throw(expr))
+    }
+
     /// Parses an expression statement.
     ///
     /// Expression statements evaluate expressions, which may produce side effects.
@@ -238,11 +428,13 @@ impl<'a> Parser<'a> {
             Token::Int(_) => self.parse_int_expr(),
             Token::String(_) => self.parse_string_expr(),
             Token::Bool(_) => self.parse_bool_expr(),
+            Token::Null => Some(Expr::Literal(Literal::Null)),
             Token::Lbracket => self.parse_array_expr(),
             Token::Lbrace => self.parse_hash_expr(),
             Token::Bang | Token::Minus | Token::Plus => self.parse_prefix_expr(),
             Token::Lparen => self.parse_grouped_expr(),
             Token::If => self.parse_if_expr(),
+            Token::Try => self.parse_try_expr(),
             Token::Func => self.parse_func_expr(),
             _ => {
                 self.error_no_prefix_parser();
@@ -261,7 +453,8 @@ impl<'a> Parser<'a> {
                 | Token::LessThan
                 | Token::LessThanEqual
                 | Token::GreaterThan
-                | Token::GreaterThanEqual => {
+                | Token::GreaterThanEqual
+                | Token::NullCoalesce => {
                     self.bump();
                     left = self.parse_infix_expr(left.unwrap());
                 }
@@ -269,10 +462,26 @@ impl<'a> Parser<'a> {
                     self.bump();
                     left = self.parse_index_expr(left.unwrap());
                 }
+                Token::Dot => {
+                    self.bump();
+                    left = self.parse_dot_expr(left.unwrap());
+                }
+                Token::QuestionLbracket => {
+                    self.bump();
+                    left = self.parse_optional_index_expr(left.unwrap());
+                }
+                Token::QuestionDot => {
+                    self.bump();
+                    left = self.parse_optional_dot_expr(left.unwrap());
+                }
                 Token::Lparen => {
                     self.bump();
                     left = self.parse_call_expr(left.unwrap());
                 }
+                Token::Question => {
+                    self.bump();
+                    left = left.map(|expr| Expr::Propagate(Box::new(expr)));
+                }
                 _ => return left,
             }
         }
@@ -435,6 +644,7 @@ impl<'a> Parser<'a> {
             Token::LessThanEqual => Infix::LessThanEqual,
             Token::GreaterThan => Infix::GreaterThan,
             Token::GreaterThanEqual => Infix::GreaterThanEqual,
+            Token::NullCoalesce => Infix::NullCoalesce,
             _ => return None,
         };
 
@@ -450,6 +660,22 @@ impl<'a> Parser<'a> {
     ///
     /// Example: `array[0]`.
     fn parse_index_expr(&mut self, left: Expr) -> Option<Expr> {
+        let index = self.parse_index()?;
+
+        Some(Expr::Index(Box::new(left), Box::new(index)))
+    }
+
+    /// Parses a null-safe index expression.
+    ///
+    /// Example: `array?[0]`, which is `null` when `array` is `null`.
+    fn parse_optional_index_expr(&mut self, left: Expr) -> Option<Expr> {
+        let index = self.parse_index()?;
+
+        Some(Expr::OptionalIndex(Box::new(left), Box::new(index)))
+    }
+
+    /// Parses the expression between an opening bracket and `]`.
+    fn parse_index(&mut self) -> Option<Expr> {
         self.bump();
 
         let index = self.parse_expr(Precedence::Lowest)?;
@@ -458,7 +684,38 @@ impl<'a> Parser<'a> {
             return None;
         }
 
-        Some(Expr::Index(Box::new(left), Box::new(index)))
+        Some(index)
+    }
+
+    /// Parses a dot expression (field access or method call receiver).
+    ///
+    /// Example: `person.name` or the `xs.map` part of `xs.map(f)`.
+    fn parse_dot_expr(&mut self, left: Expr) -> Option<Expr> {
+        let field = self.parse_field()?;
+
+        Some(Expr::Dot(Box::new(left), field))
+    }
+
+    /// Parses a null-safe dot expression.
+    ///
+    /// Example: `person?.name`, which is `null` when `person` is `null`.
+    fn parse_optional_dot_expr(&mut self, left: Expr) -> Option<Expr> {
+        let field = self.parse_field()?;
+
+        Some(Expr::OptionalDot(Box::new(left), field))
+    }
+
+    /// Parses the field name following a dot.
+    fn parse_field(&mut self) -> Option<Ident> {
+        match &self.next_token {
+            Token::Ident(_) => self.bump(),
+            _ => {
+                self.error_next_token(Token::Ident(String::new()));
+                return None;
+            }
+        };
+
+        self.parse_ident()
     }
 
     /// Parses a grouped expression enclosed in parentheses.
@@ -512,6 +769,69 @@ impl<'a> Parser<'a> {
         })
     }
 
+    /// Parses a `try` expression.
+    ///
+    /// Example: `try { ... } catch (e) { ... } finally { ... }`. At least one of
+    /// `catch` and `finally` is required.
+    fn parse_try_expr(&mut self) -> Option<Expr> {
+        if !self.expect_next_token(Token::Lbrace) {
+            return None;
+        }
+
+        let body = self.parse_block_stmt();
+        let mut catch = None;
+        let mut finally = None;
+
+        if self.next_token_is(&Token::Catch) {
+            self.bump();
+
+            if !self.expect_next_token(Token::Lparen) {
+                return None;
+            }
+
+            match &self.next_token {
+                Token::Ident(_) => self.bump(),
+                _ => {
+                    self.error_next_token(Token::Ident(String::new()));
+                    return None;
+                }
+            };
+
+            let name = self.parse_ident()?;
+
+            if !self.expect_next_token(Token::Rparen) || !self.expect_next_token(Token::Lbrace) {
+                return None;
+            }
+
+            // Like a function body, the `catch` block is a scope of its own
+            self.const_scopes.push(HashSet::new());
+            let handler = self.parse_block_stmt();
+            self.const_scopes.pop();
+            catch = Some((name, handler));
+        }
+
+        if self.next_token_is(&Token::Finally) {
+            self.bump();
+
+            if !self.expect_next_token(Token::Lbrace) {
+                return None;
+            }
+
+            finally = Some(self.parse_block_stmt());
+        }
+
+        if catch.is_none() && finally.is_none() {
+            self.error_next_token(Token::Catch);
+            return None;
+        }
+
+        Some(Expr::Try {
+            body,
+            catch,
+            finally,
+        })
+    }
+
     /// Parses a function literal expression.
     ///
     /// Parses function parameters and body enclosed in braces.
@@ -526,9 +846,13 @@ impl<'a> Parser<'a> {
             return None;
         }
 
+        self.const_scopes.push(HashSet::new());
+        let body = self.parse_block_stmt();
+        self.const_scopes.pop();
+
         Some(Expr::Func {
-            params,
-            body: self.parse_block_stmt(),
+            params: Rc::new(params),
+            body: Rc::new(body),
         })
     }
 
@@ -569,13 +893,15 @@ impl<'a> Parser<'a> {
 
     /// Parses a function call expression.
     ///
-    /// Parses arguments and constructs an `Expr::Call`.
+    /// Parses arguments and constructs an `Expr::Call` whose span points at the `(`.
     fn parse_call_expr(&mut self, func: Expr) -> Option<Expr> {
+        let span = self.current_span;
         let args = self.parse_expr_list(Token::Rparen)?;
 
         Some(Expr::Call {
             func: Box::new(func),
             args,
+            span,
         })
     }
 }
@@ -585,11 +911,12 @@ mod tests {
     use crate::ast::*;
     use crate::lexer::Lexer;
     use crate::parser::Parser;
+    use std::rc::Rc;
 
     fn check_parse_errors(parser: &mut Parser) {
         let errors = parser.get_errors();
 
-        if errors.len() == 0 {
+        if errors.is_empty() {
             return;
         }
 
@@ -675,6 +1002,60 @@ let foobar = 838383;
         );
     }
 
+    #[test]
+    fn test_const_stmt() {
+        let input = r#"
+const x = 5;
+let f = fun() { let x = 1; x };
+        "#;
+
+        let mut parser = Parser::new(Lexer::new(input));
+        let program = parser.parse();
+
+        check_parse_errors(&mut parser);
+        assert_eq!(
+            Stmt::Const(Ident(String::from("x")), Expr::Literal(Literal::Int(5))),
+            program[0],
+        );
+    }
+
+    #[test]
+    fn test_const_stmt_redeclared() {
+        let tests = vec![
+            "const x = 5; let x = 6;",
+            "const x = 5; const x = 6;",
+            "const x = 5; let x = 6 + 7; x",
+            "try { 1 } catch (e) { const x = 1; let x = 2; }",
+        ];
+
+        for input in tests {
+            let mut parser = Parser::new(Lexer::new(input));
+            parser.parse();
+
+            let errors: Vec<String> = parser.get_errors().iter().map(|e| e.to_string()).collect();
+            assert_eq!(
+                vec!["Invalid Declaration: cannot redeclare constant: x"],
+                errors,
+                "{}",
+                input
+            );
+        }
+    }
+
+    #[test]
+    fn test_const_in_catch_scope() {
+        let tests = vec![
+            "const x = 5; try { 1 } catch (e) { const x = 6; x }",
+            "try { 1 } catch (e) { const x = 6; x }; const x = 5;",
+        ];
+
+        for input in tests {
+            let mut parser = Parser::new(Lexer::new(input));
+            parser.parse();
+            check_parse_errors(&mut parser);
+        }
+    }
+
     #[test]
     fn test_return_stmt() {
         let input = r#"
@@ -697,6 +1078,80 @@ return 993322;
         );
     }
 
+    #[test]
+    fn test_struct_stmt() {
+        let input = r#"
+struct Point { x, y };
+struct Empty {}
+        "#;
+
+        let mut parser = Parser::new(Lexer::new(input));
+        let program = parser.parse();
+
+        check_parse_errors(&mut parser);
+        assert_eq!(
+            vec![
+                Stmt::Struct(
+                    Ident(String::from("Point")),
+                    vec![Ident(String::from("x")), Ident(String::from("y"))],
+                ),
+                Stmt::Struct(Ident(String::from("Empty")), vec![]),
+            ],
+            program,
+        );
+    }
+
+    #[test]
+    fn test_struct_stmt_duplicate_field() {
+        let mut parser = Parser::new(Lexer::new("struct Point { x, x }"));
+        parser.parse();
+
+        let errors = parser.get_errors();
+        assert_eq!(
+            "Invalid Declaration: duplicate field `x` in struct Point",
+            format!("{}", errors[0])
+        );
+    }
+
+    #[test]
+    fn test_enum_stmt() {
+        let input = "enum Shape { Circle(r), Rect(w, h), Empty }";
+
+        let mut parser = Parser::new(Lexer::new(input));
+        let program = parser.parse();
+
+        check_parse_errors(&mut parser);
+        assert_eq!(
+            vec![Stmt::Enum(
+                Ident(String::from("Shape")),
+                vec![
+                    (
+                        Ident(String::from("Circle")),
+                        vec![Ident(String::from("r"))],
+                    ),
+                    (
+                        Ident(String::from("Rect")),
+                        vec![Ident(String::from("w")), Ident(String::from("h"))],
+                    ),
+                    (Ident(String::from("Empty")), vec![]),
+                ],
+            )],
+            program,
+        );
+    }
+
+    #[test]
+    fn test_enum_stmt_duplicate_variant() {
+        let mut parser = Parser::new(Lexer::new("enum Shape { Circle(r), Circle(d) }"));
+        parser.parse();
+
+        let errors = parser.get_errors();
+        assert_eq!(
+            "Invalid Declaration: duplicate variant `Circle` in enum Shape",
+            format!("{}", errors[0])
+        );
+    }
+
     #[test]
     fn test_ident_expr() {
         let input = "foobar;";
@@ -869,6 +1324,165 @@ return 993322;
         );
     }
 
+    #[test]
+    fn test_dot_expr() {
+        let tests = vec![
+            (
+                "person.name",
+                Stmt::Expr(Expr::Dot(
+                    Box::new(Expr::Ident(Ident(String::from("person")))),
+                    Ident(String::from("name")),
+                )),
+            ),
+            (
+                "\"abc\".uppercase()",
+                Stmt::Expr(Expr::Call {
+                    func: Box::new(Expr::Dot(
+                        Box::new(Expr::Literal(Literal::String(String::from("abc")))),
+                        Ident(String::from("uppercase")),
+                    )),
+                    args: vec![],
+                    span: Span::new(1, 16),
+                }),
+            ),
+            (
+                "xs.map(f).filter(g)",
+                Stmt::Expr(Expr::Call {
+                    func: Box::new(Expr::Dot(
+                        Box::new(Expr::Call {
+                            func: Box::new(Expr::Dot(
+                                Box::new(Expr::Ident(Ident(String::from("xs")))),
+                                Ident(String::from("map")),
+                            )),
+                            args: vec![Expr::Ident(Ident(String::from("f")))],
+                            span: Span::new(1, 7),
+                        }),
+                        Ident(String::from("filter")),
+                    )),
+                    args: vec![Expr::Ident(Ident(String::from("g")))],
+                    span: Span::new(1, 17),
+                }),
+            ),
+            (
+                "a.b[0] + 1",
+                Stmt::Expr(Expr::Infix(
+                    Infix::Plus,
+                    Box::new(Expr::Index(
+                        Box::new(Expr::Dot(
+                            Box::new(Expr::Ident(Ident(String::from("a")))),
+                            Ident(String::from("b")),
+                        )),
+                        Box::new(Expr::Literal(Literal::Int(0))),
+                    )),
+                    Box::new(Expr::Literal(Literal::Int(1))),
+                )),
+            ),
+        ];
+
+        for (input, expect) in tests {
+            let mut parser = Parser::new(Lexer::new(input));
+            let program = parser.parse();
+
+            check_parse_errors(&mut parser);
+            assert_eq!(vec![expect], program);
+        }
+    }
+
+    #[test]
+    fn test_null_safe_expr() {
+        let tests = vec![
+            ("null", Stmt::Expr(Expr::Literal(Literal::Null))),
+            (
+                "a?.b",
+                Stmt::Expr(Expr::OptionalDot(
+                    Box::new(Expr::Ident(Ident(String::from("a")))),
+                    Ident(String::from("b")),
+                )),
+            ),
+            (
+                "a?[1]",
+                Stmt::Expr(Expr::OptionalIndex(
+                    Box::new(Expr::Ident(Ident(String::from("a")))),
+                    Box::new(Expr::Literal(Literal::Int(1))),
+                )),
+            ),
+            (
+                "a ?? b == c",
+                Stmt::Expr(Expr::Infix(
+                    Infix::NullCoalesce,
+                    Box::new(Expr::Ident(Ident(String::from("a")))),
+                    Box::new(Expr::Infix(
+                        Infix::Equal,
+                        Box::new(Expr::Ident(Ident(String::from("b")))),
+                        Box::new(Expr::Ident(Ident(String::from("c")))),
+                    )),
+                )),
+            ),
+            (
+                "a?.b ?? 1",
+                Stmt::Expr(Expr::Infix(
+                    Infix::NullCoalesce,
+                    Box::new(Expr::OptionalDot(
+                        Box::new(Expr::Ident(Ident(String::from("a")))),
+                        Ident(String::from("b")),
+                    )),
+                    Box::new(Expr::Literal(Literal::Int(1))),
+                )),
+            ),
+        ];
+
+        for (input, expect) in tests {
+            let mut parser = Parser::new(Lexer::new(input));
+            let program = parser.parse();
+
+            check_parse_errors(&mut parser);
+            assert_eq!(vec![expect], program);
+        }
+    }
+
+    #[test]
+    fn test_propagate_expr() {
+        let tests = vec![
+            (
+                "readFile(path)?",
+                Stmt::Expr(Expr::Propagate(Box::new(Expr::Call {
+                    func: Box::new(Expr::Ident(Ident(String::from("readFile")))),
+                    args: vec![Expr::Ident(Ident(String::from("path")))],
+                    span: Span::new(1, 9),
+                }))),
+            ),
+            (
+                "a? + b?",
+                Stmt::Expr(Expr::Infix(
+                    Infix::Plus,
+                    Box::new(Expr::Propagate(Box::new(Expr::Ident(Ident(String::from(
+                        "a",
+                    )))))),
+                    Box::new(Expr::Propagate(Box::new(Expr::Ident(Ident(String::from(
+                        "b",
+                    )))))),
+                )),
+            ),
+            (
+                "-a?",
+                Stmt::Expr(Expr::Prefix(
+                    Prefix::Minus,
+                    Box::new(Expr::Propagate(Box::new(Expr::Ident(Ident(String::from(
+                        "a",
+                    )))))),
+                )),
+            ),
+        ];
+
+        for (input, expect) in tests {
+            let mut parser = Parser::new(Lexer::new(input));
+            let program = parser.parse();
+
+            check_parse_errors(&mut parser);
+            assert_eq!(vec![expect], program);
+        }
+    }
+
     #[test]
     fn test_prefix_expr() {
         let tests = vec![
@@ -1042,6 +1656,58 @@ return 993322;
         );
     }
 
+    #[test]
+    fn test_try_expr() {
+        let tests = vec![
+            (
+                "try { x } catch (e) { y }",
+                Expr::Try {
+                    body: vec![Stmt::Expr(Expr::Ident(Ident(String::from("x"))))],
+                    catch: Some((
+                        Ident(String::from("e")),
+                        vec![Stmt::Expr(Expr::Ident(Ident(String::from("y"))))],
+                    )),
+                    finally: None,
+                },
+            ),
+            (
+                "try { x } finally { z }",
+                Expr::Try {
+                    body: vec![Stmt::Expr(Expr::Ident(Ident(String::from("x"))))],
+                    catch: None,
+                    finally: Some(vec![Stmt::Expr(Expr::Ident(Ident(String::from("z"))))]),
+                },
+            ),
+            (
+                "try { throw x; } catch (e) { y } finally { z }",
+                Expr::Try {
+                    body: vec![Stmt::Throw(Expr::Ident(Ident(String::from("x"))))],
+                    catch: Some((
+                        Ident(String::from("e")),
+                        vec![Stmt::Expr(Expr::Ident(Ident(String::from("y"))))],
+                    )),
+                    finally: Some(vec![Stmt::Expr(Expr::Ident(Ident(String::from("z"))))]),
+                },
+            ),
+        ];
+
+        for (input, expect) in tests {
+            let mut parser = Parser::new(Lexer::new(input));
+            let program = parser.parse();
+
+            check_parse_errors(&mut parser);
+            assert_eq!(vec![Stmt::Expr(expect)], program);
+        }
+    }
+
+    #[test]
+    fn test_try_expr_without_handler() {
+        let mut parser = Parser::new(Lexer::new("try { x }"));
+        parser.parse();
+
+        assert!(!parser.get_errors().is_empty());
+    }
+
     #[test]
     fn test_func_expr() {
         let input = "fun(x, y) { x + y; }";
@@ -1052,12 +1718,12 @@ return 993322;
         check_parse_errors(&mut parser);
         assert_eq!(
             vec![Stmt::Expr(Expr::Func {
-                params: vec![Ident(String::from("x")), Ident(String::from("y"))],
-                body: vec![Stmt::Expr(Expr::Infix(
+                params: Rc::new(vec![Ident(String::from("x")), Ident(String::from("y"))]),
+                body: Rc::new(vec![Stmt::Expr(Expr::Infix(
                     Infix::Plus,
                     Box::new(Expr::Ident(Ident(String::from("x")))),
                     Box::new(Expr::Ident(Ident(String::from("y")))),
-                ))],
+                ))]),
             })],
             program,
         );
@@ -1085,8 +1751,8 @@ return 993322;
             check_parse_errors(&mut parser);
             assert_eq!(
                 vec![Stmt::Expr(Expr::Func {
-                    params: expect,
-                    body: vec![],
+                    params: Rc::new(expect),
+                    body: Rc::new(vec![]),
                 })],
                 program,
             );
@@ -1117,6 +1783,7 @@ return 993322;
                         Box::new(Expr::Literal(Literal::Int(5))),
                     ),
                 ],
+                span: Span::new(1, 4),
             })],
             program,
         );
@@ -1424,6 +2091,7 @@ return 993322;
                                 Box::new(Expr::Ident(Ident(String::from("b")))),
                                 Box::new(Expr::Ident(Ident(String::from("c")))),
                             )],
+                            span: Span::new(1, 8),
                         }),
                     )),
                     Box::new(Expr::Ident(Ident(String::from("d")))),
@@ -1457,8 +2125,10 @@ return 993322;
                                     Box::new(Expr::Literal(Literal::Int(8))),
                                 ),
                             ],
+                            span: Span::new(1, 31),
                         },
                     ],
+                    span: Span::new(1, 4),
                 }),
             ),
             (
@@ -1486,6 +2156,7 @@ return 993322;
                         )),
                         Box::new(Expr::Ident(Ident(String::from("g")))),
                     )],
+                    span: Span::new(1, 4),
                 }),
             ),
             (
@@ -1541,6 +2212,7 @@ return 993322;
                             )),
                         ),
                     ],
+                    span: Span::new(1, 4),
                 }),
             ),
         ];
@@ -1553,4 +2225,33 @@ return 993322;
             assert_eq!(vec![expect], program);
         }
     }
+
+    #[test]
+    fn test_display_round_trip() {
+        let tests = vec![
+            "let x = -a + b * !c;",
+            "const xs = [1, \"two\", true, null, {\"k\": [x]}];",
+            "let f = fun(a, b) { let c = a?[0] ?? b?.d; return c.e(1)[2]; };",
+            "if (x > 1) { 1 } else { if (x == 0) { throw Error(\"zero\") } };",
+            "try { f(x)? } catch (e) { e.kind } finally { print(1) };",
+            "fun(x) { x }(1);",
+            "struct Point { x, y }",
+            "enum Shape { Circle(r), Rect(w, h), Empty }",
+        ];
+
+        for input in tests {
+            let mut parser = Parser::new(Lexer::new(input));
+            let program = parser.parse();
+            check_parse_errors(&mut parser);
+            let source: Vec<String> = program.iter().map(|stmt| stmt.to_string()).collect();
+
+            // Call spans move, so the trees are compared through their source
+            let joined = source.join(" ");
+            let mut parser = Parser::new(Lexer::new(&joined));
+            let reparsed = parser.parse();
+            check_parse_errors(&mut parser);
+            let resource: Vec<String> = reparsed.iter().map(|stmt| stmt.to_string()).collect();
+            assert_eq!(source, resource, "{}", input);
+        }
+    }
 }
@@ -2,29 +2,68 @@
 // Use of this source code is governed by a MIT
 // license that can be found in the LICENSE file
 
-use crate::evaluator::builtins::new_builtins;
-use crate::evaluator::env::Env;
+use crate::evaluator::budget::InterruptHandle;
 use crate::evaluator::object::Object;
-use crate::evaluator::Evaluator;
+use crate::interpreter::{Error, Interpreter};
 use crate::lexer::Lexer;
 use crate::parser::Parser;
-use std::cell::RefCell;
 use std::collections::HashMap;
 use std::io::Write;
 use std::io::{stdin, BufRead};
-use std::rc::Rc;
+use std::sync::{Mutex, Once};
+
+pub use crate::interpreter::Config;
 
 const PROMPT: &str = ">> ";
-const STAND_PRELUDE: &str = "
-let fold = fun(f, init, lst) {
-  if (len(lst) == 0) {
-    init
-  } else {
-    let newInit = f(init, first(lst));
-    fold(f, newInit, tail(lst));
-  }
-};
-";
+
+/// What Ctrl-C does in the REPL session.
+static CTRL_C: Mutex<CtrlC> = Mutex::new(CtrlC::new());
+
+/// Tracks whether the REPL is evaluating a line or waiting at the prompt,
+/// as Ctrl-C stops the evaluation in the first case and quits in the second.
+struct CtrlC {
+    interrupt: Option<InterruptHandle>,
+    running: bool,
+    /// Ctrl-C presses at the prompt since the last line was read.
+    presses: u32,
+}
+
+/// What a Ctrl-C press should do.
+#[derive(Debug, PartialEq)]
+enum CtrlCAction {
+    Interrupt,
+    Notice,
+    Exit,
+}
+
+impl CtrlC {
+    const fn new() -> Self {
+        CtrlC {
+            interrupt: None,
+            running: false,
+            presses: 0,
+        }
+    }
+
+    /// Records that a line was read (`true`) or that its evaluation ended
+    /// and the prompt is shown again (`false`).
+    fn set_running(&mut self, running: bool) {
+        self.running = running;
+        self.presses = 0;
+    }
+
+    fn press(&mut self) -> CtrlCAction {
+        if self.running {
+            return CtrlCAction::Interrupt;
+        }
+        self.presses += 1;
+        if self.presses > 1 {
+            CtrlCAction::Exit
+        } else {
+            CtrlCAction::Notice
+        }
+    }
+}
 
 /// Starts a Read-Eval-Print Loop (REPL) for the Beavieeer language.
 ///
@@ -34,16 +73,22 @@ let fold = fun(f, init, lst) {
 /// - Prints the result to the provided output
 /// - Loops until the user inputs ":q" or EOF is reached
 ///
+/// Ctrl-C aborts the evaluation in progress and returns to the prompt,
+/// keeping the variables defined so far. At the prompt it discards the line
+/// being typed, and a second press in a row quits.
+///
 /// It also provides special commands:
 /// - `:q` - Quit the REPL
 /// - `:info` - List all available built-in functions
 /// - `:info <function>` - Show documentation for a specific built-in function
+/// - `:save <file>` - Save the variables and functions defined so far
+/// - `:load-session <file>` - Restore a session saved with `:save`
 /// - `:help` - Display help information for REPL commands
 ///
 /// # Arguments
 ///
 /// * `output` - A mutable reference to a type that implements the `Write` trait,
-///              used for displaying prompts and results
+///   used for displaying prompts and results
 ///
 /// # Examples
 ///
@@ -55,22 +100,16 @@ let fold = fun(f, init, lst) {
 /// ```
 #[inline]
 pub fn start_repl(output: &mut dyn Write) {
+    start_repl_with(output, &Config::default());
+}
+
+/// Starts a REPL session using the given [`Config`].
+pub fn start_repl_with(output: &mut dyn Write, config: &Config) {
     let mut line = String::new();
     let mut lang_input = String::new();
-    let mut env = Env::from(new_builtins());
     let buildin_doc = get_buildin_doc();
-    env.set(
-        String::from("print"),
-        &Object::Builtin(-1, |args| {
-            for arg in args {
-                println!("{}", arg);
-            }
-            Object::Null
-        }),
-    );
-
-    let mut evaluator = Evaluator::new(Rc::new(RefCell::new(env)));
-    load_prelude(&mut evaluator);
+    let mut interpreter = Interpreter::with_config(config);
+    catch_ctrl_c(&interpreter);
     writeln!(output, "Welcome to the Beavieeer REPL!").unwrap();
     writeln!(
         output,
@@ -79,6 +118,7 @@ pub fn start_repl(output: &mut dyn Write) {
     .unwrap();
 
     loop {
+        CTRL_C.lock().unwrap().set_running(false);
         write!(output, "{}", PROMPT).unwrap();
         output.flush().unwrap();
         line.clear();
@@ -88,6 +128,7 @@ pub fn start_repl(output: &mut dyn Write) {
         if bytes_read == 0 {
             return; // End of input
         }
+        CTRL_C.lock().unwrap().set_running(true);
 
         let trimmed_line = line.trim();
 
@@ -101,6 +142,23 @@ pub fn start_repl(output: &mut dyn Write) {
             continue;
         }
 
+        if let Some(path) = trimmed_line.strip_prefix(":save ") {
+            match interpreter.save_snapshot(path.trim()) {
+                Ok(()) => writeln!(output, "Saved session to {}", path.trim()).unwrap(),
+                Err(err) => writeln!(output, "{}", err).unwrap(),
+            }
+            continue;
+        }
+
+        if let Some(path) = trimmed_line.strip_prefix(":load-session ") {
+            match interpreter.load_snapshot(path.trim()) {
+                Ok(()) => writeln!(output, "Loaded session from {}", path.trim()).unwrap(),
+                Err(Error::Runtime(err)) => writeln!(output, "{}", err.traceback()).unwrap(),
+                Err(err) => writeln!(output, "{}", err).unwrap(),
+            }
+            continue;
+        }
+
         match trimmed_line {
             ":q" => {
                 writeln!(output, "Exiting REPL. Goodbye!").unwrap();
@@ -128,55 +186,91 @@ pub fn start_repl(output: &mut dyn Write) {
                     "  :info <function>  - Show documentation for a specific function"
                 )
                 .unwrap();
+                writeln!(
+                    output,
+                    "  :save <file>      - Save the session's variables and functions"
+                )
+                .unwrap();
+                writeln!(
+                    output,
+                    "  :load-session <file> - Restore a session saved with :save"
+                )
+                .unwrap();
                 writeln!(output, "  :help             - Show this help message").unwrap();
             }
-            line => {
-                let mut parser = Parser::new(Lexer::new(line));
-                let program = parser.parse();
-                let errors = parser.get_errors();
-                if !errors.is_empty() {
-                    for err in errors {
-                        writeln!(output, "{}", err).unwrap();
-                    }
-                    continue;
-                }
-                if let Some(evaluated) = evaluator.eval(program) {
-                    writeln!(output, "{}", evaluated).unwrap();
-                }
-            }
+            line => eval_line(&mut interpreter, line, output),
         }
     }
 }
 
-#[inline]
-pub fn run_file(input: &str) {
-    let mut env = Env::from(new_builtins());
-
-    env.set(
-        String::from("print"),
-        &Object::Builtin(-1, |args| {
-            for arg in args {
-                println!("{}", arg);
-            }
-            Object::Null
-        }),
-    );
-
-    let mut evaluator = Evaluator::new(Rc::new(RefCell::new(env)));
-    load_prelude(&mut evaluator);
-
-    let mut parser = Parser::new(Lexer::new(input));
+/// Evaluates one line of REPL input and prints its result or errors.
+///
+/// The globals the line defines stay in `interpreter` for the next line,
+/// and so do the earlier ones when the line fails or is interrupted.
+fn eval_line(interpreter: &mut Interpreter, line: &str, output: &mut dyn Write) {
+    let mut parser = Parser::new(Lexer::new(line));
     let program = parser.parse();
     let errors = parser.get_errors();
-
     if !errors.is_empty() {
         for err in errors {
-            println!("{}", err);
+            writeln!(output, "{}", err).unwrap();
         }
+        return;
     }
+    match interpreter.eval(program) {
+        Some(Object::Error(err)) => writeln!(output, "{}", err.traceback()).unwrap(),
+        Some(evaluated) => writeln!(output, "{}", evaluated).unwrap(),
+        None => {}
+    }
+}
+
+/// Makes Ctrl-C interrupt `interpreter` instead of ending the process.
+fn catch_ctrl_c(interpreter: &Interpreter) {
+    static INSTALL: Once = Once::new();
+
+    CTRL_C.lock().unwrap().interrupt = Some(interpreter.interrupt_handle());
+    INSTALL.call_once(|| {
+        // Without a handler, for example when the embedding application has
+        // its own, Ctrl-C keeps its usual meaning
+        let _ = ctrlc::set_handler(|| {
+            let mut ctrl_c = CTRL_C.lock().unwrap();
+            let action = ctrl_c.press();
+            if action == CtrlCAction::Interrupt {
+                if let Some(ref interrupt) = ctrl_c.interrupt {
+                    interrupt.interrupt();
+                }
+            }
+            drop(ctrl_c);
+            match action {
+                CtrlCAction::Interrupt => {}
+                // The terminal already dropped the line typed so far
+                CtrlCAction::Notice => {
+                    print!("\n(To exit, press Ctrl-C again or type :q)\n{}", PROMPT);
+                    let _ = std::io::stdout().flush();
+                }
+                CtrlCAction::Exit => {
+                    println!();
+                    std::process::exit(130);
+                }
+            }
+        });
+    });
+}
+
+#[inline]
+pub fn run_file(input: &str) {
+    run_file_with(input, &Config::default());
+}
+
+/// Runs a script using the given [`Config`].
+pub fn run_file_with(input: &str, config: &Config) {
+    let mut interpreter = Interpreter::with_config(config);
 
-    if let Some(evaluated) = evaluator.eval(program) {
-        println!("{}\n", evaluated);
+    match interpreter.eval_str(input) {
+        Ok(Object::Null) => {}
+        Ok(evaluated) => println!("{}\n", evaluated),
+        Err(Error::Runtime(err)) => println!("{}\n", err.traceback()),
+        Err(err) => println!("{}", err),
     }
 }
 
@@ -187,22 +281,6 @@ pub fn read_from_stdin(line: &mut String) -> usize {
     input.read_line(line).unwrap()
 }
 
-#[inline]
-fn load_prelude(evaluator: &mut Evaluator) {
-    let mut parser = Parser::new(Lexer::new(STAND_PRELUDE));
-    let program = parser.parse();
-    let errors = parser.get_errors();
-
-    if !errors.is_empty() {
-        for err in errors {
-            println!("Prelude Error: {}", err);
-        }
-    }
-
-    if let Some(evaluated) = evaluator.eval(program) {
-        println!("{}\n", evaluated);
-    }
-}
 /// Returns a HashMap containing documentation for all built-in functions.
 ///
 /// This function creates and populates a HashMap where:
@@ -235,11 +313,13 @@ fn get_buildin_doc() -> HashMap<String, String> {
     );
     map.insert(
         String::from("readFile"),
-        String::from("Read the contents of a file\nString -> String"),
+        String::from(
+            "Read the contents of a file\nReturns a Result in result mode\nString -> String",
+        ),
     );
     map.insert(
         String::from("writeFile"),
-        String::from("Writes to a file the given content.\nCreates a file if it does not exist\nString -> Null"),
+        String::from("Writes to a file the given content.\nCreates a file if it does not exist\nReturns a Result in result mode\nString -> Null"),
     );
 
     // List operations
@@ -284,6 +364,36 @@ fn get_buildin_doc() -> HashMap<String, String> {
         String::from("Reduces a list to a single value using a function.\nList -> Function -> InitialValue -> Value"),
     );
 
+    map.insert(
+        String::from("typeOf"),
+        String::from(
+            "Returns the name of a value's type, or the struct name for records.\nValue -> String",
+        ),
+    );
+
+    map.insert(
+        String::from("tagOf"),
+        String::from(
+            "Returns the name of the variant an enum value was built with.\nEnumValue -> String",
+        ),
+    );
+
+    map.insert(
+        String::from("isNull"),
+        String::from(
+            "Returns true if the value is null. Unlike `!`, false is not null.\nValue -> Bool",
+        ),
+    );
+
+    map.insert(
+        String::from("Ok"),
+        String::from("Wraps a successful value in a Result.\nValue -> Result"),
+    );
+    map.insert(
+        String::from("Err"),
+        String::from("Wraps an error in a Result.\nValue -> Result"),
+    );
+
     // String Utilities
     map.insert(
         String::from("lowercase"),
@@ -301,7 +411,9 @@ fn get_buildin_doc() -> HashMap<String, String> {
     );
     map.insert(
         String::from("parseNumber"),
-        String::from("Converts a String into a number.\nString -> Number"),
+        String::from(
+            "Converts a String into a number.\nReturns a Result in result mode\nString -> Number",
+        ),
     );
     map.insert(
         String::from("replaceString"),
@@ -317,3 +429,86 @@ fn get_buildin_doc() -> HashMap<String, String> {
     );
     map
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn test_ctrl_c() {
+        let mut ctrl_c = CtrlC::new();
+        assert_eq!(CtrlCAction::Notice, ctrl_c.press());
+        assert_eq!(CtrlCAction::Exit, ctrl_c.press());
+
+        // Reading a line starts the count again
+        ctrl_c.set_running(true);
+        assert_eq!(CtrlCAction::Interrupt, ctrl_c.press());
+        assert_eq!(CtrlCAction::Interrupt, ctrl_c.press());
+        ctrl_c.set_running(false);
+        assert_eq!(CtrlCAction::Notice, ctrl_c.press());
+        ctrl_c.set_running(true);
+        ctrl_c.set_running(false);
+        assert_eq!(CtrlCAction::Notice, ctrl_c.press());
+        assert_eq!(CtrlCAction::Exit, ctrl_c.press());
+    }
+
+    fn eval_lines(interpreter: &mut Interpreter, lines: &[&str]) -> String {
+        let mut output = vec![];
+        for line in lines {
+            eval_line(interpreter, line, &mut output);
+        }
+        String::from_utf8(output).unwrap()
+    }
+
+    #[test]
+    fn test_eval_line() {
+        for vm in [false, true] {
+            let mut interpreter = Interpreter::with_config(&Config {
+                vm,
+                ..Config::default()
+            });
+            let output = eval_lines(&mut interpreter, &["let x = 1;", "x + 1", "let = 2;"]);
+            let mut lines = output.lines();
+            assert_eq!(Some("2"), lines.next());
+            assert!(lines.next().is_some(), "expected a parse error");
+        }
+    }
+
+    #[test]
+    fn test_eval_line_interrupted() {
+        for vm in [false, true] {
+            let mut interpreter = Interpreter::with_config(&Config {
+                vm,
+                ..Config::default()
+            });
+            assert_eq!("", eval_lines(&mut interpreter, &["let answer = 42;"]));
+
+            // Interrupt until the line is done, as each run clears earlier interrupts
+            let interrupt = interpreter.interrupt_handle();
+            let done = Arc::new(AtomicBool::new(false));
+            let interrupter = {
+                let done = Arc::clone(&done);
+                thread::spawn(move || {
+                    while !done.load(Ordering::Relaxed) {
+                        interrupt.interrupt();
+                        thread::sleep(Duration::from_millis(10));
+                    }
+                })
+            };
+            let output = eval_lines(
+                &mut interpreter,
+                &["let spin = fun(n) { spin(n + 1) }; spin(0)"],
+            );
+            done.store(true, Ordering::Relaxed);
+            interrupter.join().unwrap();
+
+            assert!(output.contains("interrupted"), "got {:?}", output);
+            // The next line still sees the globals defined before the interrupt
+            assert_eq!("43\n", eval_lines(&mut interpreter, &["answer + 1"]));
+        }
+    }
+}
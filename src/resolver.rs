@@ -0,0 +1,525 @@
+// Copyright 2024 Dimitrios Papakonstantinou. All rights reserved.
+// Use of this source code is governed by a MIT
+// license that can be found in the LICENSE file
+
+use crate::ast::*;
+use crate::evaluator::error::{ErrorKind, RuntimeError};
+use std::collections::{HashMap, HashSet};
+use std::rc::Rc;
+
+/// Names bound in one function or `catch` scope, mapped to their slots.
+struct Scope {
+    slots: HashMap<String, usize>,
+    /// Names bound wherever they are read from the point being resolved on:
+    /// the parameters and the declarations made directly in the scope so far.
+    bound: HashSet<String>,
+}
+
+/// Resolves the variables of a program before it runs.
+///
+/// Parameters and names declared inside a function or `catch` block live in
+/// numbered slots of that scope: references to them become `Expr::Local` and
+/// their declarations become `Stmt::Local`. All other names are global and are
+/// looked up by name when they are read. A name read outside of any function
+/// that is never declared is reported up front, unless it is read inside a
+/// `try` block, whose `catch` may handle the error. Functions may run after
+/// later code has defined the globals they read, so their reads are not checked.
+pub struct Resolver {
+    globals: HashSet<String>,
+    scopes: Vec<Scope>,
+    /// Number of `try` blocks around the code being resolved.
+    tries: usize,
+    /// Number of function bodies around the code being resolved.
+    functions: usize,
+    errors: Vec<RuntimeError>,
+}
+
+impl Resolver {
+    /// Creates a resolver for code running after `globals` have been defined.
+    pub fn new(globals: HashSet<String>) -> Self {
+        Resolver {
+            globals,
+            scopes: vec![],
+            tries: 0,
+            functions: 0,
+            errors: vec![],
+        }
+    }
+
+    pub fn get_errors(&mut self) -> Vec<RuntimeError> {
+        self.errors.clone()
+    }
+
+    pub fn resolve(&mut self, program: Program) -> Program {
+        let mut names = vec![];
+        Self::hoist_block(&program, &mut names);
+        self.globals.extend(names);
+
+        self.resolve_block(program)
+    }
+
+    /// Resolves a function body or `catch` block in a new scope whose first slots hold `params`.
+    fn resolve_scope(&mut self, params: Vec<String>, body: BlockStmt) -> BlockStmt {
+        let mut scope = Scope {
+            slots: HashMap::new(),
+            bound: params.iter().cloned().collect(),
+        };
+        let mut next = params.len();
+        for (index, name) in params.into_iter().enumerate() {
+            scope.slots.insert(name, index);
+        }
+
+        // Declarations anywhere in the scope get a slot up front, so closures
+        // can refer to names declared after them
+        let mut names = vec![];
+        Self::hoist_block(&body, &mut names);
+        for name in names {
+            scope.slots.entry(name).or_insert_with(|| {
+                next += 1;
+                next - 1
+            });
+        }
+
+        self.scopes.push(scope);
+        let body = body
+            .into_iter()
+            .map(|stmt| {
+                let names = Self::declared_names(&stmt);
+                let stmt = self.resolve_stmt(stmt);
+                let scope = self.scopes.last_mut().expect("scope was pushed");
+                scope.bound.extend(names);
+                stmt
+            })
+            .collect();
+        self.scopes.pop();
+        body
+    }
+
+    fn resolve_block(&mut self, stmts: BlockStmt) -> BlockStmt {
+        stmts
+            .into_iter()
+            .map(|stmt| self.resolve_stmt(stmt))
+            .collect()
+    }
+
+    fn resolve_stmt(&mut self, stmt: Stmt) -> Stmt {
+        let stmt = match stmt {
+            Stmt::Let(name, expr) => Stmt::Let(name, self.resolve_expr(expr)),
+            Stmt::Const(name, expr) => Stmt::Const(name, self.resolve_expr(expr)),
+            Stmt::Return(expr) => Stmt::Return(self.resolve_expr(expr)),
+            Stmt::Throw(expr) => Stmt::Throw(self.resolve_expr(expr)),
+            Stmt::Expr(expr) => Stmt::Expr(self.resolve_expr(expr)),
+            stmt => stmt,
+        };
+
+        let names = Self::declared_names(&stmt);
+        match self.scopes.last() {
+            Some(scope) if !names.is_empty() => {
+                let slots = names.iter().map(|name| scope.slots[name]).collect();
+                Stmt::Local(slots, Box::new(stmt))
+            }
+            _ => stmt,
+        }
+    }
+
+    fn resolve_expr(&mut self, expr: Expr) -> Expr {
+        match expr {
+            Expr::Ident(Ident(name)) => self.resolve_ident(name),
+            Expr::Literal(Literal::Array(items)) => {
+                Expr::Literal(Literal::Array(self.resolve_exprs(items)))
+            }
+            Expr::Literal(Literal::Hash(pairs)) => Expr::Literal(Literal::Hash(
+                pairs
+                    .into_iter()
+                    .map(|(key, value)| (self.resolve_expr(key), self.resolve_expr(value)))
+                    .collect(),
+            )),
+            Expr::Prefix(prefix, right) => Expr::Prefix(prefix, self.resolve_boxed(*right)),
+            Expr::Infix(infix, left, right) => {
+                Expr::Infix(infix, self.resolve_boxed(*left), self.resolve_boxed(*right))
+            }
+            Expr::Index(left, index) => {
+                Expr::Index(self.resolve_boxed(*left), self.resolve_boxed(*index))
+            }
+            Expr::OptionalIndex(left, index) => {
+                Expr::OptionalIndex(self.resolve_boxed(*left), self.resolve_boxed(*index))
+            }
+            Expr::Dot(left, field) => Expr::Dot(self.resolve_boxed(*left), field),
+            Expr::OptionalDot(left, field) => Expr::OptionalDot(self.resolve_boxed(*left), field),
+            Expr::If {
+                cond,
+                consequence,
+                alternative,
+            } => Expr::If {
+                cond: self.resolve_boxed(*cond),
+                consequence: self.resolve_block(consequence),
+                alternative: alternative.map(|alt| self.resolve_block(alt)),
+            },
+            Expr::Func { params, body } => {
+                let names = params.iter().map(|Ident(param)| param.clone()).collect();
+                self.functions += 1;
+                let body = self.resolve_scope(names, Rc::unwrap_or_clone(body));
+                self.functions -= 1;
+                Expr::Func {
+                    params,
+                    body: Rc::new(body),
+                }
+            }
+            Expr::Call { func, args, span } => {
+                let func = match *func {
+                    Expr::Dot(receiver, name) => self.resolve_method(*receiver, name, false),
+                    Expr::OptionalDot(receiver, name) => self.resolve_method(*receiver, name, true),
+                    func => self.resolve_expr(func),
+                };
+                Expr::Call {
+                    func: Box::new(func),
+                    args: self.resolve_exprs(args),
+                    span,
+                }
+            }
+            Expr::Propagate(expr) => Expr::Propagate(self.resolve_boxed(*expr)),
+            Expr::Try {
+                body,
+                catch,
+                finally,
+            } => {
+                self.tries += 1;
+                let body = self.resolve_block(body);
+                self.tries -= 1;
+
+                Expr::Try {
+                    body,
+                    catch: catch.map(|(name, handler)| {
+                        let handler = self.resolve_scope(vec![name.0.clone()], handler);
+                        (name, handler)
+                    }),
+                    finally: finally.map(|finally| self.resolve_block(finally)),
+                }
+            }
+            expr => expr,
+        }
+    }
+
+    fn resolve_boxed(&mut self, expr: Expr) -> Box<Expr> {
+        Box::new(self.resolve_expr(expr))
+    }
+
+    fn resolve_exprs(&mut self, exprs: Vec<Expr>) -> Vec<Expr> {
+        exprs
+            .into_iter()
+            .map(|expr| self.resolve_expr(expr))
+            .collect()
+    }
+
+    fn resolve_ident(&mut self, name: String) -> Expr {
+        if let Some(local) = self.lookup(&name) {
+            return local;
+        }
+
+        if self.tries == 0 && self.functions == 0 && !self.globals.contains(&name) {
+            self.errors.push(RuntimeError::new(
+                ErrorKind::NameError,
+                format!("identifier not found: {}", name),
+            ));
+        }
+        Expr::Ident(Ident(name))
+    }
+
+    /// Resolves the function of a method call, whose name need not be in scope
+    /// if the receiver has a field of that name.
+    fn resolve_method(&mut self, receiver: Expr, name: Ident, optional: bool) -> Expr {
+        let var = self
+            .lookup(&name.0)
+            .unwrap_or_else(|| Expr::Ident(name.clone()));
+        Expr::Method {
+            receiver: self.resolve_boxed(receiver),
+            name,
+            optional,
+            var: Box::new(var),
+        }
+    }
+
+    /// Finds the innermost local variable named `name`, if any.
+    ///
+    /// A declaration may not have run yet when the variable is read, as in
+    /// `let x = x + 1` or after an `if` that did not declare it, so each local
+    /// falls back to the one it shadows and the outermost to the global.
+    fn lookup(&self, name: &str) -> Option<Expr> {
+        let mut local = None;
+        for (i, scope) in self.scopes.iter().enumerate() {
+            if let Some(&index) = scope.slots.get(name) {
+                let fallback = local.unwrap_or_else(|| Expr::Ident(Ident(name.to_string())));
+                let slot = Slot {
+                    depth: self.scopes.len() - 1 - i,
+                    index,
+                };
+                let fallback = (!scope.bound.contains(name)).then(|| Box::new(fallback));
+                local = Some(Expr::Local(Ident(name.to_string()), slot, fallback));
+            }
+        }
+        local
+    }
+
+    /// Names a statement binds in the scope it appears in.
+    pub(crate) fn declared_names(stmt: &Stmt) -> Vec<String> {
+        match *stmt {
+            Stmt::Let(Ident(ref name), _)
+            | Stmt::Const(Ident(ref name), _)
+            | Stmt::Struct(Ident(ref name), _) => vec![name.clone()],
+            Stmt::Enum(_, ref variants) => {
+                variants.iter().map(|(Ident(tag), _)| tag.clone()).collect()
+            }
+            _ => vec![],
+        }
+    }
+
+    /// Collects the names declared in `stmts`, including inside `if` and `try`
+    /// blocks but not in nested functions or `catch` blocks, which have their own scope.
+    pub(crate) fn hoist_block(stmts: &[Stmt], names: &mut Vec<String>) {
+        for stmt in stmts {
+            names.extend(Self::declared_names(stmt));
+
+            match *stmt {
+                Stmt::Let(_, ref expr)
+                | Stmt::Const(_, ref expr)
+                | Stmt::Return(ref expr)
+                | Stmt::Throw(ref expr)
+                | Stmt::Expr(ref expr) => Self::hoist_expr(expr, names),
+                _ => {}
+            }
+        }
+    }
+
+    pub(crate) fn hoist_expr(expr: &Expr, names: &mut Vec<String>) {
+        match *expr {
+            Expr::Literal(Literal::Array(ref items)) => {
+                for item in items {
+                    Self::hoist_expr(item, names);
+                }
+            }
+            Expr::Literal(Literal::Hash(ref pairs)) => {
+                for (key, value) in pairs {
+                    Self::hoist_expr(key, names);
+                    Self::hoist_expr(value, names);
+                }
+            }
+            Expr::Prefix(_, ref expr)
+            | Expr::Dot(ref expr, _)
+            | Expr::OptionalDot(ref expr, _)
+            | Expr::Propagate(ref expr) => Self::hoist_expr(expr, names),
+            Expr::Infix(_, ref left, ref right)
+            | Expr::Index(ref left, ref right)
+            | Expr::OptionalIndex(ref left, ref right) => {
+                Self::hoist_expr(left, names);
+                Self::hoist_expr(right, names);
+            }
+            Expr::If {
+                ref cond,
+                ref consequence,
+                ref alternative,
+            } => {
+                Self::hoist_expr(cond, names);
+                Self::hoist_block(consequence, names);
+                if let Some(alt) = alternative {
+                    Self::hoist_block(alt, names);
+                }
+            }
+            Expr::Call {
+                ref func, ref args, ..
+            } => {
+                Self::hoist_expr(func, names);
+                for arg in args {
+                    Self::hoist_expr(arg, names);
+                }
+            }
+            Expr::Try {
+                ref body,
+                ref finally,
+                ..
+            } => {
+                Self::hoist_block(body, names);
+                if let Some(finally) = finally {
+                    Self::hoist_block(finally, names);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ast::*;
+    use crate::lexer::Lexer;
+    use crate::parser::Parser;
+    use crate::resolver::Resolver;
+    use std::collections::HashSet;
+    use std::rc::Rc;
+
+    fn resolve(input: &str) -> Program {
+        let program = Parser::new(Lexer::new(input)).parse();
+        let mut resolver = Resolver::new(HashSet::new());
+        let program = resolver.resolve(program);
+        assert!(resolver.get_errors().is_empty());
+        program
+    }
+
+    fn local(name: &str, depth: usize, index: usize) -> Expr {
+        Expr::Local(Ident(String::from(name)), Slot { depth, index }, None)
+    }
+
+    fn local_or(name: &str, depth: usize, index: usize, fallback: Expr) -> Expr {
+        let slot = Slot { depth, index };
+        Expr::Local(Ident(String::from(name)), slot, Some(Box::new(fallback)))
+    }
+
+    fn global(name: &str) -> Expr {
+        Expr::Ident(Ident(String::from(name)))
+    }
+
+    #[test]
+    fn test_resolve_slots() {
+        let input = "let g = 1; fun(a) { let b = a; fun(c) { a + b + c + g } }";
+
+        assert_eq!(
+            vec![
+                Stmt::Let(Ident(String::from("g")), Expr::Literal(Literal::Int(1))),
+                Stmt::Expr(Expr::Func {
+                    params: Rc::new(vec![Ident(String::from("a"))]),
+                    body: Rc::new(vec![
+                        Stmt::Local(
+                            vec![1],
+                            Box::new(Stmt::Let(Ident(String::from("b")), local("a", 0, 0))),
+                        ),
+                        Stmt::Expr(Expr::Func {
+                            params: Rc::new(vec![Ident(String::from("c"))]),
+                            body: Rc::new(vec![Stmt::Expr(Expr::Infix(
+                                Infix::Plus,
+                                Box::new(Expr::Infix(
+                                    Infix::Plus,
+                                    Box::new(Expr::Infix(
+                                        Infix::Plus,
+                                        Box::new(local("a", 1, 0)),
+                                        Box::new(local("b", 1, 1)),
+                                    )),
+                                    Box::new(local("c", 0, 0)),
+                                )),
+                                Box::new(global("g")),
+                            ))]),
+                        }),
+                    ]),
+                }),
+            ],
+            resolve(input)
+        );
+    }
+
+    #[test]
+    fn test_resolve_shadowed_variables() {
+        let input = "let x = 1; fun() { let x = x + 1; if (true) { let y = 2; } y; x }";
+        let body = vec![
+            Stmt::Local(
+                vec![0],
+                Box::new(Stmt::Let(
+                    Ident(String::from("x")),
+                    Expr::Infix(
+                        Infix::Plus,
+                        Box::new(local_or("x", 0, 0, global("x"))),
+                        Box::new(Expr::Literal(Literal::Int(1))),
+                    ),
+                )),
+            ),
+            Stmt::Expr(Expr::If {
+                cond: Box::new(Expr::Literal(Literal::Bool(true))),
+                consequence: vec![Stmt::Local(
+                    vec![1],
+                    Box::new(Stmt::Let(
+                        Ident(String::from("y")),
+                        Expr::Literal(Literal::Int(2)),
+                    )),
+                )],
+                alternative: None,
+            }),
+            Stmt::Expr(local_or("y", 0, 1, global("y"))),
+            Stmt::Expr(local("x", 0, 0)),
+        ];
+        assert_eq!(
+            vec![
+                Stmt::Let(Ident(String::from("x")), Expr::Literal(Literal::Int(1))),
+                Stmt::Expr(Expr::Func {
+                    params: Rc::new(vec![]),
+                    body: Rc::new(body),
+                }),
+            ],
+            resolve(input)
+        );
+
+        let input = "fun() { let x = 1; fun() { if (true) { let x = 2; } x } }";
+        let inner = vec![
+            Stmt::Expr(Expr::If {
+                cond: Box::new(Expr::Literal(Literal::Bool(true))),
+                consequence: vec![Stmt::Local(
+                    vec![0],
+                    Box::new(Stmt::Let(
+                        Ident(String::from("x")),
+                        Expr::Literal(Literal::Int(2)),
+                    )),
+                )],
+                alternative: None,
+            }),
+            Stmt::Expr(local_or("x", 0, 0, local("x", 1, 0))),
+        ];
+        assert_eq!(
+            vec![Stmt::Expr(Expr::Func {
+                params: Rc::new(vec![]),
+                body: Rc::new(vec![
+                    Stmt::Local(
+                        vec![0],
+                        Box::new(Stmt::Let(
+                            Ident(String::from("x")),
+                            Expr::Literal(Literal::Int(1)),
+                        )),
+                    ),
+                    Stmt::Expr(Expr::Func {
+                        params: Rc::new(vec![]),
+                        body: Rc::new(inner),
+                    }),
+                ]),
+            })],
+            resolve(input)
+        );
+    }
+
+    #[test]
+    fn test_resolve_undefined_variables() {
+        let tests = vec![
+            ("x", vec!["identifier not found: x"]),
+            ("x + fun() { y }()", vec!["identifier not found: x"]),
+            (
+                "try { 1 } catch (e) { e }; e",
+                vec!["identifier not found: e"],
+            ),
+            // Globals read in functions may be defined by the time they run
+            ("fun(a) { a + b }", vec![]),
+            ("fun() { try { 1 } catch (e) { x } }", vec![]),
+            ("let f = fun() { g() }; let g = fun() { 1 };", vec![]),
+            ("try { x } catch (e) { 1 }", vec![]),
+            (
+                "try { 1 } catch (e) { x } finally { y }",
+                vec!["identifier not found: x", "identifier not found: y"],
+            ),
+        ];
+
+        for (input, expect) in tests {
+            let program = Parser::new(Lexer::new(input)).parse();
+            let mut resolver = Resolver::new(HashSet::new());
+            resolver.resolve(program);
+            let errors: Vec<String> = resolver
+                .get_errors()
+                .iter()
+                .map(|err| err.message.clone())
+                .collect();
+            assert_eq!(expect, errors);
+        }
+    }
+}
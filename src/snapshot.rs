@@ -0,0 +1,188 @@
+// Copyright 2024 Dimitrios Papakonstantinou. All rights reserved.
+// Use of this source code is governed by a MIT
+// license that can be found in the LICENSE file
+
+//! Saving the global variables of a session as a script that defines them again.
+//!
+//! Data values are written as literals and functions by their source, so a
+//! snapshot is restored by running it like any other script.
+
+use crate::ast::{Expr, Ident, Literal, Stmt};
+use crate::evaluator::env::Env;
+use crate::evaluator::object::Object;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashSet};
+use std::rc::Rc;
+
+const HEADER: &str = "// Beavieeer session snapshot";
+
+/// Writes the globals of `globals`, except those named in `skip`, as a script.
+///
+/// Bindings that can not be written as source, such as closures over local
+/// variables, are left out with a comment saying why.
+pub fn snapshot(globals: &Rc<RefCell<Env>>, skip: &HashSet<String>) -> String {
+    let env = globals.borrow();
+    let mut names: Vec<String> = env
+        .global_names()
+        .into_iter()
+        .filter(|name| !skip.contains(name))
+        .collect();
+    names.sort();
+
+    let mut lines = vec![String::from(HEADER)];
+    let mut declared = HashSet::new();
+    let mut enums: BTreeMap<String, Vec<(Ident, Vec<Ident>)>> = BTreeMap::new();
+    for name in &names {
+        match env.get_global(name) {
+            Some(Object::StructCtor(ref struct_name, ref fields)) if struct_name == name => {
+                let fields = fields.iter().cloned().map(Ident).collect();
+                lines.push(Stmt::Struct(Ident(name.clone()), fields).to_string());
+                declared.insert(name);
+            }
+            Some(Object::VariantCtor(ref enum_name, ref tag, ref fields)) if tag == name => {
+                let fields = fields.iter().cloned().map(Ident).collect();
+                enums
+                    .entry(enum_name.clone())
+                    .or_default()
+                    .push((Ident(tag.clone()), fields));
+                declared.insert(name);
+            }
+            Some(Object::Variant(ref enum_name, ref tag, ref fields))
+                if tag == name && fields.is_empty() =>
+            {
+                enums
+                    .entry(enum_name.clone())
+                    .or_default()
+                    .push((Ident(tag.clone()), vec![]));
+                declared.insert(name);
+            }
+            _ => {}
+        }
+    }
+    for (name, variants) in enums {
+        lines.push(Stmt::Enum(Ident(name), variants).to_string());
+    }
+
+    let writer = Writer { globals, skip };
+    for name in names.iter().filter(|name| !declared.contains(name)) {
+        let value = env.get_global(name).expect("name is bound");
+        match writer.write(&value) {
+            Ok(expr) => {
+                let keyword = if env.is_const(name) { "const" } else { "let" };
+                lines.push(format!("{} {} = {};", keyword, name, expr));
+            }
+            Err(reason) => lines.push(format!("// {} was not saved: {}", name, reason)),
+        }
+    }
+
+    lines.push(String::new());
+    lines.join("\n")
+}
+
+/// Writes values as expressions that evaluate to them in the global scope.
+struct Writer<'a> {
+    globals: &'a Rc<RefCell<Env>>,
+    skip: &'a HashSet<String>,
+}
+
+impl Writer<'_> {
+    fn write(&self, value: &Object) -> Result<String, String> {
+        match *value {
+            Object::Int(value) => Ok(Literal::Int(value).to_string()),
+            Object::String(ref value) => {
+                // Strings have no escapes, so a quote can not be written
+                if value.contains('"') {
+                    return Err(String::from("strings with quotes can not be saved"));
+                }
+                Ok(Literal::String(value.clone()).to_string())
+            }
+            Object::Bool(value) => Ok(value.to_string()),
+            Object::Null => Ok(String::from("null")),
+            Object::Array(ref items) => {
+                let items = self.write_all(items.iter())?;
+                Ok(format!("[{}]", items.join(", ")))
+            }
+            Object::Hash(ref hash) => {
+                let mut pairs = vec![];
+                for (key, value) in hash.iter() {
+                    pairs.push(format!("{}: {}", self.write(key)?, self.write(value)?));
+                }
+                pairs.sort();
+                Ok(format!("{{{}}}", pairs.join(", ")))
+            }
+            Object::Struct(ref name, ref fields) => {
+                let names = fields.iter().map(|(field, _)| field.clone()).collect();
+                self.check_global(name, &Object::StructCtor(name.clone(), names))?;
+                let values = self.write_all(fields.iter().map(|(_, value)| value))?;
+                Ok(format!("{}({})", name, values.join(", ")))
+            }
+            Object::Variant(ref enum_name, ref tag, ref fields) => {
+                if fields.is_empty() {
+                    self.check_global(tag, value)?;
+                    return Ok(tag.clone());
+                }
+
+                let names = fields.iter().map(|(field, _)| field.clone()).collect();
+                let ctor = Object::VariantCtor(enum_name.clone(), tag.clone(), names);
+                self.check_global(tag, &ctor)?;
+                let values = self.write_all(fields.iter().map(|(_, value)| value))?;
+                Ok(format!("{}({})", tag, values.join(", ")))
+            }
+            Object::StructCtor(ref name, _) | Object::VariantCtor(_, ref name, _) => {
+                self.check_global(name, value)?;
+                Ok(name.clone())
+            }
+            Object::Func(ref params, ref body, ref env) => {
+                if !Rc::ptr_eq(env, self.globals) {
+                    return Err(String::from("it captures local variables"));
+                }
+                Ok(Self::write_func(params, body))
+            }
+            Object::Closure(ref closure) => {
+                if !closure.upvalues.is_empty() {
+                    return Err(String::from("it captures local variables"));
+                }
+                Ok(Self::write_func(&closure.proto.params, &closure.proto.body))
+            }
+            Object::Builtin(..) => self
+                .builtin_name(value)
+                .ok_or_else(|| String::from("builtin functions can not be saved")),
+            Object::ReturnValue(ref value) => self.write(value),
+            Object::Error(_) => Err(String::from("errors can not be saved")),
+        }
+    }
+
+    fn write_all<'o>(
+        &self,
+        values: impl Iterator<Item = &'o Object>,
+    ) -> Result<Vec<String>, String> {
+        values.map(|value| self.write(value)).collect()
+    }
+
+    fn write_func(params: &[Ident], body: &Rc<Vec<Stmt>>) -> String {
+        Expr::Func {
+            params: Rc::new(params.to_vec()),
+            body: Rc::clone(body),
+        }
+        .to_string()
+    }
+
+    /// Fails unless `name` still refers to `value`, so that writing the name restores it.
+    fn check_global(&self, name: &str, value: &Object) -> Result<(), String> {
+        match self.globals.borrow().get_global(name) {
+            Some(ref global) if global == value => Ok(()),
+            _ => Err(format!("`{}` no longer refers to its type", name)),
+        }
+    }
+
+    /// The name of a builtin the interpreter defines itself.
+    fn builtin_name(&self, builtin: &Object) -> Option<String> {
+        let env = self.globals.borrow();
+        let mut names: Vec<&String> = self.skip.iter().collect();
+        names.sort();
+        names
+            .into_iter()
+            .find(|name| env.get_global(name).as_ref() == Some(builtin))
+            .cloned()
+    }
+}
@@ -24,6 +24,8 @@ pub enum Token {
     String(String),
     /// Represents a boolean literal (`true` or `false`).
     Bool(bool),
+    /// Represents the `null` literal.
+    Null,
 
     // Statements
     /// Represents an assignment (`=`) operator.
@@ -57,12 +59,22 @@ pub enum Token {
     GreaterThan,
     /// Represents the greater-than-or-equal comparison (`>=`).
     GreaterThanEqual,
+    /// Represents the null-coalescing (`??`) operator.
+    NullCoalesce,
 
     // Delimiters
     /// Represents a comma (`,`).
     Comma,
     /// Represents a colon (`:`).
     Colon,
+    /// Represents a dot (`.`) used for field access and method calls.
+    Dot,
+    /// Represents the postfix error propagation (`?`) operator.
+    Question,
+    /// Represents a null-safe dot (`?.`).
+    QuestionDot,
+    /// Represents a null-safe left bracket (`?[`).
+    QuestionLbracket,
     /// Represents a semicolon (`;`).
     Semicolon,
     /// Represents a left parenthesis (`(`).
@@ -83,6 +95,20 @@ pub enum Token {
     Func,
     /// Represents the `let` keyword for variable declarations.
     Let,
+    /// Represents the `const` keyword for immutable declarations.
+    Const,
     /// Represents the `return` keyword.
     Return,
+    /// Represents the `struct` keyword for declaring record types.
+    Struct,
+    /// Represents the `enum` keyword for declaring tagged unions.
+    Enum,
+    /// Represents the `try` keyword.
+    Try,
+    /// Represents the `catch` keyword.
+    Catch,
+    /// Represents the `finally` keyword.
+    Finally,
+    /// Represents the `throw` keyword.
+    Throw,
 }
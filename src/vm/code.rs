@@ -0,0 +1,150 @@
+// Copyright 2024 Dimitrios Papakonstantinou. All rights reserved.
+// Use of this source code is governed by a MIT
+// license that can be found in the LICENSE file
+
+use crate::ast::*;
+use crate::evaluator::env::Env;
+use crate::evaluator::error::Frame;
+use crate::evaluator::object::Object;
+use std::cell::RefCell;
+use std::fmt;
+use std::rc::Rc;
+
+/// A single VM instruction.
+///
+/// Operands index into the pools of the [`Proto`] the instruction belongs to,
+/// or into the registers of the running call for locals. Jump targets are
+/// offsets into the same code.
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum Op {
+    Constant(u32),
+    Null,
+    /// Pushes the value of an expression that has none, such as `if` without
+    /// `else` when the condition is false.
+    Nothing,
+    /// Replaces a top of the stack that has no value with null.
+    OrNull,
+    Pop,
+    GetLocal(u32),
+    /// Pushes a register and jumps to the target, or goes on to read the
+    /// variable it shadows if its declaration has not run yet.
+    GetLocalOr(u32, u32),
+    /// Pops the top of the stack into a register.
+    SetLocal(u32),
+    GetUpvalue(u32),
+    /// Like `GetLocalOr`, for an upvalue.
+    GetUpvalueOr(u32, u32),
+    GetGlobal(u32),
+    /// Pops the top of the stack into a global, as a constant if the flag is set.
+    DeclareGlobal(u32, bool),
+    Prefix(Prefix),
+    Infix(Infix),
+    Index,
+    Field(u32),
+    Array(u32),
+    Hash(u32),
+    Jump(u32),
+    /// Pops the condition and jumps if it is falsy.
+    JumpIfFalse(u32),
+    /// Jumps if the top of the stack is null, keeping it.
+    JumpIfNull(u32),
+    /// Jumps if the top of the stack is not null, keeping it, otherwise pops it.
+    JumpIfNotNull(u32),
+    /// Jumps if the top of the stack has no value, keeping it.
+    JumpIfNothing(u32),
+    Closure(u32),
+    /// Calls the function on top of the stack with the `argc` values below it.
+    Call {
+        argc: u32,
+        site: u32,
+        tail: bool,
+    },
+    /// Calls `receiver.name(args)`, with the receiver below the arguments.
+    ///
+    /// With `local` set, the function in scope under `name` was pushed last,
+    /// otherwise it is looked up in the globals.
+    Invoke {
+        name: u32,
+        argc: u32,
+        site: u32,
+        local: bool,
+        tail: bool,
+    },
+    Return,
+    /// Unwraps an `Ok` and jumps, leaving an `Err` to be returned.
+    Propagate(u32),
+    Throw,
+    /// Catches errors raised until the matching `PopHandler` by jumping to the target.
+    PushHandler(u32),
+    PopHandler,
+    /// Turns a caught error into the value bound by `catch`.
+    CatchError,
+    /// Raises the top of the stack again if it is an uncaught error.
+    Rethrow,
+    /// Records the value of a top-level statement as the program's result.
+    SetResult,
+    ClearResult,
+    End,
+}
+
+/// How a closure finds a variable of an enclosing function.
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub struct UpvalueDesc {
+    /// Whether `index` is a register of the directly enclosing function,
+    /// rather than one of its upvalues.
+    pub local: bool,
+    pub index: u32,
+    /// Name of the variable, in the pool of the closure's [`Proto`].
+    pub name: u32,
+}
+
+/// A compiled function body, or a whole program.
+#[derive(PartialEq, Debug, Default)]
+pub struct Proto {
+    pub params: Vec<Ident>,
+    pub code: Vec<Op>,
+    pub constants: Vec<Object>,
+    /// Names of globals, fields, methods and captured variables.
+    pub names: Vec<Ident>,
+    /// Functions defined in this one.
+    pub protos: Vec<Rc<Proto>>,
+    /// Call sites, as reported in stack traces.
+    pub sites: Vec<Frame>,
+    pub upvalues: Vec<UpvalueDesc>,
+    /// Names of the registers holding parameters and local variables.
+    pub locals: Vec<Ident>,
+    /// The body the function was compiled from, so it can be saved as source.
+    pub body: Rc<BlockStmt>,
+}
+
+/// A variable captured by a closure.
+///
+/// It stays on the stack of the function declaring it while that call is
+/// running and moves into the upvalue once the call returns.
+#[derive(Debug)]
+pub enum Upvalue {
+    Open(usize),
+    Closed(Object),
+}
+
+/// A function value created by the VM.
+pub struct Closure {
+    pub proto: Rc<Proto>,
+    pub upvalues: Vec<Rc<RefCell<Upvalue>>>,
+    pub globals: Rc<RefCell<Env>>,
+}
+
+impl PartialEq for Closure {
+    fn eq(&self, other: &Self) -> bool {
+        std::ptr::eq(self, other)
+    }
+}
+
+// The globals usually hold the closure itself, so they are left out
+impl fmt::Debug for Closure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Closure")
+            .field("params", &self.proto.params)
+            .finish_non_exhaustive()
+    }
+}
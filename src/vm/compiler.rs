@@ -0,0 +1,1020 @@
+// Copyright 2024 Dimitrios Papakonstantinou. All rights reserved.
+// Use of this source code is governed by a MIT
+// license that can be found in the LICENSE file
+
+use crate::ast::*;
+use crate::evaluator::error::Frame;
+use crate::evaluator::object::Object;
+use crate::evaluator::Evaluator;
+use crate::vm::code::*;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+/// A function or `catch` scope of the resolver, mapped onto the registers
+/// of the function it belongs to.
+struct Scope {
+    function: usize,
+    base: usize,
+}
+
+/// A `try` whose handler is active or whose `finally` block is still to run
+/// at the point being compiled.
+#[derive(Clone, Copy)]
+struct TryBlock<'a> {
+    handler: bool,
+    finally: Option<&'a [Stmt]>,
+}
+
+/// A function being compiled.
+struct Function<'a> {
+    proto: Proto,
+    names: HashMap<String, u32>,
+    next_register: usize,
+    tries: Vec<TryBlock<'a>>,
+}
+
+impl Function<'_> {
+    fn new(params: Vec<Ident>) -> Self {
+        Function {
+            proto: Proto {
+                params,
+                ..Proto::default()
+            },
+            names: HashMap::new(),
+            next_register: 0,
+            tries: vec![],
+        }
+    }
+
+    /// Returns the index of `name` in the pool of names, adding it if needed.
+    fn name(&mut self, name: &str) -> u32 {
+        if let Some(&index) = self.names.get(name) {
+            return index;
+        }
+
+        let index = self.proto.names.len() as u32;
+        self.proto.names.push(Ident(name.to_string()));
+        self.names.insert(name.to_string(), index);
+        index
+    }
+}
+
+/// Compiles a resolved program to bytecode for the [`Vm`](crate::vm::Vm).
+///
+/// Parameters and locals live in registers at the bottom of each call's stack
+/// window. `catch` scopes get registers of their own after those of the
+/// function, and variables of enclosing functions are reached through upvalues.
+pub struct Compiler<'a> {
+    functions: Vec<Function<'a>>,
+    scopes: Vec<Scope>,
+}
+
+impl<'a> Compiler<'a> {
+    pub fn new() -> Self {
+        Compiler {
+            functions: vec![],
+            scopes: vec![],
+        }
+    }
+
+    /// Compiles a program the resolver has run over.
+    pub fn compile(&mut self, program: &'a Program) -> Rc<Proto> {
+        self.functions.push(Function::new(vec![]));
+        for stmt in program {
+            self.compile_top_stmt(stmt);
+        }
+        self.emit(Op::End);
+
+        let function = self.functions.pop().expect("script is being compiled");
+        Rc::new(function.proto)
+    }
+
+    fn function(&mut self) -> &mut Function<'a> {
+        self.functions
+            .last_mut()
+            .expect("a function is being compiled")
+    }
+
+    fn emit(&mut self, op: Op) -> usize {
+        let code = &mut self.function().proto.code;
+        code.push(op);
+        code.len() - 1
+    }
+
+    /// Points the jump at `at` to the next instruction.
+    fn patch(&mut self, at: usize) {
+        let target = self.function().proto.code.len() as u32;
+        match self.function().proto.code[at] {
+            Op::Jump(ref mut to)
+            | Op::JumpIfFalse(ref mut to)
+            | Op::JumpIfNull(ref mut to)
+            | Op::JumpIfNotNull(ref mut to)
+            | Op::JumpIfNothing(ref mut to)
+            | Op::Propagate(ref mut to)
+            | Op::PushHandler(ref mut to)
+            | Op::GetLocalOr(_, ref mut to)
+            | Op::GetUpvalueOr(_, ref mut to) => *to = target,
+            op => unreachable!("{:?} is not a jump", op),
+        }
+    }
+
+    fn constant(&mut self, value: Object) -> u32 {
+        let constants = &mut self.function().proto.constants;
+        constants.push(value);
+        (constants.len() - 1) as u32
+    }
+
+    fn name(&mut self, name: &str) -> u32 {
+        self.function().name(name)
+    }
+
+    fn site(&mut self, func: &Expr, span: Span) -> u32 {
+        let sites = &mut self.function().proto.sites;
+        sites.push(Frame {
+            function: Evaluator::call_name(func),
+            span,
+        });
+        (sites.len() - 1) as u32
+    }
+
+    /// Opens a scope whose first slots hold `params`, followed by the locals declared in `body`.
+    fn enter_scope(&mut self, params: &[Ident], body: &[Stmt]) -> usize {
+        let mut names: Vec<(String, usize)> = params
+            .iter()
+            .enumerate()
+            .map(|(index, Ident(name))| (name.clone(), index))
+            .collect();
+        Self::declared_block(body, &mut names);
+        let size = names.iter().map(|&(_, slot)| slot + 1).max().unwrap_or(0);
+
+        let function = self.functions.len() - 1;
+        let base = self.function().next_register;
+        self.function().next_register += size;
+
+        let locals = &mut self.function().proto.locals;
+        locals.resize(base + size, Ident(String::new()));
+        for (name, slot) in &names {
+            locals[base + slot] = Ident(name.clone());
+        }
+
+        self.scopes.push(Scope { function, base });
+        base
+    }
+
+    /// Collects the slots declared in `stmts`, in the scope they appear in.
+    fn declared_block(stmts: &[Stmt], names: &mut Vec<(String, usize)>) {
+        for stmt in stmts {
+            Self::declared_stmt(stmt, names);
+        }
+    }
+
+    fn declared_stmt(stmt: &Stmt, names: &mut Vec<(String, usize)>) {
+        match *stmt {
+            Stmt::Local(ref slots, ref stmt) => {
+                let declared: Vec<String> = match **stmt {
+                    Stmt::Let(Ident(ref name), _)
+                    | Stmt::Const(Ident(ref name), _)
+                    | Stmt::Struct(Ident(ref name), _) => vec![name.clone()],
+                    Stmt::Enum(_, ref variants) => {
+                        variants.iter().map(|(Ident(tag), _)| tag.clone()).collect()
+                    }
+                    _ => vec![],
+                };
+                names.extend(declared.into_iter().zip(slots.iter().copied()));
+                Self::declared_stmt(stmt, names);
+            }
+            Stmt::Let(_, ref expr)
+            | Stmt::Const(_, ref expr)
+            | Stmt::Return(ref expr)
+            | Stmt::Throw(ref expr)
+            | Stmt::Expr(ref expr) => Self::declared_expr(expr, names),
+            _ => {}
+        }
+    }
+
+    fn declared_expr(expr: &Expr, names: &mut Vec<(String, usize)>) {
+        match *expr {
+            Expr::Literal(Literal::Array(ref items)) => {
+                for item in items {
+                    Self::declared_expr(item, names);
+                }
+            }
+            Expr::Literal(Literal::Hash(ref pairs)) => {
+                for (key, value) in pairs {
+                    Self::declared_expr(key, names);
+                    Self::declared_expr(value, names);
+                }
+            }
+            Expr::Prefix(_, ref expr)
+            | Expr::Dot(ref expr, _)
+            | Expr::OptionalDot(ref expr, _)
+            | Expr::Propagate(ref expr)
+            | Expr::Method {
+                receiver: ref expr, ..
+            } => Self::declared_expr(expr, names),
+            Expr::Infix(_, ref left, ref right)
+            | Expr::Index(ref left, ref right)
+            | Expr::OptionalIndex(ref left, ref right) => {
+                Self::declared_expr(left, names);
+                Self::declared_expr(right, names);
+            }
+            Expr::If {
+                ref cond,
+                ref consequence,
+                ref alternative,
+            } => {
+                Self::declared_expr(cond, names);
+                Self::declared_block(consequence, names);
+                if let Some(alt) = alternative {
+                    Self::declared_block(alt, names);
+                }
+            }
+            Expr::Call {
+                ref func, ref args, ..
+            } => {
+                Self::declared_expr(func, names);
+                for arg in args {
+                    Self::declared_expr(arg, names);
+                }
+            }
+            Expr::Try {
+                ref body,
+                ref finally,
+                ..
+            } => {
+                Self::declared_block(body, names);
+                if let Some(finally) = finally {
+                    Self::declared_block(finally, names);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Whether `expr` can end up without a value, as `None` in the evaluator.
+    fn may_be_nothing(expr: &Expr) -> bool {
+        match *expr {
+            Expr::Prefix(_, ref expr)
+            | Expr::Dot(ref expr, _)
+            | Expr::OptionalDot(ref expr, _)
+            | Expr::Propagate(ref expr)
+            | Expr::Infix(Infix::NullCoalesce, _, ref expr) => Self::may_be_nothing(expr),
+            Expr::Infix(_, ref left, ref right)
+            | Expr::Index(ref left, ref right)
+            | Expr::OptionalIndex(ref left, ref right) => {
+                Self::may_be_nothing(left) || Self::may_be_nothing(right)
+            }
+            Expr::If {
+                ref cond,
+                ref consequence,
+                ref alternative,
+            } => {
+                Self::may_be_nothing(cond)
+                    || Self::block_may_be_nothing(consequence)
+                    || alternative
+                        .as_ref()
+                        .is_none_or(|alt| Self::block_may_be_nothing(alt))
+            }
+            Expr::Try {
+                ref body,
+                ref catch,
+                ..
+            } => {
+                Self::block_may_be_nothing(body)
+                    || catch
+                        .as_ref()
+                        .is_some_and(|(_, handler)| Self::block_may_be_nothing(handler))
+            }
+            _ => false,
+        }
+    }
+
+    /// Whether the block can end up without a value: it is empty, ends with a
+    /// statement other than an expression, or with an expression that can.
+    fn block_may_be_nothing(stmts: &[Stmt]) -> bool {
+        match stmts.iter().rfind(|stmt| **stmt != Stmt::Blank) {
+            Some(Stmt::Expr(expr)) => Self::may_be_nothing(expr),
+            _ => true,
+        }
+    }
+
+    /// Compiles an expression whose lack of a value counts as null, as in
+    /// arguments and the items of literals.
+    fn compile_value(&mut self, expr: &'a Expr) {
+        self.compile_expr(expr);
+        if Self::may_be_nothing(expr) {
+            self.emit(Op::OrNull);
+        }
+    }
+
+    /// Compiles the left operand of an expression, jumping to a target patched
+    /// later, with the operand kept as the value, if it has none.
+    fn compile_operand(&mut self, expr: &'a Expr) -> Option<usize> {
+        self.compile_expr(expr);
+        Self::may_be_nothing(expr).then(|| self.emit(Op::JumpIfNothing(0)))
+    }
+
+    /// Emits the read of the variable in `slot`, counted from the innermost scope.
+    ///
+    /// With `or_else` set, the read jumps to a target patched later when the
+    /// variable is bound, and otherwise goes on to the code that follows it.
+    fn get_slot(&mut self, slot: Slot, or_else: bool) -> usize {
+        let scope = &self.scopes[self.scopes.len() - 1 - slot.depth];
+        let register = (scope.base + slot.index) as u32;
+        let owner = scope.function;
+
+        let current = self.functions.len() - 1;
+        let op = if owner == current {
+            match or_else {
+                true => Op::GetLocalOr(register, 0),
+                false => Op::GetLocal(register),
+            }
+        } else {
+            let upvalue = self.upvalue(current, owner, register);
+            match or_else {
+                true => Op::GetUpvalueOr(upvalue, 0),
+                false => Op::GetUpvalue(upvalue),
+            }
+        };
+        self.emit(op)
+    }
+
+    /// Returns the upvalue through which `function` reaches `register` of the enclosing `owner`.
+    fn upvalue(&mut self, function: usize, owner: usize, register: u32) -> u32 {
+        let Ident(name) = self.functions[owner].proto.locals[register as usize].clone();
+        let name = self.functions[function].name(&name);
+
+        let desc = if function - 1 == owner {
+            UpvalueDesc {
+                local: true,
+                index: register,
+                name,
+            }
+        } else {
+            UpvalueDesc {
+                local: false,
+                index: self.upvalue(function - 1, owner, register),
+                name,
+            }
+        };
+
+        let upvalues = &mut self.functions[function].proto.upvalues;
+        match upvalues.iter().position(|&up| up == desc) {
+            Some(index) => index as u32,
+            None => {
+                upvalues.push(desc);
+                (upvalues.len() - 1) as u32
+            }
+        }
+    }
+
+    /// Compiles a statement of the program itself, keeping track of the
+    /// value the program evaluates to.
+    fn compile_top_stmt(&mut self, stmt: &'a Stmt) {
+        match *stmt {
+            Stmt::Blank => {}
+            Stmt::Expr(Expr::If {
+                ref cond,
+                ref consequence,
+                ref alternative,
+            }) => {
+                let to_nothing = self.compile_operand(cond);
+                let to_alternative = self.emit(Op::JumpIfFalse(0));
+                self.emit(Op::ClearResult);
+                for stmt in consequence {
+                    self.compile_top_stmt(stmt);
+                }
+                let to_end = self.emit(Op::Jump(0));
+                self.patch(to_alternative);
+                self.emit(Op::ClearResult);
+                for stmt in alternative.iter().flatten() {
+                    self.compile_top_stmt(stmt);
+                }
+                if let Some(to_nothing) = to_nothing {
+                    let to_if_end = self.emit(Op::Jump(0));
+                    self.patch(to_nothing);
+                    self.emit(Op::Pop);
+                    self.emit(Op::ClearResult);
+                    self.patch(to_if_end);
+                }
+                self.patch(to_end);
+            }
+            Stmt::Expr(ref expr) => {
+                self.compile_expr(expr);
+                self.emit(Op::SetResult);
+            }
+            Stmt::Return(_) | Stmt::Throw(_) => self.compile_stmt(stmt),
+            ref stmt => {
+                self.compile_stmt(stmt);
+                self.emit(Op::ClearResult);
+            }
+        }
+    }
+
+    /// Compiles a block, leaving the value of its last statement on the stack.
+    ///
+    /// A block that is empty or ends with a statement other than an
+    /// expression has no value.
+    fn compile_block(&mut self, stmts: &'a [Stmt]) {
+        let mut stmts = stmts.iter().filter(|stmt| **stmt != Stmt::Blank).peekable();
+        if stmts.peek().is_none() {
+            self.emit(Op::Nothing);
+        }
+
+        while let Some(stmt) = stmts.next() {
+            match stmt {
+                Stmt::Expr(expr) => {
+                    self.compile_expr(expr);
+                    if stmts.peek().is_some() {
+                        self.emit(Op::Pop);
+                    }
+                }
+                stmt => {
+                    self.compile_stmt(stmt);
+                    if stmts.peek().is_none() {
+                        self.emit(Op::Nothing);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Compiles a function body, returning from it at the end.
+    ///
+    /// Calls in tail position reuse the caller's stack window.
+    fn compile_tail_block(&mut self, stmts: &'a [Stmt]) {
+        let (last, init) = match stmts.iter().rposition(|stmt| *stmt != Stmt::Blank) {
+            Some(i) => (&stmts[i], &stmts[..i]),
+            None => {
+                self.emit(Op::Null);
+                self.emit(Op::Return);
+                return;
+            }
+        };
+
+        for stmt in init {
+            match stmt {
+                Stmt::Blank => {}
+                Stmt::Expr(expr) => {
+                    self.compile_expr(expr);
+                    self.emit(Op::Pop);
+                }
+                stmt => self.compile_stmt(stmt),
+            }
+        }
+
+        match *last {
+            Stmt::Expr(Expr::Call {
+                ref func,
+                ref args,
+                span,
+            })
+            | Stmt::Return(Expr::Call {
+                ref func,
+                ref args,
+                span,
+            }) => {
+                let mut exits = vec![];
+                self.compile_call(func, args, span, true, &mut exits);
+                for at in exits {
+                    self.patch(at);
+                }
+                self.emit(Op::Return);
+            }
+            Stmt::Expr(Expr::If {
+                ref cond,
+                ref consequence,
+                ref alternative,
+            }) => {
+                let to_nothing = self.compile_operand(cond);
+                let to_alternative = self.emit(Op::JumpIfFalse(0));
+                self.compile_tail_block(consequence);
+                self.patch(to_alternative);
+                match alternative {
+                    Some(alt) => self.compile_tail_block(alt),
+                    None => {
+                        self.emit(Op::Null);
+                        self.emit(Op::Return);
+                    }
+                }
+                if let Some(to_nothing) = to_nothing {
+                    self.patch(to_nothing);
+                    self.emit(Op::Return);
+                }
+            }
+            ref stmt => {
+                self.compile_block(std::slice::from_ref(stmt));
+                self.emit(Op::Return);
+            }
+        }
+    }
+
+    /// Compiles a statement that leaves nothing on the stack.
+    fn compile_stmt(&mut self, stmt: &'a Stmt) {
+        match *stmt {
+            Stmt::Blank => {}
+            Stmt::Expr(ref expr) => {
+                self.compile_expr(expr);
+                self.emit(Op::Pop);
+            }
+            Stmt::Let(Ident(ref name), ref expr) | Stmt::Const(Ident(ref name), ref expr) => {
+                self.compile_expr(expr);
+                let name = self.name(name);
+                self.emit(Op::DeclareGlobal(name, matches!(stmt, Stmt::Const(..))));
+            }
+            Stmt::Struct(..) | Stmt::Enum(..) => {
+                for (name, value) in Self::type_bindings(stmt) {
+                    let value = self.constant(value);
+                    self.emit(Op::Constant(value));
+                    let name = self.name(&name);
+                    self.emit(Op::DeclareGlobal(name, false));
+                }
+            }
+            Stmt::Local(ref slots, ref stmt) => {
+                let base = self.scopes.last().expect("locals are in a scope").base;
+                match **stmt {
+                    Stmt::Let(_, ref expr) | Stmt::Const(_, ref expr) => self.compile_expr(expr),
+                    ref stmt => {
+                        for (_, value) in Self::type_bindings(stmt) {
+                            let value = self.constant(value);
+                            self.emit(Op::Constant(value));
+                        }
+                    }
+                }
+                for slot in slots.iter().rev() {
+                    self.emit(Op::SetLocal((base + slot) as u32));
+                }
+            }
+            Stmt::Return(ref expr) => {
+                // Returning an expression without a value does not return
+                let to_skip = self.compile_operand(expr);
+                self.compile_return();
+                if let Some(to_skip) = to_skip {
+                    self.patch(to_skip);
+                    self.emit(Op::Pop);
+                }
+            }
+            Stmt::Throw(ref expr) => {
+                self.compile_value(expr);
+                self.emit(Op::Throw);
+            }
+        }
+    }
+
+    /// The constructors or values a `struct` or `enum` declaration binds, in order.
+    fn type_bindings(stmt: &Stmt) -> Vec<(String, Object)> {
+        match *stmt {
+            Stmt::Struct(Ident(ref name), ref fields) => {
+                let fields = fields.iter().map(|Ident(field)| field.clone()).collect();
+                vec![(name.clone(), Object::StructCtor(name.clone(), fields))]
+            }
+            Stmt::Enum(Ident(ref name), ref variants) => variants
+                .iter()
+                .map(|(Ident(tag), fields)| {
+                    let fields: Vec<String> = fields.iter().map(|Ident(f)| f.clone()).collect();
+                    let value = if fields.is_empty() {
+                        Object::Variant(name.clone(), tag.clone(), vec![])
+                    } else {
+                        Object::VariantCtor(name.clone(), tag.clone(), fields)
+                    };
+                    (tag.clone(), value)
+                })
+                .collect(),
+            _ => vec![],
+        }
+    }
+
+    /// Returns the value on top of the stack from the current function,
+    /// leaving the `try` blocks around it and running their `finally` blocks.
+    fn compile_return(&mut self) {
+        let tries = self.function().tries.clone();
+        for (i, block) in tries.iter().enumerate().rev() {
+            if block.handler {
+                self.emit(Op::PopHandler);
+            }
+            if let Some(finally) = block.finally {
+                self.function().tries = tries[..i].to_vec();
+                self.compile_block(finally);
+                self.emit(Op::Pop);
+            }
+        }
+        self.function().tries = tries;
+        self.emit(Op::Return);
+    }
+
+    fn compile_expr(&mut self, expr: &'a Expr) {
+        match *expr {
+            Expr::Ident(Ident(ref name)) => {
+                let name = self.name(name);
+                self.emit(Op::GetGlobal(name));
+            }
+            Expr::Method { .. } => {
+                unreachable!("methods are only resolved as the function of a call")
+            }
+            Expr::Local(_, slot, None) => {
+                self.get_slot(slot, false);
+            }
+            Expr::Local(_, slot, Some(ref fallback)) => {
+                let to_end = self.get_slot(slot, true);
+                self.compile_expr(fallback);
+                self.patch(to_end);
+            }
+            Expr::Literal(ref literal) => self.compile_literal(literal),
+            Expr::Prefix(prefix, ref right) => {
+                self.compile_expr(right);
+                self.emit(Op::Prefix(prefix));
+            }
+            Expr::Infix(Infix::NullCoalesce, ref left, ref right) => {
+                self.compile_expr(left);
+                let to_end = self.emit(Op::JumpIfNotNull(0));
+                self.compile_expr(right);
+                self.patch(to_end);
+            }
+            Expr::Infix(infix, ref left, ref right) => {
+                let to_end = self.compile_operand(left);
+                self.compile_expr(right);
+                self.emit(Op::Infix(infix));
+                if let Some(to_end) = to_end {
+                    self.patch(to_end);
+                }
+            }
+            Expr::Index(..)
+            | Expr::Dot(..)
+            | Expr::OptionalIndex(..)
+            | Expr::OptionalDot(..)
+            | Expr::Call { .. } => {
+                let mut exits = vec![];
+                self.compile_link(expr, &mut exits);
+                for at in exits {
+                    self.patch(at);
+                }
+            }
+            Expr::If {
+                ref cond,
+                ref consequence,
+                ref alternative,
+            } => {
+                let to_nothing = self.compile_operand(cond);
+                let to_alternative = self.emit(Op::JumpIfFalse(0));
+                self.compile_block(consequence);
+                let to_end = self.emit(Op::Jump(0));
+                self.patch(to_alternative);
+                match alternative {
+                    Some(alt) => self.compile_block(alt),
+                    None => {
+                        self.emit(Op::Nothing);
+                    }
+                }
+                self.patch(to_end);
+                if let Some(to_nothing) = to_nothing {
+                    self.patch(to_nothing);
+                }
+            }
+            Expr::Func {
+                ref params,
+                ref body,
+            } => self.compile_func(params, body),
+            Expr::Propagate(ref expr) => {
+                self.compile_expr(expr);
+                let to_ok = self.emit(Op::Propagate(0));
+                self.compile_return();
+                self.patch(to_ok);
+            }
+            Expr::Try {
+                ref body,
+                ref catch,
+                ref finally,
+            } => self.compile_try(body, catch.as_ref(), finally.as_deref()),
+        }
+    }
+
+    /// Compiles a member, index or call expression, or any other expression
+    /// that starts a chain of them.
+    ///
+    /// A `?.` or `?[` that meets null adds a jump to `exits`, to be patched at
+    /// the end of the whole chain with the null as its value.
+    fn compile_link(&mut self, expr: &'a Expr, exits: &mut Vec<usize>) {
+        match *expr {
+            Expr::Dot(ref left, Ident(ref field))
+            | Expr::OptionalDot(ref left, Ident(ref field)) => {
+                self.compile_link(left, exits);
+                if let Expr::OptionalDot(..) = *expr {
+                    exits.push(self.emit(Op::JumpIfNull(0)));
+                }
+                let field = self.name(field);
+                self.emit(Op::Field(field));
+            }
+            Expr::Index(ref left, ref index) | Expr::OptionalIndex(ref left, ref index) => {
+                self.compile_link(left, exits);
+                let to_end = Self::may_be_nothing(left).then(|| self.emit(Op::JumpIfNothing(0)));
+                if let Expr::OptionalIndex(..) = *expr {
+                    exits.push(self.emit(Op::JumpIfNull(0)));
+                }
+                self.compile_expr(index);
+                self.emit(Op::Index);
+                if let Some(to_end) = to_end {
+                    self.patch(to_end);
+                }
+            }
+            Expr::Call {
+                ref func,
+                ref args,
+                span,
+            } => self.compile_call(func, args, span, false, exits),
+            ref expr => self.compile_expr(expr),
+        }
+    }
+
+    fn compile_literal(&mut self, literal: &'a Literal) {
+        match *literal {
+            Literal::Null => {
+                self.emit(Op::Null);
+            }
+            Literal::Array(ref items) => {
+                for item in items {
+                    self.compile_value(item);
+                }
+                self.emit(Op::Array(items.len() as u32));
+            }
+            Literal::Hash(ref pairs) => {
+                for (key, value) in pairs {
+                    self.compile_value(key);
+                    self.compile_value(value);
+                }
+                self.emit(Op::Hash(pairs.len() as u32));
+            }
+            Literal::Int(value) => {
+                let value = self.constant(Object::Int(value));
+                self.emit(Op::Constant(value));
+            }
+            Literal::Bool(value) => {
+                let value = self.constant(Object::Bool(value));
+                self.emit(Op::Constant(value));
+            }
+            Literal::String(ref value) => {
+                let value = self.constant(Object::String(value.clone()));
+                self.emit(Op::Constant(value));
+            }
+        }
+    }
+
+    fn compile_func(&mut self, params: &'a [Ident], body: &'a Rc<BlockStmt>) {
+        self.functions.push(Function::new(params.to_vec()));
+        self.function().proto.body = Rc::clone(body);
+        self.enter_scope(params, body);
+        self.compile_tail_block(body);
+        self.scopes.pop();
+
+        let function = self.functions.pop().expect("function is being compiled");
+        let protos = &mut self.function().proto.protos;
+        protos.push(Rc::new(function.proto));
+        let index = (protos.len() - 1) as u32;
+        self.emit(Op::Closure(index));
+    }
+
+    /// Compiles a call, evaluating the arguments before the function like the evaluator.
+    ///
+    /// The call is a link of a chain whose `?.` and `?[` jump to `exits`.
+    fn compile_call(
+        &mut self,
+        func: &'a Expr,
+        args: &'a [Expr],
+        span: Span,
+        tail: bool,
+        exits: &mut Vec<usize>,
+    ) {
+        let site = self.site(func, span);
+        let argc = args.len() as u32;
+
+        match *func {
+            Expr::Method {
+                ref receiver,
+                name: Ident(ref method),
+                optional,
+                ref var,
+            } => {
+                self.compile_link(receiver, exits);
+                if Self::may_be_nothing(receiver) {
+                    self.emit(Op::OrNull);
+                }
+                if optional {
+                    exits.push(self.emit(Op::JumpIfNull(0)));
+                }
+
+                for arg in args {
+                    self.compile_value(arg);
+                }
+                let name = self.name(method);
+                let invoke = |local| Op::Invoke {
+                    name,
+                    argc,
+                    site,
+                    local,
+                    tail,
+                };
+
+                // Push the local the method names, if one is bound, or look
+                // the method up in the globals
+                let mut to_local = vec![];
+                let mut var = &**var;
+                let local = loop {
+                    match *var {
+                        Expr::Local(_, slot, Some(ref fallback)) => {
+                            to_local.push(self.get_slot(slot, true));
+                            var = fallback;
+                        }
+                        Expr::Local(_, slot, None) => {
+                            self.get_slot(slot, false);
+                            break true;
+                        }
+                        _ => break false,
+                    }
+                };
+                if !local {
+                    self.emit(invoke(false));
+                }
+                if local || !to_local.is_empty() {
+                    let to_invoked = (!local).then(|| self.emit(Op::Jump(0)));
+                    for at in to_local {
+                        self.patch(at);
+                    }
+                    self.emit(invoke(true));
+                    if let Some(to_invoked) = to_invoked {
+                        self.patch(to_invoked);
+                    }
+                }
+            }
+            ref func => {
+                for arg in args {
+                    self.compile_value(arg);
+                }
+                let mut skips = vec![];
+                self.compile_link(func, &mut skips);
+                self.emit(Op::Call { argc, site, tail });
+
+                // The arguments are already on the stack when the function
+                // turns out to be skipped, so drop them before leaving the chain
+                if !skips.is_empty() {
+                    let to_called = self.emit(Op::Jump(0));
+                    for at in skips {
+                        self.patch(at);
+                    }
+                    for _ in 0..=argc {
+                        self.emit(Op::Pop);
+                    }
+                    self.emit(Op::Null);
+                    exits.push(self.emit(Op::Jump(0)));
+                    self.patch(to_called);
+                }
+            }
+        }
+    }
+
+    /// Compiles `try`, leaving the value of the body or the handler on the stack.
+    ///
+    /// An error that is not caught stays on the stack while `finally` runs and
+    /// is raised again afterwards.
+    fn compile_try(
+        &mut self,
+        body: &'a [Stmt],
+        catch: Option<&'a (Ident, BlockStmt)>,
+        finally: Option<&'a [Stmt]>,
+    ) {
+        let to_catch = self.emit(Op::PushHandler(0));
+        self.function().tries.push(TryBlock {
+            handler: true,
+            finally,
+        });
+        self.compile_block(body);
+        self.function().tries.pop();
+        self.emit(Op::PopHandler);
+        let to_finally = self.emit(Op::Jump(0));
+
+        self.patch(to_catch);
+        if let Some((name, handler)) = catch {
+            let to_handler_finally = finally.map(|_| self.emit(Op::PushHandler(0)));
+            self.function().tries.push(TryBlock {
+                handler: finally.is_some(),
+                finally,
+            });
+
+            self.emit(Op::CatchError);
+            let base = self.enter_scope(std::slice::from_ref(name), handler);
+            self.emit(Op::SetLocal(base as u32));
+            self.compile_block(handler);
+            self.scopes.pop();
+
+            self.function().tries.pop();
+            if to_handler_finally.is_some() {
+                self.emit(Op::PopHandler);
+            }
+            self.patch(to_finally);
+            if let Some(to_handler_finally) = to_handler_finally {
+                self.patch(to_handler_finally);
+            }
+        } else {
+            self.patch(to_finally);
+        }
+
+        if let Some(finally) = finally {
+            self.compile_block(finally);
+            self.emit(Op::Pop);
+        }
+        if catch.is_none() || finally.is_some() {
+            self.emit(Op::Rethrow);
+        }
+    }
+}
+
+impl Default for Compiler<'_> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::lexer::Lexer;
+    use crate::parser::Parser;
+    use crate::resolver::Resolver;
+    use crate::vm::compiler::*;
+    use std::collections::HashSet;
+
+    fn compile(input: &str) -> Rc<Proto> {
+        let program = Parser::new(Lexer::new(input)).parse();
+        let mut resolver = Resolver::new(HashSet::new());
+        let program = resolver.resolve(program);
+        assert!(resolver.get_errors().is_empty());
+        Compiler::new().compile(&program)
+    }
+
+    #[test]
+    fn test_compile_function() {
+        let script = compile("let add = fun(a, b) { let c = a + b; c }; add(1, 2);");
+
+        assert_eq!(
+            vec![
+                Op::Closure(0),
+                Op::DeclareGlobal(0, false),
+                Op::ClearResult,
+                Op::Constant(0),
+                Op::Constant(1),
+                Op::GetGlobal(0),
+                Op::Call {
+                    argc: 2,
+                    site: 0,
+                    tail: false,
+                },
+                Op::SetResult,
+                Op::End,
+            ],
+            script.code
+        );
+
+        let add = &script.protos[0];
+        assert_eq!(
+            vec![
+                Op::GetLocal(0),
+                Op::GetLocal(1),
+                Op::Infix(Infix::Plus),
+                Op::SetLocal(2),
+                Op::GetLocal(2),
+                Op::Return,
+            ],
+            add.code
+        );
+        assert_eq!(
+            vec![
+                Ident(String::from("a")),
+                Ident(String::from("b")),
+                Ident(String::from("c")),
+            ],
+            add.locals
+        );
+    }
+
+    #[test]
+    fn test_compile_upvalues() {
+        let script = compile("fun(a) { fun() { fun() { a } } }");
+
+        let inner = &script.protos[0].protos[0];
+        assert_eq!(
+            vec![UpvalueDesc {
+                local: true,
+                index: 0,
+                name: 0,
+            }],
+            inner.upvalues
+        );
+        let innermost = &inner.protos[0];
+        assert_eq!(
+            vec![UpvalueDesc {
+                local: false,
+                index: 0,
+                name: 0,
+            }],
+            innermost.upvalues
+        );
+        assert_eq!(vec![Op::GetUpvalue(0), Op::Return], innermost.code);
+    }
+}
@@ -0,0 +1,915 @@
+// Copyright 2024 Dimitrios Papakonstantinou. All rights reserved.
+// Use of this source code is governed by a MIT
+// license that can be found in the LICENSE file
+
+//! A bytecode compiler and stack-based virtual machine.
+//!
+//! This is an alternative to the tree-walking [`Evaluator`], which remains
+//! the reference for how programs behave. Both share the object model, the
+//! builtins and the global [`Env`].
+
+pub mod code;
+pub mod compiler;
+
+use crate::ast::*;
+use crate::evaluator::budget::{Budget, InterruptHandle, Limits};
+use crate::evaluator::env::Env;
+use crate::evaluator::error::*;
+use crate::evaluator::object::Object;
+use crate::evaluator::{grow_stack, Evaluator, Interp, DEFAULT_MAX_DEPTH};
+use crate::gc;
+use crate::resolver::Resolver;
+use crate::vm::code::*;
+use crate::vm::compiler::Compiler;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+/// Value of a local variable whose declaration has not run yet.
+///
+/// Errors are raised rather than stored, so no variable holds one otherwise.
+const UNSET: Object = Object::Error(RuntimeError {
+    kind: ErrorKind::NameError,
+    message: String::new(),
+    stack: Vec::new(),
+});
+
+/// Value of an expression that has none, where the evaluator gives `None`.
+///
+/// Enums always have a name, so no value a script makes is equal to it.
+const NOTHING: Object = Object::Variant(String::new(), String::new(), Vec::new());
+
+fn is_nothing(value: &Object) -> bool {
+    matches!(*value, Object::Variant(ref name, ..) if name.is_empty())
+}
+
+/// A running function call.
+#[derive(Debug)]
+struct CallFrame {
+    closure: Rc<Closure>,
+    ip: usize,
+    /// Start of the call's registers on the stack.
+    base: usize,
+    /// Call site in the calling function, if the call came from bytecode.
+    site: Option<u32>,
+    /// Latest call in tail position that replaced this call's function.
+    tail_site: Option<(Rc<Proto>, u32)>,
+}
+
+/// An active `try` block.
+#[derive(Debug)]
+struct Handler {
+    /// Number of frames when the handler was pushed.
+    frames: usize,
+    stack: usize,
+    target: usize,
+}
+
+#[derive(Debug)]
+pub struct Vm {
+    globals: Rc<RefCell<Env>>,
+    stack: Vec<Object>,
+    frames: Vec<CallFrame>,
+    handlers: Vec<Handler>,
+    /// Upvalues still pointing into the stack, by stack index.
+    open_upvalues: Vec<(usize, Rc<RefCell<Upvalue>>)>,
+    result: Option<Object>,
+    max_depth: usize,
+    budget: Budget,
+    interrupt: InterruptHandle,
+}
+
+impl Vm {
+    pub fn new(globals: Rc<RefCell<Env>>) -> Self {
+        Vm {
+            globals,
+            stack: vec![],
+            frames: vec![],
+            handlers: vec![],
+            open_upvalues: vec![],
+            result: None,
+            max_depth: DEFAULT_MAX_DEPTH,
+            budget: Budget::default(),
+            interrupt: InterruptHandle::new(),
+        }
+    }
+
+    /// Sets how deeply calls may nest before a `RecursionError` is raised.
+    pub fn set_max_depth(&mut self, max_depth: usize) {
+        self.max_depth = max_depth;
+    }
+
+    /// Starts a new run under `limits`, as [`Evaluator::set_limits`] does.
+    pub fn set_limits(&mut self, limits: &Limits) {
+        self.budget = Budget::new(limits);
+    }
+
+    /// Makes calls stop with an `InterruptError` once `interrupt` is triggered.
+    pub fn set_interrupt(&mut self, interrupt: InterruptHandle) {
+        self.interrupt = interrupt;
+    }
+
+    /// Compiles and runs a program, returning what [`Evaluator::eval`] would.
+    pub fn eval(&mut self, program: Program) -> Option<Object> {
+        let mut resolver = Resolver::new(self.globals.borrow().global_names());
+        let program = resolver.resolve(program);
+        if let Some(err) = resolver.get_errors().into_iter().next() {
+            return Some(Object::Error(err));
+        }
+
+        let proto = Compiler::new().compile(&program);
+        let script = Rc::new(Closure {
+            proto,
+            upvalues: vec![],
+            globals: Rc::clone(&self.globals),
+        });
+
+        self.result = None;
+        let entry = self.frames.len();
+        self.push_frame(script, self.stack.len(), None);
+        match self.run(entry) {
+            Ok(result) => result,
+            Err(err) => Some(Object::Error(err)),
+        }
+    }
+
+    /// Calls a closure from outside the VM, as higher-order builtins do.
+    pub fn call(&mut self, closure: &Rc<Closure>, args: Vec<Object>) -> Object {
+        if let Err(err) = self.interrupt.check() {
+            return Object::Error(err);
+        }
+
+        if closure.proto.params.len() != args.len() {
+            return Object::error(
+                ErrorKind::ArityError,
+                format!(
+                    "wrong number of arguments: {} expected but {} given",
+                    closure.proto.params.len(),
+                    args.len()
+                ),
+            );
+        }
+
+        let entry = self.frames.len();
+        let base = self.stack.len();
+        self.stack.extend(args);
+        self.push_frame(Rc::clone(closure), base, None);
+        match self.run(entry) {
+            Ok(result) => result.unwrap_or(Object::Null),
+            Err(err) => Object::Error(err),
+        }
+    }
+
+    fn push_frame(&mut self, closure: Rc<Closure>, base: usize, site: Option<u32>) {
+        self.stack.resize(base + closure.proto.locals.len(), UNSET);
+        self.frames.push(CallFrame {
+            closure,
+            ip: 0,
+            base,
+            site,
+            tail_site: None,
+        });
+    }
+
+    fn frame(&self) -> &CallFrame {
+        self.frames.last().expect("a call is running")
+    }
+
+    fn proto(&self) -> &Proto {
+        &self.frame().closure.proto
+    }
+
+    fn pop(&mut self) -> Object {
+        self.stack.pop().expect("stack holds an operand")
+    }
+
+    fn top(&self) -> &Object {
+        self.stack.last().expect("stack holds an operand")
+    }
+
+    fn jump(&mut self, target: u32) {
+        self.frames.last_mut().expect("a call is running").ip = target as usize;
+    }
+
+    /// Runs until the call made at `entry` frames returns, or the program ends.
+    fn run(&mut self, entry: usize) -> Result<Option<Object>, RuntimeError> {
+        loop {
+            if let Err(err) = self.budget.step() {
+                self.unwind(err, entry)?;
+                continue;
+            }
+
+            let frame = self.frames.last_mut().expect("a call is running");
+            let op = frame.closure.proto.code[frame.ip];
+            let base = frame.base;
+            frame.ip += 1;
+
+            let outcome = match op {
+                Op::Constant(index) => {
+                    let value = self.proto().constants[index as usize].clone();
+                    self.stack.push(value);
+                    Ok(())
+                }
+                Op::Null => {
+                    self.stack.push(Object::Null);
+                    Ok(())
+                }
+                Op::Nothing => {
+                    self.stack.push(NOTHING);
+                    Ok(())
+                }
+                Op::OrNull => {
+                    if is_nothing(self.top()) {
+                        *self.stack.last_mut().expect("stack holds an operand") = Object::Null;
+                    }
+                    Ok(())
+                }
+                Op::Pop => {
+                    self.pop();
+                    Ok(())
+                }
+                Op::GetLocal(register) => match self.stack[base + register as usize] {
+                    Object::Error(_) => {
+                        let Ident(ref name) = self.proto().locals[register as usize];
+                        Err(Self::unset_error(name))
+                    }
+                    ref value => {
+                        let value = value.clone();
+                        self.stack.push(value);
+                        Ok(())
+                    }
+                },
+                Op::GetLocalOr(register, target) => {
+                    match self.stack[base + register as usize] {
+                        Object::Error(_) => {}
+                        ref value => {
+                            let value = value.clone();
+                            self.stack.push(value);
+                            self.jump(target);
+                        }
+                    }
+                    Ok(())
+                }
+                Op::SetLocal(register) => {
+                    // A declaration without a value binds nothing
+                    let value = self.pop();
+                    if !is_nothing(&value) {
+                        self.stack[base + register as usize] = value;
+                    }
+                    Ok(())
+                }
+                Op::GetUpvalue(index) => match self.upvalue(index) {
+                    Object::Error(_) => {
+                        let proto = self.proto();
+                        let name = proto.upvalues[index as usize].name;
+                        Err(Self::unset_error(&proto.names[name as usize].0))
+                    }
+                    value => {
+                        self.stack.push(value);
+                        Ok(())
+                    }
+                },
+                Op::GetUpvalueOr(index, target) => {
+                    match self.upvalue(index) {
+                        Object::Error(_) => {}
+                        value => {
+                            self.stack.push(value);
+                            self.jump(target);
+                        }
+                    }
+                    Ok(())
+                }
+                Op::GetGlobal(name) => {
+                    let Ident(ref name) = self.proto().names[name as usize];
+                    let value = self.globals.borrow().get_global(name);
+                    match value {
+                        Some(value) => {
+                            self.stack.push(value);
+                            Ok(())
+                        }
+                        None => Err(RuntimeError::new(
+                            ErrorKind::NameError,
+                            format!("identifier not found: {}", name),
+                        )),
+                    }
+                }
+                Op::DeclareGlobal(name, constant) => {
+                    let value = self.pop();
+                    if is_nothing(&value) {
+                        continue;
+                    }
+                    let Ident(ref name) = self.proto().names[name as usize];
+                    let declared =
+                        self.globals
+                            .borrow_mut()
+                            .declare(name.clone(), &value, constant);
+                    declared.map_err(|msg| RuntimeError::new(ErrorKind::NameError, msg))
+                }
+                Op::Prefix(prefix) => {
+                    let right = self.pop();
+                    if is_nothing(&right) {
+                        self.stack.push(right);
+                        continue;
+                    }
+                    self.push_result(Evaluator::eval_prefix_expr(prefix, right))
+                }
+                Op::Infix(infix) => {
+                    let right = self.pop();
+                    // Integers are worked on in place, as most arithmetic is on them
+                    if let (&Object::Int(left), &Object::Int(right)) = (self.top(), &right) {
+                        let value = Evaluator::eval_infix_int_expr(infix, left, right);
+                        *self.stack.last_mut().expect("stack holds an operand") = value;
+                        Ok(())
+                    } else {
+                        let left = self.pop();
+                        if is_nothing(&right) {
+                            self.stack.push(right);
+                            continue;
+                        }
+                        let value = Evaluator::eval_infix_expr(infix, left, right);
+                        self.budget
+                            .allocate(&value)
+                            .and_then(|()| self.push_result(value))
+                    }
+                }
+                Op::Index => {
+                    let index = self.pop();
+                    let left = self.pop();
+                    if is_nothing(&index) {
+                        self.stack.push(index);
+                        continue;
+                    }
+                    self.push_result(Evaluator::eval_index_expr(left, index))
+                }
+                Op::Field(name) => {
+                    let left = self.pop();
+                    if is_nothing(&left) {
+                        self.stack.push(left);
+                        continue;
+                    }
+                    let value = Evaluator::eval_dot_expr(left, &self.proto().names[name as usize]);
+                    self.push_result(value)
+                }
+                Op::Array(len) => {
+                    let items = self.stack.split_off(self.stack.len() - len as usize);
+                    let array = Object::Array(Rc::new(items));
+                    self.budget
+                        .allocate(&array)
+                        .map(|()| self.stack.push(array))
+                }
+                Op::Hash(len) => {
+                    let items = self.stack.split_off(self.stack.len() - 2 * len as usize);
+                    #[allow(clippy::mutable_key_type)]
+                    let mut hash = HashMap::new();
+                    let mut items = items.into_iter();
+                    while let (Some(key), Some(value)) = (items.next(), items.next()) {
+                        hash.insert(key, value);
+                    }
+                    let hash = Object::Hash(Rc::new(hash));
+                    self.budget.allocate(&hash).map(|()| self.stack.push(hash))
+                }
+                Op::Jump(target) => {
+                    self.jump(target);
+                    Ok(())
+                }
+                Op::JumpIfFalse(target) => {
+                    if !Evaluator::is_truthy(self.pop()) {
+                        self.jump(target);
+                    }
+                    Ok(())
+                }
+                Op::JumpIfNull(target) => {
+                    if *self.top() == Object::Null {
+                        self.jump(target);
+                    }
+                    Ok(())
+                }
+                Op::JumpIfNotNull(target) => {
+                    if *self.top() == Object::Null || is_nothing(self.top()) {
+                        self.pop();
+                    } else {
+                        self.jump(target);
+                    }
+                    Ok(())
+                }
+                Op::JumpIfNothing(target) => {
+                    if is_nothing(self.top()) {
+                        self.jump(target);
+                    }
+                    Ok(())
+                }
+                Op::Closure(index) => {
+                    self.make_closure(index as usize, base);
+                    Ok(())
+                }
+                Op::Call { argc, site, tail } => {
+                    let func = self.pop();
+                    self.call_value(func, argc as usize, site, tail)
+                }
+                Op::Invoke {
+                    name,
+                    argc,
+                    site,
+                    local,
+                    tail,
+                } => self.invoke(name, argc as usize, site, local, tail),
+                Op::Return => {
+                    let value = match self.pop() {
+                        value if is_nothing(&value) => Object::Null,
+                        value => value,
+                    };
+                    if let Some(value) = self.return_value(value, entry) {
+                        return Ok(Some(value));
+                    }
+                    Ok(())
+                }
+                Op::Propagate(target) => {
+                    let result = self.pop();
+                    if is_nothing(&result) {
+                        self.stack.push(result);
+                        self.jump(target);
+                        continue;
+                    }
+                    match Evaluator::eval_propagate_expr(result) {
+                        Object::ReturnValue(err) => {
+                            self.stack.push(*err);
+                            Ok(())
+                        }
+                        Object::Error(err) => Err(err),
+                        value => {
+                            self.stack.push(value);
+                            self.jump(target);
+                            Ok(())
+                        }
+                    }
+                }
+                Op::Throw => {
+                    let value = self.pop();
+                    Err(Evaluator::thrown_error(value))
+                }
+                Op::PushHandler(target) => {
+                    self.handlers.push(Handler {
+                        frames: self.frames.len(),
+                        stack: self.stack.len(),
+                        target: target as usize,
+                    });
+                    Ok(())
+                }
+                Op::PopHandler => {
+                    self.handlers.pop();
+                    Ok(())
+                }
+                Op::CatchError => {
+                    let value = match self.pop() {
+                        Object::Error(err) => Evaluator::error_value(&err),
+                        o => o,
+                    };
+                    self.stack.push(value);
+                    Ok(())
+                }
+                Op::Rethrow => match self.pop() {
+                    Object::Error(err) => Err(err),
+                    value => {
+                        self.stack.push(value);
+                        Ok(())
+                    }
+                },
+                Op::SetResult => {
+                    let value = self.pop();
+                    self.result = (!is_nothing(&value)).then_some(value);
+                    Ok(())
+                }
+                Op::ClearResult => {
+                    self.result = None;
+                    Ok(())
+                }
+                Op::End => {
+                    let frame = self.frames.pop().expect("script is running");
+                    self.stack.truncate(frame.base);
+                    return Ok(self.result.take());
+                }
+            };
+
+            if let Err(err) = outcome {
+                self.unwind(err, entry)?;
+            }
+        }
+    }
+
+    /// The value of an upvalue of the running closure, `UNSET` if its declaration has not run yet.
+    fn upvalue(&self, index: u32) -> Object {
+        match *self.frame().closure.upvalues[index as usize].borrow() {
+            Upvalue::Open(slot) => self.stack[slot].clone(),
+            Upvalue::Closed(ref value) => value.clone(),
+        }
+    }
+
+    fn unset_error(name: &str) -> RuntimeError {
+        RuntimeError::new(
+            ErrorKind::NameError,
+            format!("identifier not found: {}", name),
+        )
+    }
+
+    /// Pushes the value of an operation, raising it instead if it is an error.
+    fn push_result(&mut self, value: Object) -> Result<(), RuntimeError> {
+        match value {
+            Object::Error(err) => Err(err),
+            value => {
+                self.stack.push(value);
+                Ok(())
+            }
+        }
+    }
+
+    fn make_closure(&mut self, index: usize, base: usize) {
+        let closure = Rc::clone(&self.frame().closure);
+        let proto = Rc::clone(&closure.proto.protos[index]);
+        let upvalues = proto
+            .upvalues
+            .iter()
+            .map(|desc| match desc.local {
+                true => self.capture(base + desc.index as usize),
+                false => Rc::clone(&closure.upvalues[desc.index as usize]),
+            })
+            .collect();
+
+        self.stack.push(Object::Closure(Rc::new(Closure {
+            proto,
+            upvalues,
+            globals: Rc::clone(&self.globals),
+        })));
+    }
+
+    /// Returns the upvalue for a stack slot, sharing it with other closures over the same slot.
+    fn capture(&mut self, slot: usize) -> Rc<RefCell<Upvalue>> {
+        let position = self.open_upvalues.partition_point(|&(open, _)| open < slot);
+        match self.open_upvalues.get(position) {
+            Some((open, upvalue)) if *open == slot => Rc::clone(upvalue),
+            _ => {
+                let upvalue = Rc::new(RefCell::new(Upvalue::Open(slot)));
+                gc::track_upvalue(&upvalue);
+                self.open_upvalues
+                    .insert(position, (slot, Rc::clone(&upvalue)));
+                upvalue
+            }
+        }
+    }
+
+    /// Moves the values of upvalues at or above `base` off the stack.
+    fn close_upvalues(&mut self, base: usize) {
+        while let Some((slot, _)) = self.open_upvalues.last() {
+            if *slot < base {
+                break;
+            }
+            let (slot, upvalue) = self.open_upvalues.pop().expect("upvalue is open");
+            *upvalue.borrow_mut() = Upvalue::Closed(self.stack[slot].clone());
+        }
+    }
+
+    /// Ends the running call, handing `value` to the caller.
+    ///
+    /// Returns the value instead once the call made at `entry` frames is done.
+    fn return_value(&mut self, value: Object, entry: usize) -> Option<Object> {
+        let frame = self.frames.pop().expect("a call is running");
+        self.close_upvalues(frame.base);
+        self.stack.truncate(frame.base);
+
+        if self.frames.len() == entry {
+            return Some(value);
+        }
+        self.stack.push(value);
+        None
+    }
+
+    fn depth(&self) -> usize {
+        self.frames.len() - 1
+    }
+
+    fn site(&self, site: u32) -> Frame {
+        self.proto().sites[site as usize].clone()
+    }
+
+    /// Calls `func` with the top `argc` values of the stack as arguments.
+    ///
+    /// A function called in tail position takes over the running call. Other
+    /// callables are applied right away and leave their value on the stack.
+    fn call_value(
+        &mut self,
+        func: Object,
+        argc: usize,
+        site: u32,
+        tail: bool,
+    ) -> Result<(), RuntimeError> {
+        self.interrupt.check()?;
+        let closure = match func {
+            Object::Closure(closure) => closure,
+            // Calling something without a value gives null, once the arguments are evaluated
+            func if is_nothing(&func) => {
+                self.stack.truncate(self.stack.len() - argc);
+                self.stack.push(Object::Null);
+                return Ok(());
+            }
+            func => {
+                self.check_depth(site)?;
+                let args = self.stack.split_off(self.stack.len() - argc);
+                // Higher-order builtins call back into `run`, recursing on the native stack
+                return match grow_stack(|| Evaluator::apply_native(self, func, args)) {
+                    Object::Error(mut err) => {
+                        err.stack.push(self.site(site));
+                        Err(err)
+                    }
+                    value => {
+                        self.budget.allocate(&value)?;
+                        self.stack.push(value);
+                        Ok(())
+                    }
+                };
+            }
+        };
+
+        if !tail {
+            self.check_depth(site)?;
+        }
+
+        let params = closure.proto.params.len();
+        if params != argc {
+            let mut err = RuntimeError::new(
+                ErrorKind::ArityError,
+                format!(
+                    "wrong number of arguments: {} expected but {} given",
+                    params, argc
+                ),
+            );
+            if tail {
+                let proto = Rc::clone(&self.frame().closure.proto);
+                self.frames.last_mut().expect("a call is running").tail_site = Some((proto, site));
+            } else {
+                err.stack.push(self.site(site));
+            }
+            return Err(err);
+        }
+
+        let base = self.stack.len() - argc;
+        if !tail {
+            self.push_frame(closure, base, Some(site));
+            return Ok(());
+        }
+
+        let frame_base = self.frame().base;
+        self.close_upvalues(frame_base);
+        self.stack.drain(frame_base..base);
+        self.stack
+            .resize(frame_base + closure.proto.locals.len(), UNSET);
+
+        let frame = self.frames.last_mut().expect("a call is running");
+        let caller = std::mem::replace(&mut frame.closure, closure);
+        frame.tail_site = Some((Rc::clone(&caller.proto), site));
+        frame.ip = 0;
+        Ok(())
+    }
+
+    fn check_depth(&self, site: u32) -> Result<(), RuntimeError> {
+        if self.depth() < self.max_depth {
+            return Ok(());
+        }
+
+        Err(RuntimeError {
+            kind: ErrorKind::RecursionError,
+            message: String::from("maximum recursion depth exceeded"),
+            stack: vec![self.site(site)],
+        })
+    }
+
+    /// Calls `receiver.name(args)` the way [`Evaluator`] resolves methods.
+    fn invoke(
+        &mut self,
+        name: u32,
+        argc: usize,
+        site: u32,
+        local: bool,
+        tail: bool,
+    ) -> Result<(), RuntimeError> {
+        let fallback = if local { Some(self.pop()) } else { None };
+        let receiver_index = self.stack.len() - argc - 1;
+        let Ident(name) = self.proto().names[name as usize].clone();
+
+        let method = match self.stack[receiver_index] {
+            Object::Hash(ref hash) => hash.get(&Object::String(name.clone())).cloned(),
+            ref receiver @ (Object::Struct(..) | Object::Variant(..)) => {
+                receiver.get_field(&name).cloned()
+            }
+            _ => None,
+        };
+        if let Some(func) = method {
+            self.stack.remove(receiver_index);
+            return self.call_value(func, argc, site, tail);
+        }
+
+        let func = match fallback {
+            Some(func) => func,
+            None => match self.globals.borrow().get_global(&name) {
+                Some(func) => func,
+                None => {
+                    return Err(RuntimeError::new(
+                        ErrorKind::NameError,
+                        format!("unknown method: {}", name),
+                    ))
+                }
+            },
+        };
+        self.call_value(func, argc + 1, site, tail)
+    }
+
+    /// Unwinds to the innermost `try` block, adding the calls left to the error's stack.
+    ///
+    /// Fails with the error once the call made at `entry` frames is left.
+    fn unwind(&mut self, mut err: RuntimeError, entry: usize) -> Result<(), RuntimeError> {
+        loop {
+            if let Some(handler) = self.handlers.last() {
+                if handler.frames == self.frames.len() {
+                    let handler = self.handlers.pop().expect("handler is active");
+                    // Running out of a budget or being interrupted skips `catch` and `finally` blocks
+                    if err.kind.is_fatal() {
+                        continue;
+                    }
+                    self.stack.truncate(handler.stack);
+                    self.stack.push(Object::Error(err));
+                    self.frames.last_mut().expect("a call is running").ip = handler.target;
+                    return Ok(());
+                }
+            }
+
+            let frame = self.frames.pop().expect("a call is running");
+            self.close_upvalues(frame.base);
+            self.stack.truncate(frame.base);
+            if let Some((proto, site)) = frame.tail_site {
+                err.stack.push(proto.sites[site as usize].clone());
+            }
+
+            if self.frames.len() == entry {
+                return Err(err);
+            }
+            if let Some(site) = frame.site {
+                err.stack.push(self.site(site));
+            }
+        }
+    }
+}
+
+impl Interp for Vm {
+    fn apply(&mut self, func: Object, args: Vec<Object>) -> Object {
+        match func {
+            Object::Closure(closure) => self.call(&closure, args),
+            func => Evaluator::apply_native(self, func, args),
+        }
+    }
+
+    fn get_global(&self, name: &str) -> Option<Object> {
+        self.globals.borrow().get_global(name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::evaluator::builtins::new_builtins;
+    use crate::lexer::Lexer;
+    use crate::parser::Parser;
+    use crate::vm::*;
+
+    fn new_vm() -> Vm {
+        Vm::new(Rc::new(RefCell::new(Env::from(new_builtins()))))
+    }
+
+    fn eval(vm: &mut Vm, input: &str) -> Option<Object> {
+        vm.eval(Parser::new(Lexer::new(input)).parse())
+    }
+
+    #[test]
+    fn test_upvalues() {
+        let input = r#"
+let pair = fun(x) {
+  let get = fun() { x };
+  let twice = fun() { get() + x };
+  [get, twice]
+};
+let p = pair(21);
+let outer = fun(a) { fun(b) { fun(c) { a + b + c } } };
+[p[0](), p[1](), outer(1)(2)(3)]
+        "#;
+
+        assert_eq!(
+            Some(Object::Array(Rc::new(vec![
+                Object::Int(21),
+                Object::Int(42),
+                Object::Int(6),
+            ]))),
+            eval(&mut new_vm(), input)
+        );
+    }
+
+    #[test]
+    fn test_shadowed_locals() {
+        let tests = vec![
+            (
+                "let x = 1; let f = fun() { let x = x + 1; x }; f()",
+                Object::Int(2),
+            ),
+            (
+                "let x = 1; let g = fun() { if (false) { let x = 3; } x }; g()",
+                Object::Int(1),
+            ),
+            (
+                "let f = fun(x) { fun(c) { if (c) { let x = 3; } x } }; [f(1)(false), f(1)(true)]",
+                Object::Array(Rc::new(vec![Object::Int(1), Object::Int(3)])),
+            ),
+            (
+                "let f = fun() { let xs = [1, 2]; if (false) { let len = 0; } xs.len() }; f()",
+                Object::Int(2),
+            ),
+            (
+                "let f = fun(len) { let xs = [1, 2]; if (false) { let len = 0; } xs.len() }; f(fun(xs) { 5 })",
+                Object::Int(5),
+            ),
+        ];
+
+        for (input, expect) in tests {
+            assert_eq!(Some(expect), eval(&mut new_vm(), input), "{}", input);
+        }
+    }
+
+    #[test]
+    fn test_globals_persist() {
+        let mut vm = new_vm();
+
+        assert_eq!(None, eval(&mut vm, "let double = fun(x) { x * 2 };"));
+        assert_eq!(Some(Object::Int(8)), eval(&mut vm, "double(4)"));
+        match eval(&mut vm, "double(missing)") {
+            Some(Object::Error(err)) => assert_eq!(ErrorKind::NameError, err.kind),
+            o => panic!("expected an error, got {:?}", o),
+        }
+        assert_eq!(Some(Object::Int(2)), eval(&mut vm, "double(1)"));
+    }
+
+    #[test]
+    fn test_stack_is_balanced() {
+        let mut vm = new_vm();
+        let input = r#"
+let f = fun(s) {
+  try {
+    if (s == "") { return "empty"; }
+    parseNumber(s)
+  } catch (e) {
+    e.kind
+  } finally {
+    "ignored"
+  }
+};
+map(["", "x", "4"], f)
+        "#;
+
+        assert_eq!(
+            Some(Object::Array(Rc::new(vec![
+                Object::String(String::from("empty")),
+                Object::String(String::from("ValueError")),
+                Object::Int(4),
+            ]))),
+            eval(&mut vm, input)
+        );
+        assert!(vm.stack.is_empty());
+        assert!(vm.frames.is_empty());
+        assert!(vm.handlers.is_empty());
+        assert!(vm.open_upvalues.is_empty());
+    }
+
+    #[test]
+    fn test_recursion_depth_limit() {
+        let mut vm = new_vm();
+        vm.set_max_depth(10);
+
+        let input = "let down = fun(n) { if (n == 0) { 0 } else { 1 + down(n - 1) } };";
+        assert_eq!(None, eval(&mut vm, input));
+        assert_eq!(Some(Object::Int(9)), eval(&mut vm, "down(9)"));
+        match eval(&mut vm, "down(10)") {
+            Some(Object::Error(err)) => assert_eq!(ErrorKind::RecursionError, err.kind),
+            o => panic!("expected an error, got {:?}", o),
+        }
+
+        let input = "let count = fun(n) { if (n == 0) { 0 } else { count(n - 1) } }; count(100)";
+        assert_eq!(Some(Object::Int(0)), eval(&mut vm, input));
+    }
+
+    #[test]
+    fn test_propagate_user_result_enum() {
+        let mut vm = new_vm();
+        let input = "enum Result { Nope }; let f = fun() { Nope? }; f()";
+
+        match eval(&mut vm, input) {
+            Some(Object::Error(err)) => {
+                assert_eq!(ErrorKind::TypeError, err.kind);
+                assert_eq!("`?` expects a Result value, got Nope", err.message);
+            }
+            o => panic!("expected a TypeError, got {:?}", o),
+        }
+    }
+}